@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::base::world::{VoxelWorld, BlockPosition};
+use crate::base::block_update::BlockUpdateScheduler;
+
+pub type BlockId = u16;
+
+pub const AIR: BlockId = 0;
+
+/// Everything a block hook is allowed to touch while reacting to an update.
+pub struct BlockUpdateContext<'a> {
+    pub world: &'a mut VoxelWorld,
+    pub scheduler: &'a mut BlockUpdateScheduler,
+    pub registry: &'a BlockRegistry,
+}
+
+/// Hooks called by the block update system. All of them default to doing nothing,
+/// so a block only needs to implement the ones it cares about.
+pub trait BlockBehaviour: Send + Sync {
+    /// Called when an update scheduled through `BlockUpdateScheduler::schedule` comes due.
+    fn on_scheduled_update(&self, _context: &mut BlockUpdateContext, _position: BlockPosition) {}
+
+    /// Called when the block was picked by the random tick system.
+    fn on_random_tick(&self, _context: &mut BlockUpdateContext, _position: BlockPosition) {}
+
+    /// Called when the block at `neighbor`, adjacent to `position`, was changed by `VoxelWorld::set`.
+    fn on_neighbor_changed(&self, _context: &mut BlockUpdateContext, _position: BlockPosition, _neighbor: BlockPosition) {}
+}
+
+#[derive(Clone)]
+pub struct BlockDefinition {
+    pub name: String,
+    pub opaque: bool,
+    pub fluid: bool,
+    pub random_ticks: bool,
+    /// Vertex color of the block faces, in linear RGB. Textures are tinted by it.
+    pub color: [f32; 3],
+    /// Opacity of the faces. Faces of blocks below 1 are blended over what is behind them.
+    pub alpha: f32,
+    /// Texture of each face, in the order of `culled_mesher::FACES`: +x, -x, +y, -y, +z, -z.
+    /// Faces without a texture are plain `color`.
+    pub textures: [Option<String>; 6],
+    pub behaviour: Option<Arc<dyn BlockBehaviour>>,
+}
+
+impl BlockDefinition {
+    pub fn new(name: &str) -> Self {
+        BlockDefinition {
+            name: name.to_owned(),
+            opaque: true,
+            fluid: false,
+            random_ticks: false,
+            color: [1.0, 1.0, 1.0],
+            alpha: 1.0,
+            textures: Default::default(),
+            behaviour: None,
+        }
+    }
+
+    pub fn transparent(mut self) -> Self {
+        self.opaque = false;
+        self
+    }
+
+    /// A block that is seen through, with faces as opaque as `alpha`.
+    pub fn translucent(mut self, alpha: f32) -> Self {
+        self.opaque = false;
+        self.alpha = alpha;
+        self
+    }
+
+    pub fn fluid(mut self) -> Self {
+        self.opaque = false;
+        self.fluid = true;
+        self
+    }
+
+    pub fn with_behaviour(mut self, behaviour: Arc<dyn BlockBehaviour>) -> Self {
+        self.behaviour = Some(behaviour);
+        self
+    }
+
+    pub fn with_color(mut self, color: [f32; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_texture(mut self, texture: &str) -> Self {
+        for face in self.textures.iter_mut() {
+            *face = Some(texture.to_owned());
+        }
+        self
+    }
+
+    /// Textures for blocks such as grass or logs whose sides differ from their top and bottom.
+    pub fn with_face_textures(mut self, top: &str, side: &str, bottom: &str) -> Self {
+        self.textures = [Some(side.to_owned()), Some(side.to_owned()), Some(top.to_owned()),
+                         Some(bottom.to_owned()), Some(side.to_owned()), Some(side.to_owned())];
+        self
+    }
+
+    pub fn with_random_ticks(mut self) -> Self {
+        self.random_ticks = true;
+        self
+    }
+}
+
+pub struct BlockRegistry {
+    blocks: Vec<BlockDefinition>,
+    ids_by_name: HashMap<String, BlockId>,
+    /// Every texture used by the blocks, in the order they were first used.
+    texture_names: Vec<String>,
+    /// Texture layer of each face of each block.
+    face_layers: Vec<[u32; 6]>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockRegistry {
+    /// Creates a registry containing only air, which always has id `AIR`.
+    pub fn new() -> Self {
+        let mut registry = BlockRegistry {
+            blocks: vec![],
+            ids_by_name: HashMap::new(),
+            texture_names: vec![],
+            face_layers: vec![],
+        };
+        registry.register(BlockDefinition::new("air").transparent());
+        return registry;
+    }
+
+    pub fn register(&mut self, definition: BlockDefinition) -> BlockId {
+        if let Some(id) = self.ids_by_name.get(&definition.name) {
+            panic!("Block {} is already registered with id {}", definition.name, id);
+        }
+        let id = self.blocks.len() as BlockId;
+        let mut layers = [0; 6];
+        for (layer, texture) in layers.iter_mut().zip(definition.textures.iter()) {
+            if let Some(texture) = texture {
+                let index = match self.texture_names.iter().position(|name| name == texture) {
+                    Some(index) => index,
+                    None => {
+                        self.texture_names.push(texture.clone());
+                        self.texture_names.len() - 1
+                    }
+                };
+                *layer = index as u32 + 1;
+            }
+        }
+        self.ids_by_name.insert(definition.name.clone(), id);
+        self.blocks.push(definition);
+        self.face_layers.push(layers);
+        return id;
+    }
+
+    pub fn get(&self, id: BlockId) -> Option<&BlockDefinition> {
+        self.blocks.get(id as usize)
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<BlockId> {
+        self.ids_by_name.get(name).cloned()
+    }
+
+    pub fn is_opaque(&self, id: BlockId) -> bool {
+        self.get(id).map(|block| block.opaque).unwrap_or(false)
+    }
+
+    pub fn is_translucent(&self, id: BlockId) -> bool {
+        self.get(id).map(|block| block.alpha < 1.0).unwrap_or(false)
+    }
+
+    pub fn is_fluid(&self, id: BlockId) -> bool {
+        self.get(id).map(|block| block.fluid).unwrap_or(false)
+    }
+
+    pub fn behaviour(&self, id: BlockId) -> Option<Arc<dyn BlockBehaviour>> {
+        self.get(id).and_then(|block| block.behaviour.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The textures used by the blocks. Texture `i` is layer `i + 1` of the texture array, layer 0 being
+    /// plain white for untextured faces.
+    pub fn texture_names(&self) -> &[String] {
+        &self.texture_names
+    }
+
+    /// Texture layer of face `face` of a block, with faces in the order of `BlockDefinition::textures`.
+    pub fn texture_layer(&self, id: BlockId, face: usize) -> u32 {
+        self.face_layers.get(id as usize).map_or(0, |layers| layers[face])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BlockId, &BlockDefinition)> {
+        self.blocks.iter().enumerate().map(|(id, block)| (id as BlockId, block))
+    }
+}
@@ -0,0 +1,330 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use specs::prelude::*;
+use crate::base::block::{BlockId, BlockRegistry, BlockUpdateContext};
+use crate::base::world::{VoxelWorld, BlockPosition};
+use crate::base::voxel::CHUNK_SIZE;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledUpdate {
+    pub due_tick: u64,
+    /// Updates due on the same tick run in ascending priority order.
+    pub priority: i32,
+    pub position: BlockPosition,
+    /// The block that requested the update. The update is dropped if the block was replaced in the meantime.
+    pub block: BlockId,
+    sequence: u64,
+}
+
+impl Ord for ScheduledUpdate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.due_tick.cmp(&other.due_tick)
+            .then(self.priority.cmp(&other.priority))
+            .then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl PartialOrd for ScheduledUpdate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct BlockUpdateScheduler {
+    tick: u64,
+    next_sequence: u64,
+    queue: BinaryHeap<Reverse<ScheduledUpdate>>,
+    scheduled_positions: HashSet<BlockPosition>,
+    /// How many blocks are picked per loaded chunk for random ticks, each tick.
+    pub random_ticks_per_chunk: usize,
+    /// Upper bound on scheduled updates run in a single tick, the rest are postponed.
+    pub max_updates_per_tick: usize,
+    /// Upper bound on neighbor notifications delivered in a single tick, the rest wait for the next ones.
+    pub max_notifications_per_tick: usize,
+    rng_state: u64,
+}
+
+impl Default for BlockUpdateScheduler {
+    fn default() -> Self {
+        Self::new(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl BlockUpdateScheduler {
+    pub fn new(seed: u64) -> Self {
+        BlockUpdateScheduler {
+            tick: 0,
+            next_sequence: 0,
+            queue: BinaryHeap::new(),
+            scheduled_positions: HashSet::new(),
+            random_ticks_per_chunk: 3,
+            max_updates_per_tick: 65536,
+            max_notifications_per_tick: 65536,
+            rng_state: seed | 1,
+        }
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Schedules an update for `block` at `position` after `delay` ticks (at least one).
+    /// Returns false if an update is already pending for that position.
+    pub fn schedule(&mut self, position: BlockPosition, block: BlockId, delay: u64, priority: i32) -> bool {
+        if !self.scheduled_positions.insert(position) {
+            return false;
+        }
+        let update = ScheduledUpdate {
+            due_tick: self.tick + delay.max(1),
+            priority,
+            position,
+            block,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.queue.push(Reverse(update));
+        return true;
+    }
+
+    pub fn is_scheduled(&self, position: BlockPosition) -> bool {
+        self.scheduled_positions.contains(&position)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Removes and returns every update that is due on the current tick, in execution order.
+    fn take_due(&mut self) -> Vec<ScheduledUpdate> {
+        let mut due = vec![];
+        while due.len() < self.max_updates_per_tick {
+            match self.queue.peek() {
+                Some(Reverse(update)) if update.due_tick <= self.tick => {
+                    let update = self.queue.pop().unwrap().0;
+                    self.scheduled_positions.remove(&update.position);
+                    due.push(update);
+                }
+                _ => break,
+            }
+        }
+        return due;
+    }
+
+    /// xorshift64*, good enough to pick blocks for random ticks.
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+/// Advances the world by one tick: delivers neighbor notifications queued by `VoxelWorld::set`
+/// (at most `max_notifications_per_tick` of them, oldest first), then runs the scheduled updates that are due, then the random ticks.
+/// Anything triggered by the hooks themselves is handled on the next tick.
+pub fn run_tick(world: &mut VoxelWorld, scheduler: &mut BlockUpdateScheduler, registry: &BlockRegistry) {
+    scheduler.tick += 1;
+
+    for notification in world.take_notifications_up_to(scheduler.max_notifications_per_tick) {
+        let block = world.get(notification.position);
+        if let Some(behaviour) = registry.behaviour(block) {
+            let mut context = BlockUpdateContext { world: &mut *world, scheduler: &mut *scheduler, registry };
+            behaviour.on_neighbor_changed(&mut context, notification.position, notification.neighbor);
+        }
+    }
+
+    for update in scheduler.take_due() {
+        if world.get(update.position) != update.block {
+            continue;
+        }
+        if let Some(behaviour) = registry.behaviour(update.block) {
+            let mut context = BlockUpdateContext { world: &mut *world, scheduler: &mut *scheduler, registry };
+            behaviour.on_scheduled_update(&mut context, update.position);
+        }
+    }
+
+    if scheduler.random_ticks_per_chunk == 0 {
+        return;
+    }
+    let chunk_positions: Vec<_> = world.chunks().map(|(position, _)| *position).collect();
+    for chunk_position in chunk_positions {
+        for _ in 0..scheduler.random_ticks_per_chunk {
+            let index = (scheduler.next_random() % (CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE) as u64) as usize;
+            let (x, y, z) = crate::base::voxel::ChunkData::coordinates_from_position(index);
+            let position = chunk_position.block(x, y, z);
+            let block = world.get(position);
+            let definition = match registry.get(block) {
+                Some(definition) if definition.random_ticks => definition,
+                _ => continue,
+            };
+            if let Some(behaviour) = definition.behaviour.clone() {
+                let mut context = BlockUpdateContext { world: &mut *world, scheduler: &mut *scheduler, registry };
+                behaviour.on_random_tick(&mut context, position);
+            }
+        }
+    }
+}
+
+pub struct BlockUpdateSystem;
+
+impl<'a> System<'a> for BlockUpdateSystem {
+    type SystemData = (Write<'a, VoxelWorld>, Write<'a, BlockUpdateScheduler>, Read<'a, BlockRegistry>);
+
+    fn run(&mut self, (mut world, mut scheduler, registry): Self::SystemData) {
+        run_tick(&mut world, &mut scheduler, &registry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::base::block::{BlockBehaviour, BlockDefinition};
+    use crate::base::voxel::ChunkData;
+    use crate::base::world::ChunkPosition;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum Event {
+        Scheduled(BlockPosition),
+        Random(BlockPosition),
+        Neighbor(BlockPosition, BlockPosition),
+    }
+
+    /// Records every hook it gets, in order.
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl BlockBehaviour for Recorder {
+        fn on_scheduled_update(&self, _context: &mut BlockUpdateContext, position: BlockPosition) {
+            self.events.lock().unwrap().push(Event::Scheduled(position));
+        }
+
+        fn on_random_tick(&self, _context: &mut BlockUpdateContext, position: BlockPosition) {
+            self.events.lock().unwrap().push(Event::Random(position));
+        }
+
+        fn on_neighbor_changed(&self, _context: &mut BlockUpdateContext, position: BlockPosition, neighbor: BlockPosition) {
+            self.events.lock().unwrap().push(Event::Neighbor(position, neighbor));
+        }
+    }
+
+    fn setup() -> (VoxelWorld, BlockRegistry, BlockId, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let mut registry = BlockRegistry::new();
+        let block = registry.register(BlockDefinition::new("recorder").with_behaviour(recorder.clone()));
+        let mut world = VoxelWorld::new();
+        world.insert_chunk(ChunkPosition::new(0, 0, 0), ChunkData::new());
+        (world, registry, block, recorder)
+    }
+
+    fn events(recorder: &Recorder) -> Vec<Event> {
+        std::mem::take(&mut *recorder.events.lock().unwrap())
+    }
+
+    #[test]
+    fn scheduled_updates_run_by_due_tick_then_priority_then_order() {
+        let (mut world, registry, block, recorder) = setup();
+        let positions: Vec<_> = (0..4).map(|x| BlockPosition::new(x, 1, 1)).collect();
+        for position in positions.iter() {
+            world.set_without_notifications(*position, block);
+        }
+        let mut scheduler = BlockUpdateScheduler::new(1);
+        scheduler.random_ticks_per_chunk = 0;
+        scheduler.schedule(positions[0], block, 2, 0);
+        scheduler.schedule(positions[1], block, 1, 5);
+        scheduler.schedule(positions[2], block, 1, -5);
+        scheduler.schedule(positions[3], block, 1, 5);
+
+        run_tick(&mut world, &mut scheduler, &registry);
+        assert_eq!(events(&recorder), vec![Event::Scheduled(positions[2]), Event::Scheduled(positions[1]), Event::Scheduled(positions[3])]);
+        run_tick(&mut world, &mut scheduler, &registry);
+        assert_eq!(events(&recorder), vec![Event::Scheduled(positions[0])]);
+        assert_eq!(scheduler.pending_count(), 0);
+    }
+
+    #[test]
+    fn updates_are_deduplicated_by_position() {
+        let (mut world, registry, block, recorder) = setup();
+        let position = BlockPosition::new(3, 3, 3);
+        world.set_without_notifications(position, block);
+        let mut scheduler = BlockUpdateScheduler::new(1);
+        scheduler.random_ticks_per_chunk = 0;
+        assert!(scheduler.schedule(position, block, 1, 0));
+        assert!(!scheduler.schedule(position, block, 3, -1));
+        assert!(scheduler.is_scheduled(position));
+        assert_eq!(scheduler.pending_count(), 1);
+
+        run_tick(&mut world, &mut scheduler, &registry);
+        assert_eq!(events(&recorder), vec![Event::Scheduled(position)]);
+        assert!(!scheduler.is_scheduled(position));
+        assert!(scheduler.schedule(position, block, 1, 0));
+    }
+
+    #[test]
+    fn updates_of_replaced_blocks_are_dropped() {
+        let (mut world, registry, block, recorder) = setup();
+        let position = BlockPosition::new(3, 3, 3);
+        let mut scheduler = BlockUpdateScheduler::new(1);
+        scheduler.random_ticks_per_chunk = 0;
+        scheduler.schedule(position, block, 1, 0);
+        run_tick(&mut world, &mut scheduler, &registry);
+        assert!(events(&recorder).is_empty());
+    }
+
+    #[test]
+    fn neighbors_are_notified_before_scheduled_updates() {
+        let (mut world, registry, block, recorder) = setup();
+        let position = BlockPosition::new(5, 5, 5);
+        let above = position.offset(0, 1, 0);
+        world.set_without_notifications(above, block);
+        let mut scheduler = BlockUpdateScheduler::new(1);
+        scheduler.random_ticks_per_chunk = 0;
+        scheduler.schedule(above, block, 1, 0);
+        world.set(position, 1 + block);
+
+        run_tick(&mut world, &mut scheduler, &registry);
+        // Only the neighbor holding a block with a behaviour hears about it
+        assert_eq!(events(&recorder), vec![Event::Neighbor(above, position), Event::Scheduled(above)]);
+        assert!(world.take_notifications().is_empty());
+    }
+
+    #[test]
+    fn notifications_beyond_the_tick_budget_wait_for_the_next_tick() {
+        let (mut world, registry, block, recorder) = setup();
+        world.insert_chunk(ChunkPosition::new(0, 0, 0), ChunkData::from_vec(vec![block; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]));
+        let mut scheduler = BlockUpdateScheduler::new(1);
+        scheduler.random_ticks_per_chunk = 0;
+        scheduler.max_notifications_per_tick = 4;
+        for i in 1..4 {
+            world.set(BlockPosition::new(3*i, 3*i, 3*i), crate::base::block::AIR);
+        }
+        assert_eq!(world.pending_notification_count(), 18);
+
+        for expected in [4, 4, 4, 4, 2, 0].iter() {
+            run_tick(&mut world, &mut scheduler, &registry);
+            assert_eq!(events(&recorder).len(), *expected);
+        }
+        assert_eq!(world.pending_notification_count(), 0);
+    }
+
+    #[test]
+    fn random_ticks_only_reach_blocks_that_want_them() {
+        let recorder = Arc::new(Recorder::default());
+        let mut registry = BlockRegistry::new();
+        let quiet = registry.register(BlockDefinition::new("quiet").with_behaviour(recorder.clone()));
+        let ticking = registry.register(BlockDefinition::new("ticking").with_behaviour(recorder.clone()).with_random_ticks());
+        let mut world = VoxelWorld::new();
+        world.insert_chunk(ChunkPosition::new(0, 0, 0), ChunkData::from_vec(vec![quiet; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]));
+        world.insert_chunk(ChunkPosition::new(1, 0, 0), ChunkData::from_vec(vec![ticking; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]));
+        let mut scheduler = BlockUpdateScheduler::new(7);
+        run_tick(&mut world, &mut scheduler, &registry);
+        let events = events(&recorder);
+        assert_eq!(events.len(), scheduler.random_ticks_per_chunk);
+        assert!(events.iter().all(|event| match event {
+            Event::Random(position) => position.chunk() == ChunkPosition::new(1, 0, 0),
+            _ => false,
+        }));
+    }
+}
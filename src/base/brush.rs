@@ -0,0 +1,360 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use crate::base::block::BlockId;
+use crate::base::edit_history::{BlockChange, EditTransaction};
+use crate::base::voxel::CHUNK_SIZE;
+use crate::base::world::{VoxelWorld, BlockPosition, ChunkPosition};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shape {
+    /// Every block between the two corners, both included.
+    Box { min: BlockPosition, max: BlockPosition },
+    /// The blocks of a box that are at most `thickness` blocks away from its faces.
+    HollowBox { min: BlockPosition, max: BlockPosition, thickness: i32 },
+    Sphere { center: BlockPosition, radius: f32 },
+    /// An upright cylinder standing on `base`, the center of its bottom face.
+    Cylinder { base: BlockPosition, radius: f32, height: i32 },
+    /// The blocks at most `radius` away from the segment between `from` and `to`.
+    /// A radius of 0.5 gives a line one block thick.
+    Line { from: BlockPosition, to: BlockPosition, radius: f32 },
+}
+
+fn ordered(a: BlockPosition, b: BlockPosition) -> (BlockPosition, BlockPosition) {
+    (BlockPosition::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+     BlockPosition::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)))
+}
+
+impl Shape {
+    /// Corners of the smallest box containing the shape, both included.
+    pub fn bounds(&self) -> (BlockPosition, BlockPosition) {
+        match *self {
+            Shape::Box { min, max } | Shape::HollowBox { min, max, .. } => ordered(min, max),
+            Shape::Sphere { center, radius } => {
+                let extent = radius.max(0.0) as i32;
+                (center.offset(-extent, -extent, -extent), center.offset(extent, extent, extent))
+            }
+            Shape::Cylinder { base, radius, height } => {
+                let extent = radius.max(0.0) as i32;
+                (base.offset(-extent, 0, -extent), base.offset(extent, (height - 1).max(0), extent))
+            }
+            Shape::Line { from, to, radius } => {
+                let extent = radius.max(0.0) as i32;
+                let (min, max) = ordered(from, to);
+                (min.offset(-extent, -extent, -extent), max.offset(extent, extent, extent))
+            }
+        }
+    }
+
+    pub fn contains(&self, position: BlockPosition) -> bool {
+        match *self {
+            Shape::Box { min, max } => {
+                let (min, max) = ordered(min, max);
+                position.x >= min.x && position.x <= max.x
+                    && position.y >= min.y && position.y <= max.y
+                    && position.z >= min.z && position.z <= max.z
+            }
+            Shape::HollowBox { min, max, thickness } => {
+                let (min, max) = ordered(min, max);
+                if !(Shape::Box { min, max }).contains(position) {
+                    return false;
+                }
+                let inner_min = min.offset(thickness, thickness, thickness);
+                let inner_max = max.offset(-thickness, -thickness, -thickness);
+                !(Shape::Box { min: inner_min, max: inner_max }).contains(position)
+                    || inner_min.x > inner_max.x || inner_min.y > inner_max.y || inner_min.z > inner_max.z
+            }
+            Shape::Sphere { center, radius } => {
+                let (dx, dy, dz) = ((position.x - center.x) as f32, (position.y - center.y) as f32, (position.z - center.z) as f32);
+                dx*dx + dy*dy + dz*dz <= radius*radius
+            }
+            Shape::Cylinder { base, radius, height } => {
+                let (dx, dz) = ((position.x - base.x) as f32, (position.z - base.z) as f32);
+                position.y >= base.y && position.y < base.y + height && dx*dx + dz*dz <= radius*radius
+            }
+            Shape::Line { from, to, radius } => {
+                let segment = [(to.x - from.x) as f32, (to.y - from.y) as f32, (to.z - from.z) as f32];
+                let offset = [(position.x - from.x) as f32, (position.y - from.y) as f32, (position.z - from.z) as f32];
+                let length_squared = segment[0]*segment[0] + segment[1]*segment[1] + segment[2]*segment[2];
+                let t = if length_squared == 0.0 {
+                    0.0
+                } else {
+                    ((offset[0]*segment[0] + offset[1]*segment[1] + offset[2]*segment[2])/length_squared).max(0.0).min(1.0)
+                };
+                let distance = [offset[0] - segment[0]*t, offset[1] - segment[1]*t, offset[2] - segment[2]*t];
+                distance[0]*distance[0] + distance[1]*distance[1] + distance[2]*distance[2] <= radius*radius
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrushMode {
+    /// Sets every block of the shape.
+    Fill(BlockId),
+    /// Only replaces the blocks of the shape that are `from`.
+    Replace { from: BlockId, to: BlockId },
+}
+
+impl BrushMode {
+    fn new_block(&self, old: BlockId) -> Option<BlockId> {
+        match *self {
+            BrushMode::Fill(block) => Some(block),
+            BrushMode::Replace { from, to } => if old == from { Some(to) } else { None },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EditReport {
+    /// Number of blocks that actually changed.
+    pub changed: usize,
+    /// Chunks marked for rebuild, including neighbors sharing a changed border.
+    pub dirty_chunks: Vec<ChunkPosition>,
+}
+
+/// Edits the loaded chunks one at a time, writing directly to their data. Brush edits do not queue
+/// neighbor notifications, as doing so for every block of a large shape would flood the block update system.
+fn apply_by_chunk<F>(world: &mut VoxelWorld, min: BlockPosition, max: BlockPosition, mut edit: F, changes: &mut Vec<BlockChange>) -> EditReport
+    where F: FnMut(BlockPosition, BlockId) -> Option<BlockId> {
+    let size = CHUNK_SIZE as i32;
+    let (min_chunk, max_chunk) = (min.chunk(), max.chunk());
+    let mut changed = 0;
+    let mut dirty_chunks = BTreeSet::new();
+    for cx in min_chunk.x..=max_chunk.x {
+        for cy in min_chunk.y..=max_chunk.y {
+            for cz in min_chunk.z..=max_chunk.z {
+                let chunk_position = ChunkPosition::new(cx, cy, cz);
+                let origin = chunk_position.origin();
+                let chunk = match world.chunk_mut(chunk_position) {
+                    Some(chunk) => chunk,
+                    None => continue,
+                };
+                let local_min = ((min.x - origin.x).max(0) as usize, (min.y - origin.y).max(0) as usize, (min.z - origin.z).max(0) as usize);
+                let local_max = ((max.x - origin.x).min(size - 1) as usize, (max.y - origin.y).min(size - 1) as usize, (max.z - origin.z).min(size - 1) as usize);
+                let mut changed_min = (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
+                let mut changed_max = (0, 0, 0);
+                for x in local_min.0..=local_max.0 {
+                    for y in local_min.1..=local_max.1 {
+                        for z in local_min.2..=local_max.2 {
+                            let position = chunk_position.block(x, y, z);
+                            let old = chunk.chunk_data.get(x, y, z);
+                            let new = match edit(position, old) {
+                                Some(new) if new != old => new,
+                                _ => continue,
+                            };
+                            chunk.chunk_data.set(new, x, y, z);
+                            changes.push(BlockChange { position, old, new });
+                            changed += 1;
+                            changed_min = (changed_min.0.min(x), changed_min.1.min(y), changed_min.2.min(z));
+                            changed_max = (changed_max.0.max(x), changed_max.1.max(y), changed_max.2.max(z));
+                        }
+                    }
+                }
+                if changed_min.0 <= changed_max.0 {
+                    dirty_chunks.extend(world.mark_modified(chunk_position, changed_min, changed_max));
+                }
+            }
+        }
+    }
+    EditReport { changed, dirty_chunks: dirty_chunks.into_iter().collect() }
+}
+
+fn apply_shape(world: &mut VoxelWorld, shape: &Shape, mode: BrushMode, changes: &mut Vec<BlockChange>) -> EditReport {
+    let (min, max) = shape.bounds();
+    apply_by_chunk(world, min, max, |position, old| {
+        if shape.contains(position) { mode.new_block(old) } else { None }
+    }, changes)
+}
+
+/// Applies a brush to the loaded chunks intersecting `shape`.
+pub fn apply(world: &mut VoxelWorld, shape: &Shape, mode: BrushMode) -> EditReport {
+    apply_shape(world, shape, mode, &mut vec![])
+}
+
+/// Same as `apply`, recording the changes in an undoable transaction.
+pub fn apply_in(transaction: &mut EditTransaction, shape: &Shape, mode: BrushMode) -> EditReport {
+    let (world, changes) = transaction.world_and_changes();
+    apply_shape(world, shape, mode, changes)
+}
+
+/// Finds the blocks connected to `start` through faces that are the same block as `start`,
+/// or `None` if there are more than `max_blocks` of them.
+fn flood_region(world: &VoxelWorld, start: BlockPosition, max_blocks: usize) -> Option<HashSet<BlockPosition>> {
+    if !world.is_loaded(start.chunk()) {
+        return Some(HashSet::new());
+    }
+    let target = world.get(start);
+    let mut region = HashSet::new();
+    let mut queue = VecDeque::new();
+    region.insert(start);
+    queue.push_back(start);
+    while let Some(position) = queue.pop_front() {
+        for neighbor in position.neighbors().iter() {
+            if region.contains(neighbor) || !world.is_loaded(neighbor.chunk()) || world.get(*neighbor) != target {
+                continue;
+            }
+            if region.len() >= max_blocks {
+                return None;
+            }
+            region.insert(*neighbor);
+            queue.push_back(*neighbor);
+        }
+    }
+    Some(region)
+}
+
+fn apply_flood_fill(world: &mut VoxelWorld, start: BlockPosition, block: BlockId, max_blocks: usize, changes: &mut Vec<BlockChange>) -> Option<EditReport> {
+    let region = flood_region(world, start, max_blocks)?;
+    let mut by_chunk: HashMap<ChunkPosition, (BlockPosition, BlockPosition)> = HashMap::new();
+    for position in region.iter() {
+        let bounds = by_chunk.entry(position.chunk()).or_insert((*position, *position));
+        *bounds = (ordered(bounds.0, *position).0, ordered(bounds.1, *position).1);
+    }
+    let mut report = EditReport::default();
+    let mut dirty_chunks = BTreeSet::new();
+    for (min, max) in by_chunk.values() {
+        let chunk_report = apply_by_chunk(world, *min, *max, |position, _| {
+            if region.contains(&position) { Some(block) } else { None }
+        }, changes);
+        report.changed += chunk_report.changed;
+        dirty_chunks.extend(chunk_report.dirty_chunks);
+    }
+    report.dirty_chunks = dirty_chunks.into_iter().collect();
+    Some(report)
+}
+
+/// Replaces the region of identical blocks connected to `start` with `block`. Nothing is changed and
+/// `None` is returned if the region has more than `max_blocks` blocks, e.g. when filling open air.
+pub fn flood_fill(world: &mut VoxelWorld, start: BlockPosition, block: BlockId, max_blocks: usize) -> Option<EditReport> {
+    apply_flood_fill(world, start, block, max_blocks, &mut vec![])
+}
+
+/// Same as `flood_fill`, recording the changes in an undoable transaction.
+pub fn flood_fill_in(transaction: &mut EditTransaction, start: BlockPosition, block: BlockId, max_blocks: usize) -> Option<EditReport> {
+    let (world, changes) = transaction.world_and_changes();
+    apply_flood_fill(world, start, block, max_blocks, changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::edit_history::EditHistory;
+    use crate::base::voxel::ChunkData;
+
+    fn world_with(chunks: &[ChunkPosition]) -> VoxelWorld {
+        let mut world = VoxelWorld::new();
+        for chunk in chunks {
+            world.insert_chunk(*chunk, ChunkData::new());
+        }
+        for (_, chunk) in world.chunks_mut() {
+            chunk.must_rebuild = false;
+        }
+        world
+    }
+
+    #[test]
+    fn hollow_boxes_thicker_than_half_their_size_are_solid() {
+        let (min, max) = (BlockPosition::new(0, 0, 0), BlockPosition::new(4, 4, 4));
+        let hollow = Shape::HollowBox { min, max, thickness: 2 };
+        assert!(!hollow.contains(BlockPosition::new(2, 2, 2)));
+        assert!(hollow.contains(BlockPosition::new(1, 2, 2)));
+        assert!(!hollow.contains(BlockPosition::new(5, 2, 2)));
+
+        for thickness in [3, 10].iter() {
+            let solid = Shape::HollowBox { min: max, max: min, thickness: *thickness };
+            assert!(solid.contains(BlockPosition::new(2, 2, 2)));
+            assert!(!solid.contains(BlockPosition::new(2, -1, 2)));
+            assert_eq!(solid.bounds(), (min, max));
+        }
+    }
+
+    #[test]
+    fn lines_between_identical_points_are_spheres() {
+        let point = BlockPosition::new(5, -3, 7);
+        let line = Shape::Line { from: point, to: point, radius: 0.5 };
+        assert!(line.contains(point));
+        assert!(!line.contains(point.offset(1, 0, 0)));
+        assert_eq!(line.bounds(), (point, point));
+
+        let line = Shape::Line { from: point, to: point, radius: 1.5 };
+        assert!(line.contains(point.offset(0, -1, 0)));
+        assert!(line.contains(point.offset(1, 1, 0)));
+        assert!(!line.contains(point.offset(1, 1, 1)));
+        assert_eq!(line.bounds(), (point.offset(-1, -1, -1), point.offset(1, 1, 1)));
+    }
+
+    #[test]
+    fn empty_cylinders_change_nothing() {
+        let mut world = world_with(&[ChunkPosition::new(0, 0, 0)]);
+        let base = BlockPosition::new(8, 8, 8);
+        for height in [0, -3].iter() {
+            let cylinder = Shape::Cylinder { base, radius: 2.0, height: *height };
+            let (min, max) = cylinder.bounds();
+            assert_eq!((min.y, max.y), (base.y, base.y));
+            assert!(!cylinder.contains(base));
+            assert_eq!(apply(&mut world, &cylinder, BrushMode::Fill(1)), EditReport::default());
+        }
+    }
+
+    #[test]
+    fn flood_fill_gives_up_over_max_blocks() {
+        let mut world = world_with(&[ChunkPosition::new(0, 0, 0)]);
+        let enclosure = Shape::HollowBox { min: BlockPosition::new(0, 0, 0), max: BlockPosition::new(4, 4, 4), thickness: 1 };
+        apply(&mut world, &enclosure, BrushMode::Fill(1));
+
+        // 27 blocks of air inside the enclosure, the whole rest of the chunk outside
+        let inside = BlockPosition::new(2, 2, 2);
+        assert_eq!(flood_fill(&mut world, BlockPosition::new(20, 20, 20), 2, 1000), None);
+        assert_eq!(world.get(BlockPosition::new(20, 20, 20)), 0);
+        assert_eq!(flood_fill(&mut world, inside, 2, 26), None);
+        assert_eq!(world.get(inside), 0);
+        assert_eq!(flood_fill(&mut world, inside, 2, 27).unwrap().changed, 27);
+        assert_eq!(world.get(inside), 2);
+    }
+
+    #[test]
+    fn edits_across_chunk_borders_mark_neighbors_dirty() {
+        let chunks = [ChunkPosition::new(0, 0, 0), ChunkPosition::new(1, 0, 0), ChunkPosition::new(0, 1, 0), ChunkPosition::new(0, 0, -1)];
+        let mut world = world_with(&chunks);
+        let size = CHUNK_SIZE as i32;
+        let shape = Shape::Box { min: BlockPosition::new(size - 2, 10, 10), max: BlockPosition::new(size + 1, 10, 10) };
+        let report = apply(&mut world, &shape, BrushMode::Fill(1));
+        assert_eq!(report.changed, 4);
+        assert_eq!(report.dirty_chunks, vec![chunks[0], chunks[1]]);
+        assert!(!world.chunk(chunks[2]).unwrap().must_rebuild);
+        assert!(world.chunk(chunks[1]).unwrap().must_save);
+
+        // Only the neighbor on the changed face is marked, and unloaded neighbors are skipped
+        let shape = Shape::Box { min: BlockPosition::new(0, 10, 0), max: BlockPosition::new(3, 10, 0) };
+        let report = apply(&mut world, &shape, BrushMode::Fill(1));
+        assert_eq!(report.dirty_chunks, vec![chunks[3], chunks[0]]);
+        assert!(world.chunk(chunks[3]).unwrap().must_rebuild);
+        assert!(!world.chunk(chunks[3]).unwrap().must_save);
+        assert!(world.take_notifications().is_empty());
+    }
+
+    #[test]
+    fn undoing_or_cancelling_a_brush_queues_no_notifications() {
+        let chunks = [ChunkPosition::new(0, 0, 0), ChunkPosition::new(1, 0, 0)];
+        let mut world = world_with(&chunks);
+        let mut history = EditHistory::new();
+        let size = CHUNK_SIZE as i32;
+        let shape = Shape::Box { min: BlockPosition::new(0, 0, 0), max: BlockPosition::new(size + 9, 9, 9) };
+
+        let mut transaction = history.transaction(&mut world);
+        assert_eq!(apply_in(&mut transaction, &shape, BrushMode::Fill(1)).changed, (size as usize + 10)*100);
+        transaction.commit();
+        assert_eq!(history.undo(&mut world).unwrap(), (size as usize + 10)*100);
+        assert_eq!(world.get(BlockPosition::new(size + 9, 9, 9)), 0);
+        assert!(world.take_notifications().is_empty());
+
+        let mut transaction = history.transaction(&mut world);
+        apply_in(&mut transaction, &shape, BrushMode::Fill(2));
+        for chunk in chunks.iter() {
+            transaction.world_and_changes().0.chunk_mut(*chunk).unwrap().must_rebuild = false;
+        }
+        transaction.rollback();
+        assert_eq!(world.get(BlockPosition::new(0, 0, 0)), 0);
+        assert!(world.take_notifications().is_empty());
+        assert!(chunks.iter().all(|chunk| world.chunk(*chunk).unwrap().must_rebuild));
+    }
+}
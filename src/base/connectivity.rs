@@ -0,0 +1,191 @@
+use crate::base::block::BlockRegistry;
+use crate::base::voxel::{ChunkData, CHUNK_SIZE};
+
+/// The faces of a chunk, in the same order as `culled_mesher::FACES`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+pub const ALL_FACES: [Face; 6] = [Face::PosX, Face::NegX, Face::PosY, Face::NegY, Face::PosZ, Face::NegZ];
+
+impl Face {
+    pub fn offset(&self) -> (i32, i32, i32) {
+        match self {
+            Face::PosX => (1, 0, 0),
+            Face::NegX => (-1, 0, 0),
+            Face::PosY => (0, 1, 0),
+            Face::NegY => (0, -1, 0),
+            Face::PosZ => (0, 0, 1),
+            Face::NegZ => (0, 0, -1),
+        }
+    }
+
+    pub fn opposite(&self) -> Face {
+        match self {
+            Face::PosX => Face::NegX,
+            Face::NegX => Face::PosX,
+            Face::PosY => Face::NegY,
+            Face::NegY => Face::PosY,
+            Face::PosZ => Face::NegZ,
+            Face::NegZ => Face::PosZ,
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// Which pairs of faces of a chunk can see each other through non-opaque blocks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkConnectivity {
+    /// For each face, the set of faces it connects to as a bit mask.
+    connections: [u8; 6],
+}
+
+/// Every face connected to every other, which is the safe assumption for chunks whose content is unknown.
+impl Default for ChunkConnectivity {
+    fn default() -> Self {
+        ChunkConnectivity { connections: [0b11_1111; 6] }
+    }
+}
+
+impl ChunkConnectivity {
+    pub fn none() -> Self {
+        ChunkConnectivity { connections: [0; 6] }
+    }
+
+    pub fn connects(&self, from: Face, to: Face) -> bool {
+        self.connections[from as usize] & to.bit() != 0
+    }
+
+    pub fn connect(&mut self, a: Face, b: Face) {
+        self.connections[a as usize] |= b.bit();
+        self.connections[b as usize] |= a.bit();
+    }
+
+    /// Connects every pair of faces in the `faces` bit mask.
+    fn connect_all(&mut self, faces: u8) {
+        for face in ALL_FACES.iter() {
+            if faces & face.bit() != 0 {
+                self.connections[*face as usize] |= faces;
+            }
+        }
+    }
+
+    /// Flood fills the non-opaque blocks of the chunk. Faces reached by the same region of connected
+    /// blocks can see each other.
+    pub fn compute(chunk_data: &ChunkData, registry: &BlockRegistry) -> Self {
+        let mut connectivity = ChunkConnectivity::none();
+        let mut visited = vec![false; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE];
+        let mut stack = vec![];
+        let last = CHUNK_SIZE - 1;
+        for start in 0..visited.len() {
+            if visited[start] {
+                continue;
+            }
+            let (x, y, z) = ChunkData::coordinates_from_position(start);
+            visited[start] = true;
+            if registry.is_opaque(chunk_data.get(x, y, z)) {
+                continue;
+            }
+            let mut faces = 0u8;
+            stack.push((x, y, z));
+            while let Some((x, y, z)) = stack.pop() {
+                if x == last { faces |= Face::PosX.bit(); }
+                if x == 0 { faces |= Face::NegX.bit(); }
+                if y == last { faces |= Face::PosY.bit(); }
+                if y == 0 { faces |= Face::NegY.bit(); }
+                if z == last { faces |= Face::PosZ.bit(); }
+                if z == 0 { faces |= Face::NegZ.bit(); }
+                for face in ALL_FACES.iter() {
+                    let (dx, dy, dz) = face.offset();
+                    let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+                    if nx < 0 || ny < 0 || nz < 0 || nx > last as i32 || ny > last as i32 || nz > last as i32 {
+                        continue;
+                    }
+                    let (nx, ny, nz) = (nx as usize, ny as usize, nz as usize);
+                    let index = ChunkData::position_from_coordinates(nx, ny, nz);
+                    if visited[index] {
+                        continue;
+                    }
+                    visited[index] = true;
+                    if !registry.is_opaque(chunk_data.get(nx, ny, nz)) {
+                        stack.push((nx, ny, nz));
+                    }
+                }
+            }
+            connectivity.connect_all(faces);
+            if connectivity == ChunkConnectivity::default() {
+                break;
+            }
+        }
+        return connectivity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::block::{BlockDefinition, BlockId};
+
+    fn registry() -> (BlockRegistry, BlockId) {
+        let mut registry = BlockRegistry::new();
+        let stone = registry.register(BlockDefinition::new("stone"));
+        (registry, stone)
+    }
+
+    fn filled(block: BlockId) -> ChunkData {
+        ChunkData::from_vec(vec![block; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE])
+    }
+
+    #[test]
+    fn empty_chunk_connects_every_face() {
+        let (registry, _) = registry();
+        assert_eq!(ChunkConnectivity::compute(&ChunkData::new(), &registry), ChunkConnectivity::default());
+    }
+
+    #[test]
+    fn solid_chunk_connects_nothing() {
+        let (registry, stone) = registry();
+        assert_eq!(ChunkConnectivity::compute(&filled(stone), &registry), ChunkConnectivity::none());
+    }
+
+    #[test]
+    fn wall_separates_its_sides() {
+        let (registry, stone) = registry();
+        let mut chunk_data = ChunkData::new();
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk_data.set(stone, 10, y, z);
+            }
+        }
+        let connectivity = ChunkConnectivity::compute(&chunk_data, &registry);
+        assert!(!connectivity.connects(Face::NegX, Face::PosX));
+        assert!(connectivity.connects(Face::NegX, Face::PosY));
+        assert!(connectivity.connects(Face::PosX, Face::NegZ));
+        assert!(connectivity.connects(Face::PosY, Face::NegY));
+    }
+
+    #[test]
+    fn tunnel_connects_its_ends_only() {
+        let (registry, stone) = registry();
+        let mut chunk_data = filled(stone);
+        for z in 0..CHUNK_SIZE {
+            chunk_data.set(crate::base::block::AIR, 5, 5, z);
+        }
+        let connectivity = ChunkConnectivity::compute(&chunk_data, &registry);
+        assert!(connectivity.connects(Face::NegZ, Face::PosZ));
+        assert!(connectivity.connects(Face::PosZ, Face::NegZ));
+        for face in [Face::PosX, Face::NegX, Face::PosY, Face::NegY].iter() {
+            assert!(!connectivity.connects(Face::NegZ, *face));
+            assert!(!connectivity.connects(*face, *face));
+        }
+    }
+}
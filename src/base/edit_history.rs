@@ -0,0 +1,383 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+use crate::base::block::BlockId;
+use crate::base::world::{VoxelWorld, BlockPosition, ChunkPosition};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HistoryError {
+    /// There is nothing to undo or redo.
+    Empty,
+    /// The batch touches these chunks, which are not loaded. It stays in the history, so it can be
+    /// undone or redone once they are loaded again.
+    ChunksNotLoaded(Vec<ChunkPosition>),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Empty => write!(f, "Nothing to undo or redo"),
+            HistoryError::ChunksNotLoaded(chunks) => write!(f, "Edited chunks are not loaded: {:?}", chunks),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockChange {
+    pub position: BlockPosition,
+    pub old: BlockId,
+    pub new: BlockId,
+}
+
+/// A group of changes that is undone and redone as a whole.
+#[derive(Clone, Debug)]
+pub struct EditBatch {
+    pub changes: Vec<BlockChange>,
+}
+
+impl EditBatch {
+    /// Fails with the chunks the batch touches that are not loaded in `world`, if any.
+    fn check_loaded(&self, world: &VoxelWorld) -> Result<(), HistoryError> {
+        let missing: BTreeSet<_> = self.changes.iter()
+            .map(|change| change.position.chunk())
+            .filter(|chunk| !world.is_loaded(*chunk))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(HistoryError::ChunksNotLoaded(missing.into_iter().collect()))
+    }
+
+    fn memory_size(&self) -> usize {
+        std::mem::size_of::<EditBatch>() + self.changes.capacity()*std::mem::size_of::<BlockChange>()
+    }
+}
+
+/// Writes the block picked by `block` for each change, in order, directly to the chunk data, then marks
+/// each touched chunk as modified once, like the brushes do. No neighbor notifications are queued,
+/// so replaying a large edit does not flood the block update system. Changes on unloaded chunks are skipped.
+fn replay<'a, I, F>(world: &mut VoxelWorld, changes: I, block: F)
+    where I: IntoIterator<Item = &'a BlockChange>, F: Fn(&BlockChange) -> BlockId {
+    let mut bounds: HashMap<ChunkPosition, ((usize, usize, usize), (usize, usize, usize))> = HashMap::new();
+    for change in changes {
+        let chunk_position = change.position.chunk();
+        let chunk = match world.chunk_mut(chunk_position) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        let (x, y, z) = change.position.local();
+        chunk.chunk_data.set(block(change), x, y, z);
+        let (min, max) = bounds.entry(chunk_position).or_insert(((x, y, z), (x, y, z)));
+        *min = (min.0.min(x), min.1.min(y), min.2.min(z));
+        *max = (max.0.max(x), max.1.max(y), max.2.max(z));
+    }
+    for (chunk_position, (min, max)) in bounds {
+        world.mark_modified(chunk_position, min, max);
+    }
+}
+
+/// Undo and redo stacks of world edits.
+pub struct EditHistory {
+    undo_stack: VecDeque<EditBatch>,
+    redo_stack: Vec<EditBatch>,
+    memory_used: usize,
+    /// When the batch on top of the undo stack was last extended, if it may still absorb new edits.
+    last_commit: Option<Instant>,
+    /// Oldest batches are dropped once the history uses more than this many bytes.
+    pub memory_limit: usize,
+    /// Batches committed within this duration of the previous one are merged into it,
+    /// so e.g. dragging a brush around is undone in one step.
+    pub coalesce_window: Duration,
+}
+
+impl Default for EditHistory {
+    fn default() -> Self {
+        EditHistory {
+            undo_stack: VecDeque::new(),
+            redo_stack: vec![],
+            memory_used: 0,
+            last_commit: None,
+            memory_limit: 64*1024*1024,
+            coalesce_window: Duration::from_millis(250),
+        }
+    }
+}
+
+impl EditHistory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts recording edits to `world`. The edits are applied immediately, and recorded once `commit` is called.
+    pub fn transaction<'a>(&'a mut self, world: &'a mut VoxelWorld) -> EditTransaction<'a> {
+        EditTransaction {
+            world,
+            history: self,
+            changes: vec![],
+            committed: false,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo_stack.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo_stack.len()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.memory_used = 0;
+        self.last_commit = None;
+    }
+
+    fn record(&mut self, changes: Vec<BlockChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        for batch in self.redo_stack.drain(..) {
+            self.memory_used -= batch.memory_size();
+        }
+        let now = Instant::now();
+        let coalesce = match self.last_commit {
+            Some(last_commit) => now.duration_since(last_commit) <= self.coalesce_window,
+            None => false,
+        };
+        self.last_commit = Some(now);
+        if coalesce {
+            let last = self.undo_stack.back_mut().unwrap();
+            self.memory_used -= last.memory_size();
+            last.changes.extend(changes);
+            self.memory_used += last.memory_size();
+        } else {
+            let batch = EditBatch { changes };
+            self.memory_used += batch.memory_size();
+            self.undo_stack.push_back(batch);
+        }
+        while self.memory_used > self.memory_limit && self.undo_stack.len() > 1 {
+            let oldest = self.undo_stack.pop_front().unwrap();
+            self.memory_used -= oldest.memory_size();
+        }
+    }
+
+    /// Reverts the last batch, writing the chunks in bulk without neighbor notifications, like the brushes.
+    /// Returns the number of changes reverted. Nothing is reverted if some of the edited chunks are not loaded.
+    pub fn undo(&mut self, world: &mut VoxelWorld) -> Result<usize, HistoryError> {
+        let batch = self.undo_stack.back().ok_or(HistoryError::Empty)?;
+        batch.check_loaded(world)?;
+        let batch = self.undo_stack.pop_back().unwrap();
+        self.last_commit = None;
+        replay(world, batch.changes.iter().rev(), |change| change.old);
+        let count = batch.changes.len();
+        self.redo_stack.push(batch);
+        Ok(count)
+    }
+
+    /// Applies again the last undone batch, the same way `undo` reverts it. Returns the number of changes applied. Nothing is applied
+    /// if some of the edited chunks are not loaded.
+    pub fn redo(&mut self, world: &mut VoxelWorld) -> Result<usize, HistoryError> {
+        let batch = self.redo_stack.last().ok_or(HistoryError::Empty)?;
+        batch.check_loaded(world)?;
+        let batch = self.redo_stack.pop().unwrap();
+        self.last_commit = None;
+        replay(world, batch.changes.iter(), |change| change.new);
+        let count = batch.changes.len();
+        self.undo_stack.push_back(batch);
+        Ok(count)
+    }
+}
+
+/// Edits recorded as one batch of an `EditHistory`. Dropping a transaction without committing it
+/// reverts its changes, the same way `EditHistory::undo` does.
+pub struct EditTransaction<'a> {
+    world: &'a mut VoxelWorld,
+    history: &'a mut EditHistory,
+    changes: Vec<BlockChange>,
+    committed: bool,
+}
+
+impl<'a> EditTransaction<'a> {
+    /// Sets a block through `VoxelWorld::set`, returning the previous block, or `None` if its chunk is not loaded.
+    pub fn set(&mut self, position: BlockPosition, block: BlockId) -> Option<BlockId> {
+        let old = self.world.set(position, block)?;
+        if old != block {
+            self.changes.push(BlockChange { position, old, new: block });
+        }
+        Some(old)
+    }
+
+    pub fn get(&self, position: BlockPosition) -> BlockId {
+        self.world.get(position)
+    }
+
+    pub fn world(&self) -> &VoxelWorld {
+        self.world
+    }
+
+    pub fn changes(&self) -> &[BlockChange] {
+        &self.changes
+    }
+
+    /// Gives direct access to the world for bulk edits, which must push every change they make to the returned list.
+    pub(crate) fn world_and_changes(&mut self) -> (&mut VoxelWorld, &mut Vec<BlockChange>) {
+        (self.world, &mut self.changes)
+    }
+
+    pub fn commit(mut self) {
+        let changes = std::mem::take(&mut self.changes);
+        self.history.record(changes);
+        self.committed = true;
+    }
+
+    /// Reverts the changes made so far, same as dropping the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<'a> Drop for EditTransaction<'a> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        replay(self.world, self.changes.iter().rev(), |change| change.old);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::voxel::{ChunkData, CHUNK_SIZE};
+
+    fn setup() -> (EditHistory, VoxelWorld) {
+        let mut world = VoxelWorld::new();
+        world.insert_chunk(ChunkPosition::new(0, 0, 0), ChunkData::new());
+        world.insert_chunk(ChunkPosition::new(1, 0, 0), ChunkData::new());
+        let mut history = EditHistory::new();
+        // Batches are only merged when a test asks for it
+        history.coalesce_window = Duration::from_secs(0);
+        (history, world)
+    }
+
+    fn edit(history: &mut EditHistory, world: &mut VoxelWorld, position: BlockPosition, block: BlockId) {
+        std::thread::sleep(Duration::from_millis(1));
+        let mut transaction = history.transaction(world);
+        transaction.set(position, block).unwrap();
+        transaction.commit();
+    }
+
+    #[test]
+    fn quick_edits_are_coalesced() {
+        let (mut history, mut world) = setup();
+        history.coalesce_window = Duration::from_secs(60);
+        edit(&mut history, &mut world, BlockPosition::new(0, 0, 0), 1);
+        edit(&mut history, &mut world, BlockPosition::new(1, 0, 0), 1);
+        assert_eq!(history.undo_len(), 1);
+        assert_eq!(history.undo(&mut world), Ok(2));
+        assert_eq!(world.get(BlockPosition::new(0, 0, 0)), 0);
+        assert_eq!(world.get(BlockPosition::new(1, 0, 0)), 0);
+
+        // Undoing closes the batch, so the next edit starts a new one
+        edit(&mut history, &mut world, BlockPosition::new(0, 0, 0), 2);
+        assert_eq!(history.redo(&mut world), Err(HistoryError::Empty));
+        history.coalesce_window = Duration::from_secs(0);
+        edit(&mut history, &mut world, BlockPosition::new(1, 0, 0), 2);
+        assert_eq!(history.undo_len(), 2);
+    }
+
+    #[test]
+    fn oldest_batches_are_dropped_over_the_memory_limit() {
+        let (mut history, mut world) = setup();
+        edit(&mut history, &mut world, BlockPosition::new(0, 0, 0), 1);
+        history.memory_limit = 3*history.memory_used();
+        for block in 2..6 {
+            edit(&mut history, &mut world, BlockPosition::new(0, 0, 0), block);
+        }
+        assert_eq!(history.undo_len(), 3);
+        assert!(history.memory_used() <= history.memory_limit);
+        while history.can_undo() {
+            history.undo(&mut world).unwrap();
+        }
+        assert_eq!(world.get(BlockPosition::new(0, 0, 0)), 2);
+
+        // The newest batch is kept even when it alone is over the limit
+        history.memory_limit = 0;
+        edit(&mut history, &mut world, BlockPosition::new(0, 0, 0), 6);
+        assert_eq!(history.undo_len(), 1);
+    }
+
+    #[test]
+    fn new_edits_clear_the_redo_stack() {
+        let (mut history, mut world) = setup();
+        let position = BlockPosition::new(3, 4, 5);
+        edit(&mut history, &mut world, position, 1);
+        edit(&mut history, &mut world, position, 2);
+        assert_eq!(history.undo(&mut world), Ok(1));
+        assert_eq!(world.get(position), 1);
+        assert_eq!(history.redo(&mut world), Ok(1));
+        assert_eq!(world.get(position), 2);
+
+        history.undo(&mut world).unwrap();
+        edit(&mut history, &mut world, position, 3);
+        assert!(!history.can_redo());
+        assert_eq!(history.undo(&mut world), Ok(1));
+        assert_eq!(world.get(position), 1);
+    }
+
+    #[test]
+    fn undo_and_redo_write_chunks_without_notifications() {
+        let (mut history, mut world) = setup();
+        let mut transaction = history.transaction(&mut world);
+        let size = CHUNK_SIZE as i32;
+        for x in size - 20..size + 20 {
+            for z in 0..16 {
+                transaction.set(BlockPosition::new(x, 3, z), 1).unwrap();
+            }
+        }
+        transaction.commit();
+        world.take_notifications();
+        for (_, chunk) in world.chunks_mut() {
+            chunk.must_rebuild = false;
+            chunk.must_save = false;
+        }
+
+        assert_eq!(history.undo(&mut world), Ok(640));
+        assert!(world.take_notifications().is_empty());
+        assert_eq!(world.get(BlockPosition::new(size + 19, 3, 15)), 0);
+        for (_, chunk) in world.chunks() {
+            assert!(chunk.must_rebuild && chunk.must_save);
+        }
+
+        assert_eq!(history.redo(&mut world), Ok(640));
+        assert!(world.take_notifications().is_empty());
+        assert_eq!(world.get(BlockPosition::new(size - 20, 3, 0)), 1);
+    }
+
+    #[test]
+    fn batches_on_unloaded_chunks_are_kept() {
+        let (mut history, mut world) = setup();
+        let position = BlockPosition::new(70, 0, 0);
+        edit(&mut history, &mut world, position, 1);
+        let chunk = world.remove_chunk(position.chunk()).unwrap();
+        assert_eq!(history.undo(&mut world), Err(HistoryError::ChunksNotLoaded(vec![position.chunk()])));
+        assert_eq!(history.undo_len(), 1);
+
+        world.insert_chunk(position.chunk(), chunk.chunk_data);
+        assert_eq!(history.undo(&mut world), Ok(1));
+        assert_eq!(world.get(position), 0);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::base::block::BlockId;
+use crate::base::voxel::{ChunkData, CHUNK_SIZE};
+use crate::base::world::ChunkPosition;
+
+pub trait ChunkGenerator: Send + Sync {
+    /// Generates the chunk at `position`. Must be deterministic, since chunks that were never
+    /// modified are not saved and will be generated again the next time they are needed.
+    fn generate(&self, position: ChunkPosition) -> ChunkData;
+}
+
+/// Rolling hills from two octaves of value noise, with grass over a few layers of dirt over stone.
+pub struct HeightmapGenerator {
+    pub seed: u64,
+    pub base_height: i32,
+    pub amplitude: f32,
+    pub stone: BlockId,
+    pub dirt: BlockId,
+    pub grass: BlockId,
+}
+
+impl HeightmapGenerator {
+    pub fn new(seed: u64, stone: BlockId, dirt: BlockId, grass: BlockId) -> Self {
+        HeightmapGenerator {
+            seed,
+            base_height: 32,
+            amplitude: 24.0,
+            stone,
+            dirt,
+            grass,
+        }
+    }
+
+    fn hash(&self, x: i32, z: i32) -> f32 {
+        let mut value = self.seed ^ (x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (z as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        value ^= value >> 33;
+        value = value.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+        value ^= value >> 33;
+        (value & 0xFFFF) as f32 / 65535.0
+    }
+
+    fn value_noise(&self, x: f32, z: f32) -> f32 {
+        let (x0, z0) = (x.floor() as i32, z.floor() as i32);
+        let (fx, fz) = (x - x0 as f32, z - z0 as f32);
+        let (sx, sz) = (fx*fx*(3.0 - 2.0*fx), fz*fz*(3.0 - 2.0*fz));
+        let top = self.hash(x0, z0) + (self.hash(x0 + 1, z0) - self.hash(x0, z0))*sx;
+        let bottom = self.hash(x0, z0 + 1) + (self.hash(x0 + 1, z0 + 1) - self.hash(x0, z0 + 1))*sx;
+        top + (bottom - top)*sz
+    }
+
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let noise = self.value_noise(x as f32/64.0, z as f32/64.0)*0.75
+            + self.value_noise(x as f32/16.0, z as f32/16.0)*0.25;
+        self.base_height + ((noise - 0.5)*2.0*self.amplitude) as i32
+    }
+}
+
+impl ChunkGenerator for HeightmapGenerator {
+    fn generate(&self, position: ChunkPosition) -> ChunkData {
+        let mut chunk_data = ChunkData::new();
+        let origin = position.origin();
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let height = self.height_at(origin.x + x as i32, origin.z + z as i32);
+                for y in 0..CHUNK_SIZE {
+                    let world_y = origin.y + y as i32;
+                    let block = if world_y > height {
+                        continue;
+                    } else if world_y == height {
+                        self.grass
+                    } else if world_y > height - 4 {
+                        self.dirt
+                    } else {
+                        self.stone
+                    };
+                    chunk_data.set(block, x, y, z);
+                }
+            }
+        }
+        return chunk_data;
+    }
+}
@@ -1,4 +1,5 @@
 use raylib;
+use crate::base::connectivity::ChunkConnectivity;
 
 pub mod greed_mesher {
     use crate::base::voxel::ChunkData;
@@ -46,4 +47,133 @@ pub mod greed_mesher {
             vboId: [0u32; 7usize]
         }
     }
-}
\ No newline at end of file
+}
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MeshVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+    /// Linear RGB, and the opacity of the face.
+    pub color: [f32; 4],
+    /// Layer of the block texture array sampled by the face.
+    pub layer: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChunkMesh {
+    pub vertices: Vec<MeshVertex>,
+    pub indices: Vec<u32>,
+    /// Faces of translucent blocks, drawn blended after every other face. They share `vertices` with `indices`.
+    pub translucent_indices: Vec<u32>,
+    /// Which faces of the chunk can see each other, used to skip chunks hidden behind solid terrain.
+    pub connectivity: ChunkConnectivity,
+}
+
+impl ChunkMesh {
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty() && self.translucent_indices.is_empty()
+    }
+}
+
+pub mod culled_mesher {
+    use crate::base::mesher::{ChunkMesh, MeshVertex};
+    use crate::base::connectivity::ChunkConnectivity;
+    use crate::base::block::{BlockRegistry, AIR};
+    use crate::base::voxel::CHUNK_SIZE;
+    use crate::base::world::{VoxelWorld, ChunkPosition};
+
+    /// The six faces of a block: the offset to the neighbor they face, and their corners
+    /// counter clockwise when seen from outside of the block.
+    pub const FACES: [([i32; 3], [[f32; 3]; 4]); 6] = [
+        ([1, 0, 0], [[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0], [1.0, 0.0, 1.0]]),
+        ([-1, 0, 0], [[0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 1.0], [0.0, 1.0, 0.0]]),
+        ([0, 1, 0], [[0.0, 1.0, 0.0], [0.0, 1.0, 1.0], [1.0, 1.0, 1.0], [1.0, 1.0, 0.0]]),
+        ([0, -1, 0], [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [0.0, 0.0, 1.0]]),
+        ([0, 0, 1], [[0.0, 0.0, 1.0], [1.0, 0.0, 1.0], [1.0, 1.0, 1.0], [0.0, 1.0, 1.0]]),
+        ([0, 0, -1], [[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 0.0]]),
+    ];
+
+    const UVS: [[f32; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+
+    /// Emits one quad for every block face that is not hidden by an opaque neighbor or by the same block,
+    /// with positions relative to the chunk origin. Neighbor chunks are looked up in the world, faces
+    /// against unloaded chunks are emitted. Unloaded chunks get a fully connected empty mesh.
+    pub fn generate_mesh(world: &VoxelWorld, position: ChunkPosition, registry: &BlockRegistry) -> ChunkMesh {
+        let mut mesh = ChunkMesh::default();
+        let chunk = match world.chunk(position) {
+            Some(chunk) => chunk,
+            None => return mesh,
+        };
+        mesh.connectivity = ChunkConnectivity::compute(&chunk.chunk_data, registry);
+        let origin = position.origin();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let block = chunk.chunk_data.get(x, y, z);
+                    if block == AIR {
+                        continue;
+                    }
+                    let color = registry.get(block).map_or([1.0, 0.0, 1.0, 1.0], |definition| {
+                        [definition.color[0], definition.color[1], definition.color[2], definition.alpha]
+                    });
+                    let translucent = registry.is_translucent(block);
+                    for (face, (offset, corners)) in FACES.iter().enumerate() {
+                        let (nx, ny, nz) = (x as i32 + offset[0], y as i32 + offset[1], z as i32 + offset[2]);
+                        let size = CHUNK_SIZE as i32;
+                        let neighbor = if nx >= 0 && ny >= 0 && nz >= 0 && nx < size && ny < size && nz < size {
+                            chunk.chunk_data.get(nx as usize, ny as usize, nz as usize)
+                        } else {
+                            world.get(origin.offset(nx, ny, nz))
+                        };
+                        if neighbor == block || registry.is_opaque(neighbor) {
+                            continue;
+                        }
+                        let layer = registry.texture_layer(block, face);
+                        let first_index = mesh.vertices.len() as u32;
+                        for (corner, uv) in corners.iter().zip(UVS.iter()) {
+                            mesh.vertices.push(MeshVertex {
+                                position: [x as f32 + corner[0], y as f32 + corner[1], z as f32 + corner[2]],
+                                normal: [offset[0] as f32, offset[1] as f32, offset[2] as f32],
+                                uv: *uv,
+                                color,
+                                layer,
+                            });
+                        }
+                        let indices = if translucent { &mut mesh.translucent_indices } else { &mut mesh.indices };
+                        indices.extend_from_slice(&[first_index, first_index + 1, first_index + 2, first_index, first_index + 2, first_index + 3]);
+                    }
+                }
+            }
+        }
+        return mesh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::block::{BlockDefinition, BlockRegistry};
+    use crate::base::voxel::ChunkData;
+    use crate::base::world::{VoxelWorld, ChunkPosition};
+
+    #[test]
+    fn translucent_faces_are_kept_apart() {
+        let mut registry = BlockRegistry::new();
+        let stone = registry.register(BlockDefinition::new("stone"));
+        let glass = registry.register(BlockDefinition::new("glass").translucent(0.25));
+        let mut chunk_data = ChunkData::new();
+        chunk_data.set(stone, 1, 1, 1);
+        chunk_data.set(glass, 1, 2, 1);
+        chunk_data.set(glass, 1, 3, 1);
+        let mut world = VoxelWorld::new();
+        world.insert_chunk(ChunkPosition::new(0, 0, 0), chunk_data);
+
+        let mesh = culled_mesher::generate_mesh(&world, ChunkPosition::new(0, 0, 0), &registry);
+        // The stone face under the glass is kept, the glass face on the stone and those between the glass blocks are not
+        assert_eq!(mesh.indices.len(), 6*6);
+        assert_eq!(mesh.translucent_indices.len(), 9*6);
+        assert!(mesh.translucent_indices.iter().all(|index| mesh.vertices[*index as usize].color[3] == 0.25));
+        assert!(mesh.indices.iter().all(|index| mesh.vertices[*index as usize].color[3] == 1.0));
+    }
+}
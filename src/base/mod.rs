@@ -1,2 +1,12 @@
 pub mod voxel;
-pub mod mesher;
\ No newline at end of file
+pub mod mesher;
+pub mod connectivity;
+pub mod block;
+pub mod world;
+pub mod block_update;
+pub mod persistence;
+pub mod generator;
+pub mod streaming;
+pub mod schematic;
+pub mod edit_history;
+pub mod brush;
@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::{Serialize, Deserialize};
+use crate::base::voxel::{ChunkData, BlockData, CHUNK_SIZE};
+use crate::base::world::{VoxelWorld, ChunkPosition, BlockPosition};
+
+/// Number of chunks along the x and z axes stored in a single region file.
+/// A region is one chunk tall, so each file holds `REGION_SIZE*REGION_SIZE` chunks.
+pub const REGION_SIZE: i32 = 32;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+const REGION_MAGIC: &[u8; 4] = b"RBRG";
+const REGION_CHUNK_COUNT: usize = (REGION_SIZE*REGION_SIZE) as usize;
+const HEADER_SIZE: usize = 4 + 4;
+/// Each table entry is a little endian `u64` offset followed by a `u32` length. A zero length means no chunk.
+const TABLE_ENTRY_SIZE: usize = 8 + 4;
+const TABLE_SIZE: usize = REGION_CHUNK_COUNT*TABLE_ENTRY_SIZE;
+const METADATA_FILE: &str = "world.meta";
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Serialization(bincode::Error),
+    UnsupportedVersion(u32),
+    Corrupted(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(error) => write!(f, "I/O error: {}", error),
+            SaveError::Serialization(error) => write!(f, "Serialization error: {}", error),
+            SaveError::UnsupportedVersion(version) => write!(f, "Unsupported save format version {} (expected {})", version, FORMAT_VERSION),
+            SaveError::Corrupted(reason) => write!(f, "Corrupted save: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(error: io::Error) -> Self {
+        SaveError::Io(error)
+    }
+}
+
+impl From<bincode::Error> for SaveError {
+    fn from(error: bincode::Error) -> Self {
+        SaveError::Serialization(error)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, SaveError> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(SaveError::Corrupted(format!("unknown compression tag {}", tag))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldMetadata {
+    pub version: u32,
+    pub seed: u64,
+    pub spawn_point: BlockPosition,
+    pub game_time: u64,
+}
+
+impl WorldMetadata {
+    pub fn new(seed: u64, spawn_point: BlockPosition) -> Self {
+        WorldMetadata {
+            version: FORMAT_VERSION,
+            seed,
+            spawn_point,
+            game_time: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RegionPosition {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl RegionPosition {
+    pub fn of_chunk(chunk: ChunkPosition) -> Self {
+        RegionPosition {
+            x: chunk.x.div_euclid(REGION_SIZE),
+            y: chunk.y,
+            z: chunk.z.div_euclid(REGION_SIZE),
+        }
+    }
+
+    fn chunk_index(chunk: ChunkPosition) -> usize {
+        (chunk.x.rem_euclid(REGION_SIZE)*REGION_SIZE + chunk.z.rem_euclid(REGION_SIZE)) as usize
+    }
+
+    fn chunk_at(&self, index: usize) -> ChunkPosition {
+        let index = index as i32;
+        ChunkPosition::new(self.x*REGION_SIZE + index/REGION_SIZE, self.y, self.z*REGION_SIZE + index%REGION_SIZE)
+    }
+
+    fn file_name(&self) -> String {
+        format!("r.{}.{}.{}.region", self.x, self.y, self.z)
+    }
+
+    fn from_file_name(name: &str) -> Option<Self> {
+        let parts: Vec<_> = name.strip_prefix("r.")?.strip_suffix(".region")?.split('.').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        Some(RegionPosition {
+            x: parts[0].parse().ok()?,
+            y: parts[1].parse().ok()?,
+            z: parts[2].parse().ok()?,
+        })
+    }
+}
+
+/// The compressed chunks of a region, as stored on disk.
+struct Region {
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Region {
+    fn empty() -> Self {
+        Region { chunks: vec![None; REGION_CHUNK_COUNT] }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, SaveError> {
+        if bytes.len() < HEADER_SIZE + TABLE_SIZE || &bytes[0..4] != REGION_MAGIC {
+            return Err(SaveError::Corrupted("invalid region header".into()));
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(SaveError::UnsupportedVersion(version));
+        }
+        let mut region = Self::empty();
+        for index in 0..REGION_CHUNK_COUNT {
+            let entry = HEADER_SIZE + index*TABLE_ENTRY_SIZE;
+            let offset = u64::from_le_bytes(bytes[entry..entry + 8].try_into().unwrap()) as usize;
+            let length = u32::from_le_bytes(bytes[entry + 8..entry + 12].try_into().unwrap()) as usize;
+            if length == 0 {
+                continue;
+            }
+            if offset.checked_add(length).map_or(true, |end| end > bytes.len()) {
+                return Err(SaveError::Corrupted(format!("chunk {} points outside of the region file", index)));
+            }
+            region.chunks[index] = Some(bytes[offset..offset + length].to_vec());
+        }
+        Ok(region)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + TABLE_SIZE);
+        bytes.extend_from_slice(REGION_MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let mut offset = (HEADER_SIZE + TABLE_SIZE) as u64;
+        for chunk in self.chunks.iter() {
+            let length = chunk.as_ref().map_or(0, |data| data.len());
+            let entry_offset = if length == 0 { 0 } else { offset };
+            bytes.extend_from_slice(&entry_offset.to_le_bytes());
+            bytes.extend_from_slice(&(length as u32).to_le_bytes());
+            offset += length as u64;
+        }
+        for chunk in self.chunks.iter().flatten() {
+            bytes.extend_from_slice(chunk);
+        }
+        bytes
+    }
+}
+
+fn encode_chunk(chunk_data: &ChunkData, compression: Compression) -> Result<Vec<u8>, SaveError> {
+    let mut raw = Vec::with_capacity(chunk_data.as_slice().len()*2);
+    for block in chunk_data.as_slice() {
+        raw.extend_from_slice(&block.to_le_bytes());
+    }
+    let mut encoded = vec![compression.tag()];
+    match compression {
+        Compression::None => encoded.extend_from_slice(&raw),
+        Compression::Deflate => {
+            let mut encoder = flate2::write::DeflateEncoder::new(encoded, flate2::Compression::default());
+            encoder.write_all(&raw)?;
+            encoded = encoder.finish()?;
+        }
+    }
+    Ok(encoded)
+}
+
+fn decode_chunk(bytes: &[u8]) -> Result<ChunkData, SaveError> {
+    let (tag, payload) = bytes.split_first()
+        .ok_or_else(|| SaveError::Corrupted("empty chunk entry".into()))?;
+    let raw = match Compression::from_tag(*tag)? {
+        Compression::None => payload.to_vec(),
+        Compression::Deflate => {
+            let mut raw = vec![];
+            flate2::read::DeflateDecoder::new(payload).read_to_end(&mut raw)?;
+            raw
+        }
+    };
+    let block_count = CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE;
+    if raw.len() != block_count*2 {
+        return Err(SaveError::Corrupted(format!("chunk holds {} bytes, expected {}", raw.len(), block_count*2)));
+    }
+    let blocks: Vec<BlockData> = raw.chunks_exact(2)
+        .map(|bytes| BlockData::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+    Ok(ChunkData::from_vec(blocks))
+}
+
+/// Writes `bytes` to a temporary file next to `path`, syncs it and renames it over `path`,
+/// so a crash leaves either the old or the new file, never a partial one.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    // Each write gets its own temporary file, so concurrent writes never write into each other's
+    static TEMPORARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let file_name = path.file_name().expect("Saved files have a name").to_string_lossy();
+    let temporary_path = path.with_file_name(format!("{}.{}.{}.tmp", file_name, std::process::id(),
+                                                     TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    {
+        let mut file = fs::File::create(&temporary_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temporary_path, path)?;
+    if let Some(directory) = path.parent() {
+        // Persist the rename itself. Opening a directory fails on some platforms, which is fine to ignore.
+        if let Ok(directory) = fs::File::open(directory) {
+            let _ = directory.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// A world save directory, holding the metadata file and one file per region.
+pub struct WorldSave {
+    directory: PathBuf,
+    pub compression: Compression,
+    /// Saving a chunk rewrites its whole region, so writes to the same region are serialized.
+    region_locks: Mutex<HashMap<RegionPosition, Arc<Mutex<()>>>>,
+}
+
+impl WorldSave {
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self, SaveError> {
+        fs::create_dir_all(directory.as_ref())?;
+        Ok(WorldSave {
+            directory: directory.as_ref().to_owned(),
+            compression: Compression::Deflate,
+            region_locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn region_path(&self, region: RegionPosition) -> PathBuf {
+        self.directory.join(region.file_name())
+    }
+
+    fn region_lock(&self, region: RegionPosition) -> Arc<Mutex<()>> {
+        self.region_locks.lock().unwrap().entry(region).or_default().clone()
+    }
+
+    fn read_region(&self, region: RegionPosition) -> Result<Option<Region>, SaveError> {
+        match fs::read(self.region_path(region)) {
+            Ok(bytes) => Ok(Some(Region::decode(&bytes)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn save_metadata(&self, metadata: &WorldMetadata) -> Result<(), SaveError> {
+        let bytes = bincode::serialize(metadata)?;
+        write_atomically(&self.directory.join(METADATA_FILE), &bytes)?;
+        Ok(())
+    }
+
+    pub fn load_metadata(&self) -> Result<Option<WorldMetadata>, SaveError> {
+        let bytes = match fs::read(self.directory.join(METADATA_FILE)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        // The version comes first, so that other versions are reported as such even if the rest has another layout
+        let version: u32 = bincode::deserialize(&bytes)?;
+        if version != FORMAT_VERSION {
+            return Err(SaveError::UnsupportedVersion(version));
+        }
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    /// Saves the given chunks, rewriting each touched region file once.
+    pub fn save_chunks<'a, I>(&self, chunks: I) -> Result<(), SaveError>
+        where I: IntoIterator<Item = (ChunkPosition, &'a ChunkData)> {
+        let mut by_region: HashMap<RegionPosition, Vec<(ChunkPosition, &ChunkData)>> = HashMap::new();
+        for (position, chunk_data) in chunks {
+            by_region.entry(RegionPosition::of_chunk(position)).or_default().push((position, chunk_data));
+        }
+        for (region_position, chunks) in by_region {
+            let lock = self.region_lock(region_position);
+            let _guard = lock.lock().unwrap();
+            let mut region = self.read_region(region_position)?.unwrap_or_else(Region::empty);
+            for (position, chunk_data) in chunks {
+                region.chunks[RegionPosition::chunk_index(position)] = Some(encode_chunk(chunk_data, self.compression)?);
+            }
+            write_atomically(&self.region_path(region_position), &region.encode())?;
+        }
+        Ok(())
+    }
+
+    pub fn save_chunk(&self, position: ChunkPosition, chunk_data: &ChunkData) -> Result<(), SaveError> {
+        self.save_chunks(std::iter::once((position, chunk_data)))
+    }
+
+    pub fn load_chunk(&self, position: ChunkPosition) -> Result<Option<ChunkData>, SaveError> {
+        let region = match self.read_region(RegionPosition::of_chunk(position))? {
+            Some(region) => region,
+            None => return Ok(None),
+        };
+        match &region.chunks[RegionPosition::chunk_index(position)] {
+            Some(bytes) => Ok(Some(decode_chunk(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_world(&self, world: &VoxelWorld) -> Result<(), SaveError> {
+        self.save_chunks(world.chunks().map(|(position, chunk)| (*position, &chunk.chunk_data)))
+    }
+
+    /// Loads every chunk stored in the save into a new world.
+    pub fn load_world(&self) -> Result<VoxelWorld, SaveError> {
+        let mut world = VoxelWorld::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let file_name = entry?.file_name();
+            let region_position = match file_name.to_str().and_then(RegionPosition::from_file_name) {
+                Some(region_position) => region_position,
+                None => continue,
+            };
+            let region = match self.read_region(region_position)? {
+                Some(region) => region,
+                None => continue,
+            };
+            for (index, bytes) in region.chunks.iter().enumerate() {
+                if let Some(bytes) = bytes {
+                    world.insert_chunk(region_position.chunk_at(index), decode_chunk(bytes)?);
+                }
+            }
+        }
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A save in a fresh directory of its own, removed when dropped.
+    struct TemporarySave(WorldSave);
+
+    impl TemporarySave {
+        fn new(name: &str) -> Self {
+            let directory = std::env::temp_dir().join(format!("rustyblocks-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&directory);
+            TemporarySave(WorldSave::open(directory).unwrap())
+        }
+    }
+
+    impl Drop for TemporarySave {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.directory());
+        }
+    }
+
+    fn chunk(seed: u16) -> ChunkData {
+        let mut chunk_data = ChunkData::new();
+        for index in 0..500 {
+            chunk_data.set(seed + (index % 7) as u16, index % CHUNK_SIZE, (index*3) % CHUNK_SIZE, (index*11) % CHUNK_SIZE);
+        }
+        chunk_data
+    }
+
+    fn blocks(chunk_data: Option<ChunkData>) -> Option<Vec<BlockData>> {
+        chunk_data.map(|chunk_data| chunk_data.as_slice().to_vec())
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        for &compression in [Compression::None, Compression::Deflate].iter() {
+            let mut save = TemporarySave::new(&format!("round-trip-{:?}", compression));
+            save.0.compression = compression;
+            let position = ChunkPosition::new(-3, 2, 40);
+            save.0.save_chunk(position, &chunk(1)).unwrap();
+            assert_eq!(blocks(save.0.load_chunk(position).unwrap()), blocks(Some(chunk(1))));
+        }
+    }
+
+    #[test]
+    fn chunks_share_region_files() {
+        let save = TemporarySave::new("shared-region");
+        let positions = [ChunkPosition::new(0, 0, 0), ChunkPosition::new(31, 0, 5), ChunkPosition::new(4, 0, 31)];
+        let chunks: Vec<_> = (0..positions.len()).map(|index| chunk(index as u16*10 + 1)).collect();
+        save.0.save_chunks(positions.iter().cloned().zip(chunks.iter())).unwrap();
+        // Saving one more on its own keeps the others
+        save.0.save_chunk(ChunkPosition::new(7, 0, 7), &chunk(50)).unwrap();
+
+        let regions = fs::read_dir(save.0.directory()).unwrap().count();
+        assert_eq!(regions, 1);
+        for (position, chunk_data) in positions.iter().zip(chunks.iter()) {
+            assert_eq!(blocks(save.0.load_chunk(*position).unwrap()), Some(chunk_data.as_slice().to_vec()));
+        }
+        assert_eq!(blocks(save.0.load_chunk(ChunkPosition::new(7, 0, 7)).unwrap()), blocks(Some(chunk(50))));
+        assert_eq!(save.0.load_world().unwrap().chunks().count(), 4);
+    }
+
+    #[test]
+    fn saving_overwrites_chunks() {
+        let save = TemporarySave::new("overwrite");
+        let position = ChunkPosition::new(1, -1, 1);
+        save.0.save_chunk(position, &chunk(1)).unwrap();
+        save.0.save_chunk(ChunkPosition::new(2, -1, 1), &chunk(2)).unwrap();
+        save.0.save_chunk(position, &chunk(3)).unwrap();
+        assert_eq!(blocks(save.0.load_chunk(position).unwrap()), blocks(Some(chunk(3))));
+        assert_eq!(blocks(save.0.load_chunk(ChunkPosition::new(2, -1, 1)).unwrap()), blocks(Some(chunk(2))));
+    }
+
+    #[test]
+    fn concurrent_saves_keep_every_chunk() {
+        let save = std::sync::Arc::new(TemporarySave::new("concurrent"));
+        let threads: Vec<_> = (0..4).map(|thread| {
+            let save = save.clone();
+            std::thread::spawn(move || {
+                for index in 0..8 {
+                    save.0.save_chunk(ChunkPosition::new(thread, 0, index), &chunk(index as u16)).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for thread in 0..4 {
+            for index in 0..8 {
+                let position = ChunkPosition::new(thread, 0, index);
+                assert_eq!(blocks(save.0.load_chunk(position).unwrap()), blocks(Some(chunk(index as u16))));
+            }
+        }
+        assert_eq!(fs::read_dir(save.0.directory()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn missing_chunks_load_as_none() {
+        let save = TemporarySave::new("missing");
+        assert!(save.0.load_chunk(ChunkPosition::new(0, 0, 0)).unwrap().is_none());
+        save.0.save_chunk(ChunkPosition::new(0, 0, 0), &chunk(1)).unwrap();
+        assert!(save.0.load_chunk(ChunkPosition::new(0, 0, 1)).unwrap().is_none());
+        assert!(save.0.load_chunk(ChunkPosition::new(0, 1, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let save = TemporarySave::new("metadata");
+        assert_eq!(save.0.load_metadata().unwrap(), None);
+        let metadata = WorldMetadata::new(42, BlockPosition::new(1, 70, -3));
+        save.0.save_metadata(&metadata).unwrap();
+        assert_eq!(save.0.load_metadata().unwrap(), Some(metadata));
+    }
+
+    #[test]
+    fn metadata_of_other_versions_is_rejected() {
+        let save = TemporarySave::new("metadata-version");
+        let mut bytes = (FORMAT_VERSION + 1).to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"a layout this version knows nothing about");
+        fs::write(save.0.directory().join(METADATA_FILE), &bytes).unwrap();
+        assert!(matches!(save.0.load_metadata(), Err(SaveError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn worlds_round_trip() {
+        let save = TemporarySave::new("world");
+        let mut world = VoxelWorld::new();
+        let positions = [
+            ChunkPosition::new(0, 0, 0), ChunkPosition::new(-1, 0, 0), ChunkPosition::new(31, -2, 31),
+            ChunkPosition::new(32, 5, -33), ChunkPosition::new(-40, 1, 70),
+        ];
+        for (index, position) in positions.iter().enumerate() {
+            world.insert_chunk(*position, chunk(index as u16*20 + 1));
+        }
+        world.insert_chunk(ChunkPosition::new(3, 3, 3), ChunkData::new());
+        save.0.save_world(&world).unwrap();
+
+        let loaded = save.0.load_world().unwrap();
+        assert_eq!(loaded.chunks().count(), world.chunks().count());
+        for (position, chunk) in world.chunks() {
+            let loaded_chunk = loaded.chunk(*position).expect("Chunk missing from the loaded world");
+            assert_eq!(loaded_chunk.chunk_data.as_slice(), chunk.chunk_data.as_slice(), "Blocks of {:?} differ", position);
+        }
+    }
+
+    #[test]
+    fn damaged_regions_are_errors() {
+        let save = TemporarySave::new("damaged");
+        let position = ChunkPosition::new(0, 0, 0);
+        save.0.save_chunk(position, &chunk(1)).unwrap();
+        let path = save.0.region_path(RegionPosition::of_chunk(position));
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(matches!(save.0.load_chunk(position), Err(SaveError::Corrupted(_))));
+        fs::write(&path, &bytes[..100]).unwrap();
+        assert!(matches!(save.0.load_chunk(position), Err(SaveError::Corrupted(_))));
+        fs::write(&path, b"not a region").unwrap();
+        assert!(matches!(save.0.load_chunk(position), Err(SaveError::Corrupted(_))));
+
+        let mut damaged = bytes.clone();
+        let payload = HEADER_SIZE + TABLE_SIZE;
+        for byte in damaged[payload + 1..].iter_mut() {
+            *byte = !*byte;
+        }
+        fs::write(&path, &damaged).unwrap();
+        assert!(save.0.load_chunk(position).is_err());
+
+        let mut newer = bytes;
+        newer[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        assert!(matches!(save.0.load_chunk(position), Err(SaveError::UnsupportedVersion(_))));
+    }
+}
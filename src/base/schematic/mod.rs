@@ -0,0 +1,380 @@
+pub mod nbt;
+pub mod sponge;
+pub mod vox;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use crate::base::block::{BlockId, BlockRegistry, AIR};
+use crate::base::world::{VoxelWorld, BlockPosition};
+
+#[derive(Debug)]
+pub enum SchematicError {
+    Io(io::Error),
+    InvalidFormat(String),
+    TooLarge([usize; 3]),
+    InvalidMapping(String),
+}
+
+impl fmt::Display for SchematicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchematicError::Io(error) => write!(f, "I/O error: {}", error),
+            SchematicError::InvalidFormat(reason) => write!(f, "Invalid schematic: {}", reason),
+            SchematicError::TooLarge(size) => write!(f, "Schematic of size {:?} is too large for this format", size),
+            SchematicError::InvalidMapping(reason) => write!(f, "Invalid block mapping: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SchematicError {}
+
+impl From<io::Error> for SchematicError {
+    fn from(error: io::Error) -> Self {
+        SchematicError::Io(error)
+    }
+}
+
+/// Quarter turns around the y axis, clockwise when looking down.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+/// Applied to a schematic when pasting it: mirroring first, then rotation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Transform {
+    pub rotation: Rotation,
+    pub mirror_x: bool,
+    pub mirror_z: bool,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform { rotation: Rotation::None, mirror_x: false, mirror_z: false }
+    }
+}
+
+impl Transform {
+    /// Size of a region of size `size` after the transform.
+    pub fn transformed_size(&self, size: [usize; 3]) -> [usize; 3] {
+        match self.rotation {
+            Rotation::None | Rotation::Clockwise180 => size,
+            Rotation::Clockwise90 | Rotation::Clockwise270 => [size[2], size[1], size[0]],
+        }
+    }
+
+    /// Where the block at `(x, y, z)` of a region of size `size` ends up after the transform.
+    pub fn apply(&self, size: [usize; 3], x: usize, y: usize, z: usize) -> (usize, usize, usize) {
+        let [width, _, length] = size;
+        let x = if self.mirror_x { width - 1 - x } else { x };
+        let z = if self.mirror_z { length - 1 - z } else { z };
+        match self.rotation {
+            Rotation::None => (x, y, z),
+            Rotation::Clockwise90 => (length - 1 - z, y, x),
+            Rotation::Clockwise180 => (width - 1 - x, y, length - 1 - z),
+            Rotation::Clockwise270 => (z, y, width - 1 - x),
+        }
+    }
+}
+
+/// Largest number of blocks read from a schematic file, so that a damaged or malicious file
+/// cannot make the reader allocate gigabytes.
+pub const MAX_BLOCK_COUNT: usize = 1 << 26;
+
+/// A rectangular region of blocks, independent of any world.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schematic {
+    /// Width (x), height (y) and length (z).
+    pub size: [usize; 3],
+    blocks: Vec<BlockId>,
+}
+
+impl Schematic {
+    pub fn new(size: [usize; 3]) -> Self {
+        Schematic { size, blocks: vec![AIR; size[0]*size[1]*size[2]] }
+    }
+
+    /// Checks a size read from a file before a schematic is allocated for it.
+    pub(crate) fn check_size(size: [usize; 3]) -> Result<[usize; 3], SchematicError> {
+        let block_count = size[0].checked_mul(size[1]).and_then(|count| count.checked_mul(size[2]));
+        match block_count {
+            Some(block_count) if block_count <= MAX_BLOCK_COUNT => Ok(size),
+            _ => Err(SchematicError::InvalidFormat(format!("size {:?} holds more than {} blocks", size, MAX_BLOCK_COUNT))),
+        }
+    }
+
+    /// Same ordering as Sponge schematics: x varies fastest, then z, then y.
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        if x >= self.size[0] || y >= self.size[1] || z >= self.size[2] {
+            panic!("Invalid schematic position: Tried to access position ({}, {}, {}) on schematic of size {:?}",
+                   x, y, z, self.size);
+        }
+        (y*self.size[2] + z)*self.size[0] + x
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> BlockId {
+        self.blocks[self.index(x, y, z)]
+    }
+
+    pub fn set(&mut self, block: BlockId, x: usize, y: usize, z: usize) {
+        let index = self.index(x, y, z);
+        self.blocks[index] = block;
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Copies the region between the corners `min` and `max`, both included.
+    /// Blocks in unloaded chunks are copied as air.
+    pub fn copy_from(world: &VoxelWorld, min: BlockPosition, max: BlockPosition) -> Self {
+        let low = BlockPosition::new(min.x.min(max.x), min.y.min(max.y), min.z.min(max.z));
+        let high = BlockPosition::new(min.x.max(max.x), min.y.max(max.y), min.z.max(max.z));
+        let size = [
+            (high.x - low.x + 1) as usize,
+            (high.y - low.y + 1) as usize,
+            (high.z - low.z + 1) as usize,
+        ];
+        let mut schematic = Schematic::new(size);
+        for y in 0..size[1] {
+            for z in 0..size[2] {
+                for x in 0..size[0] {
+                    schematic.set(world.get(low.offset(x as i32, y as i32, z as i32)), x, y, z);
+                }
+            }
+        }
+        return schematic;
+    }
+
+    /// Pastes the schematic with its transformed minimum corner at `origin`, returning how many blocks changed.
+    /// Air in the schematic leaves the world untouched when `skip_air` is set.
+    pub fn paste(&self, world: &mut VoxelWorld, origin: BlockPosition, transform: Transform, skip_air: bool) -> usize {
+        let mut changed = 0;
+        for y in 0..self.size[1] {
+            for z in 0..self.size[2] {
+                for x in 0..self.size[0] {
+                    let block = self.get(x, y, z);
+                    if skip_air && block == AIR {
+                        continue;
+                    }
+                    let (tx, ty, tz) = transform.apply(self.size, x, y, z);
+                    let position = origin.offset(tx as i32, ty as i32, tz as i32);
+                    if let Some(old) = world.set(position, block) {
+                        if old != block {
+                            changed += 1;
+                        }
+                    }
+                }
+            }
+        }
+        return changed;
+    }
+}
+
+/// Maps the block names used by schematic files, and MagicaVoxel palette indices, to registry blocks.
+pub struct BlockMapping {
+    names_to_blocks: HashMap<String, BlockId>,
+    blocks_to_names: HashMap<BlockId, String>,
+    vox_to_blocks: HashMap<u8, BlockId>,
+    blocks_to_vox: HashMap<BlockId, u8>,
+    /// Used for names and palette indices that are not in the table.
+    pub unknown_block: BlockId,
+}
+
+pub const NAMESPACE: &str = "rustyblocks";
+
+impl BlockMapping {
+    /// Maps every registered block to `rustyblocks:<name>`, except air which is `minecraft:air` both ways
+    /// (`rustyblocks:air` is still read as air).
+    /// Palette index `n` of .vox files maps to the block with id `n`.
+    pub fn from_registry(registry: &BlockRegistry) -> Self {
+        let mut mapping = BlockMapping {
+            names_to_blocks: HashMap::new(),
+            blocks_to_names: HashMap::new(),
+            vox_to_blocks: HashMap::new(),
+            blocks_to_vox: HashMap::new(),
+            unknown_block: AIR,
+        };
+        for (id, block) in registry.iter() {
+            let name = format!("{}:{}", NAMESPACE, block.name);
+            mapping.names_to_blocks.insert(name.clone(), id);
+            mapping.blocks_to_names.insert(id, name);
+            if id != AIR && id <= 255 {
+                mapping.vox_to_blocks.insert(id as u8, id);
+                mapping.blocks_to_vox.insert(id, id as u8);
+            }
+        }
+        mapping.names_to_blocks.insert("minecraft:air".into(), AIR);
+        mapping.blocks_to_names.insert(AIR, "minecraft:air".into());
+        return mapping;
+    }
+
+    /// Adds the entries of a mapping table on top of the current ones. Each line of the table
+    /// has the form `<external name> = <registry block name>`, where the external name is either
+    /// a namespaced block name such as `minecraft:stone` or a .vox palette index such as `vox:12`.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn with_table(mut self, table: &str, registry: &BlockRegistry) -> Result<Self, SchematicError> {
+        for (number, line) in table.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            let (external, internal) = match (parts.next(), parts.next()) {
+                (Some(external), Some(internal)) if !external.is_empty() => (external, internal),
+                _ => return Err(SchematicError::InvalidMapping(format!("line {}: expected `<external> = <block>`", number + 1))),
+            };
+            let block = registry.id_of(internal)
+                .ok_or_else(|| SchematicError::InvalidMapping(format!("line {}: unknown block {}", number + 1, internal)))?;
+            if let Some(index) = external.strip_prefix("vox:") {
+                let index: u8 = index.parse()
+                    .map_err(|_| SchematicError::InvalidMapping(format!("line {}: invalid palette index {}", number + 1, index)))?;
+                self.vox_to_blocks.insert(index, block);
+                self.blocks_to_vox.insert(block, index);
+            } else {
+                self.names_to_blocks.insert(external.to_owned(), block);
+                self.blocks_to_names.insert(block, external.to_owned());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Looks up a block state such as `minecraft:oak_log[axis=y]`, falling back to the name without properties.
+    pub fn block_for_name(&self, name: &str) -> BlockId {
+        if let Some(block) = self.names_to_blocks.get(name) {
+            return *block;
+        }
+        let base_name = name.split('[').next().unwrap_or(name);
+        self.names_to_blocks.get(base_name).cloned().unwrap_or(self.unknown_block)
+    }
+
+    pub fn name_for_block(&self, block: BlockId) -> String {
+        match self.blocks_to_names.get(&block) {
+            Some(name) => name.clone(),
+            None => format!("{}:unknown_{}", NAMESPACE, block),
+        }
+    }
+
+    pub fn block_for_vox_index(&self, index: u8) -> BlockId {
+        self.vox_to_blocks.get(&index).cloned().unwrap_or(self.unknown_block)
+    }
+
+    /// Palette index to use for `block` in .vox files, or `None` if it has none.
+    pub fn vox_index_for_block(&self, block: BlockId) -> Option<u8> {
+        self.blocks_to_vox.get(&block).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use crate::base::block::BlockDefinition;
+    use crate::base::schematic::nbt::Tag;
+
+    fn mapping() -> BlockMapping {
+        let mut registry = BlockRegistry::new();
+        registry.register(BlockDefinition::new("stone"));
+        registry.register(BlockDefinition::new("log"));
+        BlockMapping::from_registry(&registry)
+            .with_table("minecraft:stone = stone\nminecraft:oak_log = log", &registry)
+            .unwrap()
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/schematics").join(name)).unwrap()
+    }
+
+    /// The structure stored in every fixture, see `tests/schematics/README.md`.
+    fn structure(mapping: &BlockMapping) -> Schematic {
+        let (stone, log) = (mapping.block_for_name("minecraft:stone"), mapping.block_for_name("minecraft:oak_log"));
+        let mut schematic = Schematic::new([3, 2, 2]);
+        for z in 0..2 {
+            for x in 0..3 {
+                schematic.set(stone, x, 0, z);
+            }
+        }
+        schematic.set(log, 0, 1, 0);
+        schematic.set(log, 2, 1, 1);
+        schematic
+    }
+
+    #[test]
+    fn sponge_schematics_round_trip() {
+        let mapping = mapping();
+        for name in ["structure_v2.schem", "structure_v3.schem"].iter() {
+            let schematic = sponge::read(&fixture(name)[..], &mapping).unwrap();
+            assert_eq!(schematic, structure(&mapping), "{}", name);
+            let mut bytes = vec![];
+            sponge::write(&mut bytes, &schematic, &mapping).unwrap();
+            assert_eq!(sponge::read(&bytes[..], &mapping).unwrap(), schematic, "{}", name);
+        }
+    }
+
+    #[test]
+    fn air_is_minecraft_air_both_ways() {
+        let mapping = mapping();
+        assert_eq!(mapping.name_for_block(AIR), "minecraft:air");
+        assert_eq!(mapping.block_for_name("minecraft:air"), AIR);
+        assert_eq!(mapping.block_for_name("rustyblocks:air"), AIR);
+
+        let mut bytes = vec![];
+        sponge::write(&mut bytes, &structure(&mapping), &mapping).unwrap();
+        let (_, root) = nbt::read(&mut flate2::read::GzDecoder::new(&bytes[..])).unwrap();
+        let names: Vec<_> = root.get("Palette").and_then(Tag::as_compound).unwrap().keys().cloned().collect();
+        assert!(names.contains(&"minecraft:air".to_owned()), "{:?}", names);
+        assert!(!names.iter().any(|name| name.ends_with(":air") && name != "minecraft:air"), "{:?}", names);
+    }
+
+    #[test]
+    fn vox_models_round_trip() {
+        let mapping = mapping();
+        let schematic = vox::read(&fixture("structure.vox")[..], &mapping).unwrap();
+        assert_eq!(schematic, structure(&mapping));
+        let mut bytes = vec![];
+        vox::write(&mut bytes, &schematic, &mapping).unwrap();
+        assert_eq!(vox::read(&bytes[..], &mapping).unwrap(), schematic);
+    }
+
+    #[test]
+    fn vox_sizes_are_validated() {
+        let mapping = mapping();
+        let damaged = |offset: usize, values: &[i32]| {
+            let mut bytes = fixture("structure.vox");
+            for (index, value) in values.iter().enumerate() {
+                bytes[offset + index*4..offset + index*4 + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            vox::read(&bytes[..], &mapping)
+        };
+        // MAIN content size, then the SIZE chunk's content size and content
+        for (offset, values) in [(12, vec![-1]), (24, vec![-12]), (24, vec![i32::MAX]), (32, vec![-3, 2, 2]), (32, vec![100_000; 3])].iter() {
+            match damaged(*offset, values) {
+                Err(SchematicError::InvalidFormat(_)) => {}
+                result => panic!("{:?} at {} was read as {:?}", values, offset, result),
+            }
+        }
+    }
+
+    #[test]
+    fn sponge_sizes_are_validated() {
+        let mut root = BTreeMap::new();
+        root.insert("Version".to_owned(), Tag::Int(2));
+        for name in ["Width", "Height", "Length"].iter() {
+            root.insert(name.to_string(), Tag::Short(-1));
+        }
+        root.insert("Palette".to_owned(), Tag::Compound(BTreeMap::new()));
+        root.insert("BlockData".to_owned(), Tag::ByteArray(vec![]));
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        nbt::write(&mut encoder, "Schematic", &Tag::Compound(root)).unwrap();
+        let bytes = encoder.finish().unwrap();
+        match sponge::read(&bytes[..], &mapping()) {
+            Err(SchematicError::InvalidFormat(_)) => {}
+            result => panic!("65535^3 blocks were read as {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Just enough of the NBT format to read and write schematics.
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(BTreeMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+const END: u8 = 0;
+/// Deepest nesting of lists and compounds that is read, the same limit as Minecraft's.
+pub const MAX_DEPTH: usize = 512;
+/// Lengths are read from the file, so vectors only grow as the elements actually arrive.
+const MAX_PREALLOCATED: usize = 4096;
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(entries) => entries.get(name),
+            _ => None,
+        }
+    }
+
+    /// Reads any integer tag as an `i64`.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Tag::Byte(value) => Some(*value as i64),
+            Tag::Short(value) => Some(*value as i64),
+            Tag::Int(value) => Some(*value as i64),
+            Tag::Long(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&BTreeMap<String, Tag>> {
+        match self {
+            Tag::Compound(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte_array(&self) -> Option<&[i8]> {
+        match self {
+            Tag::ByteArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_int_array(&self) -> Option<&[i32]> {
+        match self {
+            Tag::IntArray(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn read_bytes<R: Read>(reader: &mut R, count: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(count.min(MAX_PREALLOCATED));
+    reader.take(count as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < count {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes, the data ends after {}", count, bytes.len())));
+    }
+    Ok(bytes)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    Ok(read_bytes(reader, 1)?[0])
+}
+
+fn read_i16<R: Read>(reader: &mut R) -> io::Result<i16> {
+    let bytes = read_bytes(reader, 2)?;
+    Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_be_bytes(bytes))
+}
+
+fn read_i64<R: Read>(reader: &mut R) -> io::Result<i64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_be_bytes(bytes))
+}
+
+fn read_length<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let length = read_i32(reader)?;
+    if length < 0 {
+        return Err(invalid(format!("negative length {}", length)));
+    }
+    Ok(length as usize)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = read_i16(reader)? as u16 as usize;
+    String::from_utf8(read_bytes(reader, length)?).map_err(|error| invalid(error.to_string()))
+}
+
+fn read_payload<R: Read>(reader: &mut R, id: u8, depth: usize) -> io::Result<Tag> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("tags nested deeper than {}", MAX_DEPTH)));
+    }
+    Ok(match id {
+        1 => Tag::Byte(read_u8(reader)? as i8),
+        2 => Tag::Short(read_i16(reader)?),
+        3 => Tag::Int(read_i32(reader)?),
+        4 => Tag::Long(read_i64(reader)?),
+        5 => Tag::Float(f32::from_bits(read_i32(reader)? as u32)),
+        6 => Tag::Double(f64::from_bits(read_i64(reader)? as u64)),
+        7 => {
+            let length = read_length(reader)?;
+            Tag::ByteArray(read_bytes(reader, length)?.into_iter().map(|byte| byte as i8).collect())
+        }
+        8 => Tag::String(read_string(reader)?),
+        9 => {
+            let element_id = read_u8(reader)?;
+            let length = read_length(reader)?;
+            let mut elements = Vec::with_capacity(length.min(MAX_PREALLOCATED));
+            for _ in 0..length {
+                elements.push(read_payload(reader, element_id, depth + 1)?);
+            }
+            Tag::List(elements)
+        }
+        10 => {
+            let mut entries = BTreeMap::new();
+            loop {
+                let entry_id = read_u8(reader)?;
+                if entry_id == END {
+                    break;
+                }
+                let name = read_string(reader)?;
+                entries.insert(name, read_payload(reader, entry_id, depth + 1)?);
+            }
+            Tag::Compound(entries)
+        }
+        11 => {
+            let length = read_length(reader)?;
+            let mut values = Vec::with_capacity(length.min(MAX_PREALLOCATED));
+            for _ in 0..length {
+                values.push(read_i32(reader)?);
+            }
+            Tag::IntArray(values)
+        }
+        12 => {
+            let length = read_length(reader)?;
+            let mut values = Vec::with_capacity(length.min(MAX_PREALLOCATED));
+            for _ in 0..length {
+                values.push(read_i64(reader)?);
+            }
+            Tag::LongArray(values)
+        }
+        _ => return Err(invalid(format!("unknown tag id {}", id))),
+    })
+}
+
+/// Reads an uncompressed named root tag, returning its name and value.
+pub fn read<R: Read>(reader: &mut R) -> io::Result<(String, Tag)> {
+    let id = read_u8(reader)?;
+    if id == END {
+        return Err(invalid("missing root tag".into()));
+    }
+    let name = read_string(reader)?;
+    Ok((name, read_payload(reader, id, 0)?))
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    if value.len() > u16::MAX as usize {
+        return Err(invalid(format!("string of {} bytes is too long", value.len())));
+    }
+    writer.write_all(&(value.len() as u16).to_be_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+fn write_payload<W: Write>(writer: &mut W, tag: &Tag) -> io::Result<()> {
+    match tag {
+        Tag::Byte(value) => writer.write_all(&[*value as u8]),
+        Tag::Short(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Int(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Long(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Float(value) => writer.write_all(&value.to_bits().to_be_bytes()),
+        Tag::Double(value) => writer.write_all(&value.to_bits().to_be_bytes()),
+        Tag::ByteArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            writer.write_all(&values.iter().map(|value| *value as u8).collect::<Vec<_>>())
+        }
+        Tag::String(value) => write_string(writer, value),
+        Tag::List(elements) => {
+            let element_id = elements.first().map_or(END, |element| element.id());
+            if elements.iter().any(|element| element.id() != element_id) {
+                return Err(invalid("list elements must all have the same type".into()));
+            }
+            writer.write_all(&[element_id])?;
+            writer.write_all(&(elements.len() as i32).to_be_bytes())?;
+            for element in elements {
+                write_payload(writer, element)?;
+            }
+            Ok(())
+        }
+        Tag::Compound(entries) => {
+            for (name, entry) in entries {
+                writer.write_all(&[entry.id()])?;
+                write_string(writer, name)?;
+                write_payload(writer, entry)?;
+            }
+            writer.write_all(&[END])
+        }
+        Tag::IntArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for value in values {
+                writer.write_all(&value.to_be_bytes())?;
+            }
+            Ok(())
+        }
+        Tag::LongArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for value in values {
+                writer.write_all(&value.to_be_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Writes an uncompressed named root tag.
+pub fn write<W: Write>(writer: &mut W, name: &str, tag: &Tag) -> io::Result<()> {
+    writer.write_all(&[tag.id()])?;
+    write_string(writer, name)?;
+    write_payload(writer, tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![10, 0, 0];
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn lengths_beyond_the_data_are_errors() {
+        // A byte array, an int array and a string claiming far more than the few bytes that follow
+        for payload in [&[7, 0, 1, b'a', 0x7f, 0xff, 0xff, 0xff, 1, 2, 3][..], &[11, 0, 1, b'a', 0x7f, 0xff, 0xff, 0xff, 1, 2, 3, 4], &[8, 0, 1, b'a', 0xff, 0xff, b'b']].iter() {
+            let error = read(&mut &root(payload)[..]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| {
+            // Lists of lists, the innermost one empty
+            let mut payload = vec![9, 0, 1, b'a'];
+            for _ in 0..depth {
+                payload.extend_from_slice(&[9, 0, 0, 0, 1]);
+            }
+            payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+            payload.push(END);
+            read(&mut &root(&payload)[..])
+        };
+        assert!(nested(MAX_DEPTH - 2).is_ok());
+        assert_eq!(nested(100_000).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let mut entries = BTreeMap::new();
+        entries.insert("bytes".to_owned(), Tag::ByteArray(vec![-1, 0, 1]));
+        entries.insert("list".to_owned(), Tag::List(vec![Tag::Short(1), Tag::Short(-2)]));
+        entries.insert("longs".to_owned(), Tag::LongArray(vec![i64::MIN, 5]));
+        entries.insert("name".to_owned(), Tag::String("stone".into()));
+        let tag = Tag::Compound(entries);
+        let mut bytes = vec![];
+        write(&mut bytes, "root", &tag).unwrap();
+        assert_eq!(read(&mut &bytes[..]).unwrap(), ("root".to_owned(), tag));
+    }
+}
@@ -0,0 +1,125 @@
+//! Sponge schematics (.schem): gzip compressed NBT, read in versions 1 to 3 and written in version 2.
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use crate::base::schematic::{Schematic, BlockMapping, SchematicError};
+use crate::base::schematic::nbt::{self, Tag};
+
+const WRITTEN_VERSION: i32 = 2;
+/// Minecraft 1.16.5, the data version the palette names are written for.
+const DATA_VERSION: i32 = 2586;
+
+fn invalid(reason: &str) -> SchematicError {
+    SchematicError::InvalidFormat(reason.to_owned())
+}
+
+fn decode_varints(bytes: &[i8], count: usize) -> Result<Vec<usize>, SchematicError> {
+    let mut values = Vec::with_capacity(count);
+    let mut value = 0usize;
+    let mut shift = 0;
+    for byte in bytes {
+        let byte = *byte as u8;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            values.push(value);
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+            if shift > 28 {
+                return Err(invalid("varint is too long"));
+            }
+        }
+    }
+    if values.len() != count {
+        return Err(SchematicError::InvalidFormat(format!("expected {} blocks, found {}", count, values.len())));
+    }
+    Ok(values)
+}
+
+fn encode_varint(mut value: usize, bytes: &mut Vec<i8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte as i8);
+            return;
+        }
+        bytes.push((byte | 0x80) as i8);
+    }
+}
+
+fn dimension(root: &Tag, name: &str) -> Result<usize, SchematicError> {
+    root.get(name)
+        .and_then(Tag::as_integer)
+        // Dimensions are stored as shorts but meant to be read as unsigned
+        .map(|value| value as u16 as usize)
+        .ok_or_else(|| SchematicError::InvalidFormat(format!("missing {}", name)))
+}
+
+pub fn read<R: Read>(reader: R, mapping: &BlockMapping) -> Result<Schematic, SchematicError> {
+    let (_, root) = nbt::read(&mut flate2::read::GzDecoder::new(reader))?;
+    // Version 3 nests everything in a `Schematic` compound under an unnamed root
+    let root = match root.get("Schematic") {
+        Some(schematic) => schematic,
+        None => &root,
+    };
+    let version = root.get("Version").and_then(Tag::as_integer).ok_or_else(|| invalid("missing Version"))?;
+    let size = Schematic::check_size([dimension(root, "Width")?, dimension(root, "Height")?, dimension(root, "Length")?])?;
+    let (palette, data) = if version >= 3 {
+        let blocks = root.get("Blocks").ok_or_else(|| invalid("missing Blocks"))?;
+        (blocks.get("Palette"), blocks.get("Data"))
+    } else {
+        (root.get("Palette"), root.get("BlockData"))
+    };
+    let palette = palette.and_then(Tag::as_compound).ok_or_else(|| invalid("missing Palette"))?;
+    let data = data.and_then(Tag::as_byte_array).ok_or_else(|| invalid("missing block data"))?;
+
+    let mut blocks_by_index = HashMap::new();
+    for (name, index) in palette {
+        let index = index.as_integer().ok_or_else(|| invalid("palette entries must be integers"))?;
+        blocks_by_index.insert(index as usize, mapping.block_for_name(name));
+    }
+
+    let mut schematic = Schematic::new(size);
+    let indices = decode_varints(data, schematic.block_count())?;
+    for (position, index) in indices.into_iter().enumerate() {
+        schematic.blocks[position] = *blocks_by_index.get(&index)
+            .ok_or_else(|| SchematicError::InvalidFormat(format!("palette index {} is not in the palette", index)))?;
+    }
+    Ok(schematic)
+}
+
+pub fn write<W: Write>(writer: W, schematic: &Schematic, mapping: &BlockMapping) -> Result<(), SchematicError> {
+    if schematic.size.iter().any(|size| *size > u16::MAX as usize) {
+        return Err(SchematicError::TooLarge(schematic.size));
+    }
+
+    let mut palette_indices = HashMap::new();
+    let mut palette = BTreeMap::new();
+    let mut data = Vec::with_capacity(schematic.block_count());
+    for block in schematic.blocks.iter() {
+        let next_index = palette_indices.len();
+        let index = *palette_indices.entry(*block).or_insert_with(|| {
+            palette.insert(mapping.name_for_block(*block), Tag::Int(next_index as i32));
+            next_index
+        });
+        encode_varint(index, &mut data);
+    }
+
+    let mut root = BTreeMap::new();
+    root.insert("Version".to_owned(), Tag::Int(WRITTEN_VERSION));
+    root.insert("DataVersion".to_owned(), Tag::Int(DATA_VERSION));
+    root.insert("Width".to_owned(), Tag::Short(schematic.size[0] as u16 as i16));
+    root.insert("Height".to_owned(), Tag::Short(schematic.size[1] as u16 as i16));
+    root.insert("Length".to_owned(), Tag::Short(schematic.size[2] as u16 as i16));
+    root.insert("Offset".to_owned(), Tag::IntArray(vec![0, 0, 0]));
+    root.insert("PaletteMax".to_owned(), Tag::Int(palette.len() as i32));
+    root.insert("Palette".to_owned(), Tag::Compound(palette));
+    root.insert("BlockData".to_owned(), Tag::ByteArray(data));
+    root.insert("BlockEntities".to_owned(), Tag::List(vec![]));
+
+    let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+    nbt::write(&mut encoder, "Schematic", &Tag::Compound(root))?;
+    encoder.finish()?;
+    Ok(())
+}
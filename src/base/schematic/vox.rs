@@ -0,0 +1,131 @@
+//! MagicaVoxel models (.vox). Only the first model of a file is imported, scene graph chunks are ignored.
+//! MagicaVoxel uses z as the up axis, so its y and z axes are swapped on import and export.
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use crate::base::block::AIR;
+use crate::base::schematic::{Schematic, BlockMapping, SchematicError};
+
+const VERSION: i32 = 150;
+/// Largest model size along any axis MagicaVoxel can handle.
+pub const MAX_SIZE: usize = 256;
+
+fn invalid(reason: &str) -> SchematicError {
+    SchematicError::InvalidFormat(reason.to_owned())
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> Result<i32, SchematicError> {
+    bytes.get(offset..offset + 4)
+        .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid("unexpected end of file"))
+}
+
+/// Reads a size or count, which the format stores as a signed integer.
+fn read_size(bytes: &[u8], offset: usize) -> Result<usize, SchematicError> {
+    let value = read_i32(bytes, offset)?;
+    if value < 0 {
+        return Err(SchematicError::InvalidFormat(format!("negative size {}", value)));
+    }
+    Ok(value as usize)
+}
+
+pub fn read<R: Read>(mut reader: R, mapping: &BlockMapping) -> Result<Schematic, SchematicError> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    if bytes.len() < 8 || &bytes[0..4] != b"VOX " {
+        return Err(invalid("missing VOX header"));
+    }
+    if bytes.get(8..12) != Some(b"MAIN") {
+        return Err(invalid("missing MAIN chunk"));
+    }
+    let main_content_size = read_size(&bytes, 12)?;
+    let mut offset = main_content_size.checked_add(20).ok_or_else(|| invalid("MAIN chunk is too large"))?;
+
+    let mut schematic: Option<Schematic> = None;
+    while bytes.len().saturating_sub(offset) >= 12 {
+        let id = &bytes[offset..offset + 4];
+        let content_size = read_size(&bytes, offset + 4)?;
+        let children_size = read_size(&bytes, offset + 8)?;
+        let content_end = (offset + 12).checked_add(content_size)
+            .ok_or_else(|| invalid("chunk extends past the end of the file"))?;
+        let content = bytes.get(offset + 12..content_end)
+            .ok_or_else(|| invalid("chunk extends past the end of the file"))?;
+        match id {
+            b"SIZE" if schematic.is_none() => {
+                let size_x = read_size(content, 0)?;
+                let size_y = read_size(content, 4)?;
+                let size_z = read_size(content, 8)?;
+                schematic = Some(Schematic::new(Schematic::check_size([size_x, size_z, size_y])?));
+            }
+            b"XYZI" => {
+                let schematic = schematic.as_mut().ok_or_else(|| invalid("XYZI chunk before SIZE chunk"))?;
+                let count = read_size(content, 0)?;
+                let voxels = count.checked_mul(4)
+                    .and_then(|length| length.checked_add(4))
+                    .and_then(|end| content.get(4..end))
+                    .ok_or_else(|| invalid("truncated XYZI chunk"))?;
+                for voxel in voxels.chunks_exact(4) {
+                    let (x, y, z) = (voxel[0] as usize, voxel[2] as usize, voxel[1] as usize);
+                    if x >= schematic.size[0] || y >= schematic.size[1] || z >= schematic.size[2] {
+                        return Err(invalid("voxel outside of the model"));
+                    }
+                    schematic.set(mapping.block_for_vox_index(voxel[3]), x, y, z);
+                }
+                // Only the first model is imported
+                break;
+            }
+            _ => {}
+        }
+        offset = content_end.checked_add(children_size).ok_or_else(|| invalid("chunk is too large"))?;
+    }
+    schematic.ok_or_else(|| invalid("no model in file"))
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(content.len() as i32).to_le_bytes());
+    bytes.extend_from_slice(&(children.len() as i32).to_le_bytes());
+    bytes.extend_from_slice(content);
+    bytes.extend_from_slice(children);
+}
+
+/// Writes the schematic as a single model. Blocks without a palette index in `mapping` are left out.
+pub fn write<W: Write>(mut writer: W, schematic: &Schematic, mapping: &BlockMapping) -> Result<(), SchematicError> {
+    if schematic.size.iter().any(|size| *size > MAX_SIZE) {
+        return Err(SchematicError::TooLarge(schematic.size));
+    }
+    let [width, height, length] = schematic.size;
+
+    let mut size = vec![];
+    for value in [width, length, height].iter() {
+        size.extend_from_slice(&(*value as i32).to_le_bytes());
+    }
+
+    let mut voxels = vec![];
+    let mut count = 0i32;
+    for y in 0..height {
+        for z in 0..length {
+            for x in 0..width {
+                let block = schematic.get(x, y, z);
+                if block == AIR {
+                    continue;
+                }
+                if let Some(index) = mapping.vox_index_for_block(block) {
+                    voxels.extend_from_slice(&[x as u8, z as u8, y as u8, index]);
+                    count += 1;
+                }
+            }
+        }
+    }
+    let mut xyzi = count.to_le_bytes().to_vec();
+    xyzi.extend_from_slice(&voxels);
+
+    let mut children = vec![];
+    write_chunk(&mut children, b"SIZE", &size, &[]);
+    write_chunk(&mut children, b"XYZI", &xyzi, &[]);
+
+    let mut bytes = b"VOX ".to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_chunk(&mut bytes, b"MAIN", &[], &children);
+    writer.write_all(&bytes)?;
+    Ok(())
+}
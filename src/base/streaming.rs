@@ -0,0 +1,511 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use specs::prelude::*;
+use crate::base::generator::ChunkGenerator;
+use crate::base::persistence::{WorldSave, SaveError};
+use crate::base::voxel::{ChunkData, CHUNK_SIZE};
+use crate::base::world::{VoxelWorld, ChunkPosition};
+
+#[derive(Clone, Debug)]
+pub struct StreamingSettings {
+    /// Chunks whose center is within this many chunks of the viewer, horizontally, are kept loaded.
+    pub radius: i32,
+    /// Same as `radius`, along the y axis.
+    pub vertical_radius: i32,
+    /// Chunks are only evicted once they are this many chunks outside of the radius, so moving
+    /// back and forth over a chunk border does not reload the same chunks over and over.
+    pub eviction_margin: i32,
+    pub worker_threads: usize,
+    /// Maximum number of chunk jobs handed to the workers that have not been inserted yet.
+    pub max_in_flight: usize,
+    pub max_inserts_per_frame: usize,
+    pub max_evictions_per_frame: usize,
+    /// How long a chunk that failed to save stays loaded before it is evicted and saved again.
+    pub save_retry_interval: Duration,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        StreamingSettings {
+            radius: 6,
+            vertical_radius: 2,
+            eviction_margin: 1,
+            worker_threads: 2,
+            max_in_flight: 16,
+            max_inserts_per_frame: 4,
+            max_evictions_per_frame: 4,
+            save_retry_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Where a chunk is in the streaming pipeline. Chunks that are in the world are not tracked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChunkStage {
+    /// Handed to the workers, but no worker has picked it up yet.
+    Requested,
+    LoadingFromDisk,
+    Generating,
+    /// Done on the worker, waiting for the frame budget to be inserted in the world.
+    Ready,
+    /// Removed from the world, waiting for the worker to finish writing it to disk.
+    Saving,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StreamingStats {
+    pub stage_counts: HashMap<ChunkStage, usize>,
+    pub loaded_chunks: usize,
+    pub inserted_last_frame: usize,
+    pub evicted_last_frame: usize,
+    pub loaded_from_disk: usize,
+    pub generated: usize,
+    pub errors: usize,
+}
+
+enum Job {
+    Load(ChunkPosition),
+    Save(ChunkPosition, ChunkData),
+}
+
+enum WorkerMessage {
+    Stage(ChunkPosition, ChunkStage),
+    Loaded(ChunkPosition, ChunkData, bool),
+    Saved(ChunkPosition),
+    Failed(ChunkPosition, SaveError),
+    /// Gives the chunk back so it is not lost.
+    SaveFailed(ChunkPosition, ChunkData, SaveError),
+}
+
+fn worker_loop(jobs: Arc<Mutex<Receiver<Job>>>, messages: Sender<WorkerMessage>, save: Option<Arc<WorldSave>>, generator: Arc<dyn ChunkGenerator>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let message = match job {
+            Job::Load(position) => {
+                let loaded = match &save {
+                    Some(save) => {
+                        let _ = messages.send(WorkerMessage::Stage(position, ChunkStage::LoadingFromDisk));
+                        save.load_chunk(position)
+                    }
+                    None => Ok(None),
+                };
+                match loaded {
+                    Ok(Some(chunk_data)) => WorkerMessage::Loaded(position, chunk_data, true),
+                    Ok(None) => {
+                        let _ = messages.send(WorkerMessage::Stage(position, ChunkStage::Generating));
+                        WorkerMessage::Loaded(position, generator.generate(position), false)
+                    }
+                    Err(error) => WorkerMessage::Failed(position, error),
+                }
+            }
+            Job::Save(position, chunk_data) => {
+                match save.as_ref().map(|save| save.save_chunk(position, &chunk_data)) {
+                    Some(Err(error)) => WorkerMessage::SaveFailed(position, chunk_data, error),
+                    _ => WorkerMessage::Saved(position),
+                }
+            }
+        };
+        if messages.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+/// Keeps the chunks around a viewer loaded, generating or loading them on background threads
+/// and saving the modified ones when they leave the radius.
+pub struct ChunkStreamer {
+    pub settings: StreamingSettings,
+    save: Option<Arc<WorldSave>>,
+    // Wrapped in mutexes so the streamer can be stored as a specs resource
+    jobs: Option<Mutex<Sender<Job>>>,
+    messages: Mutex<Receiver<WorkerMessage>>,
+    workers: Vec<JoinHandle<()>>,
+    stages: HashMap<ChunkPosition, ChunkStage>,
+    ready: HashMap<ChunkPosition, ChunkData>,
+    /// Chunks put back in the world after their save failed, with when they may be evicted again.
+    failed_saves: HashMap<ChunkPosition, Instant>,
+    workers_stopped: bool,
+    stats: StreamingStats,
+}
+
+impl ChunkStreamer {
+    pub fn new(settings: StreamingSettings, save: Option<Arc<WorldSave>>, generator: Arc<dyn ChunkGenerator>) -> Self {
+        let (job_sender, job_receiver) = channel();
+        let (message_sender, message_receiver) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..settings.worker_threads.max(1)).map(|index| {
+            let jobs = job_receiver.clone();
+            let messages = message_sender.clone();
+            let save = save.clone();
+            let generator = generator.clone();
+            std::thread::Builder::new()
+                .name(format!("chunk-streaming-{}", index))
+                .spawn(move || worker_loop(jobs, messages, save, generator))
+                .expect("Failed to spawn chunk streaming worker")
+        }).collect();
+
+        ChunkStreamer {
+            settings,
+            save,
+            jobs: Some(Mutex::new(job_sender)),
+            messages: Mutex::new(message_receiver),
+            workers,
+            stages: HashMap::new(),
+            ready: HashMap::new(),
+            failed_saves: HashMap::new(),
+            workers_stopped: false,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stage(&self, position: ChunkPosition) -> Option<ChunkStage> {
+        self.stages.get(&position).cloned()
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = (&ChunkPosition, &ChunkStage)> {
+        self.stages.iter()
+    }
+
+    pub fn stats(&self) -> &StreamingStats {
+        &self.stats
+    }
+
+    /// Hands a job to the workers, or gives it back if every worker stopped, which only happens when they panicked.
+    fn send(&mut self, job: Job) -> Result<(), Job> {
+        let result = match &self.jobs {
+            Some(jobs) => jobs.lock().unwrap().send(job).map_err(|error| error.0),
+            None => Err(job),
+        };
+        if result.is_err() {
+            self.stats.errors += 1;
+            if !self.workers_stopped {
+                log::error!("Chunk streaming workers stopped, chunks can no longer be loaded or saved");
+                self.workers_stopped = true;
+            }
+        }
+        result
+    }
+
+    /// Puts a chunk that could not be saved back in the world, still modified, so its edits are not lost.
+    fn keep_unsaved(&mut self, world: &mut VoxelWorld, position: ChunkPosition, chunk_data: ChunkData) {
+        world.insert_chunk(position, chunk_data);
+        world.chunk_mut(position).unwrap().must_save = true;
+        self.failed_saves.insert(position, Instant::now() + self.settings.save_retry_interval);
+    }
+
+    fn in_radius(&self, viewer: ChunkPosition, chunk: ChunkPosition, margin: i32) -> bool {
+        let (dx, dy, dz) = (chunk.x - viewer.x, chunk.y - viewer.y, chunk.z - viewer.z);
+        let radius = self.settings.radius + margin;
+        dx*dx + dz*dz <= radius*radius && dy.abs() <= self.settings.vertical_radius + margin
+    }
+
+    /// Lower is loaded first: distance to the viewer, shortened for chunks in front of it.
+    fn priority(viewer_position: [f32; 3], view_direction: [f32; 3], chunk: ChunkPosition) -> f32 {
+        let half = CHUNK_SIZE as f32/2.0;
+        let origin = chunk.origin();
+        let offset = [
+            origin.x as f32 + half - viewer_position[0],
+            origin.y as f32 + half - viewer_position[1],
+            origin.z as f32 + half - viewer_position[2],
+        ];
+        let distance = (offset[0]*offset[0] + offset[1]*offset[1] + offset[2]*offset[2]).sqrt();
+        if distance < 1e-3 {
+            return 0.0;
+        }
+        let alignment = (offset[0]*view_direction[0] + offset[1]*view_direction[1] + offset[2]*view_direction[2])/distance;
+        distance*(1.5 - 0.5*alignment)
+    }
+
+    fn receive_messages(&mut self, world: &mut VoxelWorld) {
+        let messages: Vec<_> = self.messages.lock().unwrap().try_iter().collect();
+        for message in messages {
+            match message {
+                WorkerMessage::Stage(position, stage) => {
+                    self.stages.insert(position, stage);
+                }
+                WorkerMessage::Loaded(position, chunk_data, from_disk) => {
+                    if from_disk {
+                        self.stats.loaded_from_disk += 1;
+                    } else {
+                        self.stats.generated += 1;
+                    }
+                    self.stages.insert(position, ChunkStage::Ready);
+                    self.ready.insert(position, chunk_data);
+                }
+                WorkerMessage::Saved(position) => {
+                    self.stages.remove(&position);
+                }
+                WorkerMessage::Failed(position, error) => {
+                    log::error!("Chunk streaming failed for chunk {:?}: {}", position, error);
+                    self.stats.errors += 1;
+                    self.stages.remove(&position);
+                }
+                WorkerMessage::SaveFailed(position, chunk_data, error) => {
+                    log::error!("Failed to save chunk {:?}, keeping it loaded to retry later: {}", position, error);
+                    self.stats.errors += 1;
+                    self.stages.remove(&position);
+                    self.keep_unsaved(world, position, chunk_data);
+                }
+            }
+        }
+    }
+
+    /// Runs one frame of streaming for a viewer at `viewer_position` looking along `view_direction`.
+    pub fn update(&mut self, world: &mut VoxelWorld, viewer_position: [f32; 3], view_direction: [f32; 3]) {
+        self.receive_messages(world);
+        let size = CHUNK_SIZE as f32;
+        let viewer = ChunkPosition::new(
+            (viewer_position[0]/size).floor() as i32,
+            (viewer_position[1]/size).floor() as i32,
+            (viewer_position[2]/size).floor() as i32);
+
+        // Evict the chunks that left the radius, saving the modified ones
+        let margin = self.settings.eviction_margin;
+        let now = Instant::now();
+        self.failed_saves.retain(|_, retry_at| *retry_at > now);
+        let mut to_evict: Vec<_> = world.chunks()
+            .map(|(position, _)| *position)
+            .filter(|position| !self.in_radius(viewer, *position, margin) && !self.failed_saves.contains_key(position))
+            .collect();
+        to_evict.sort_by(|a, b| Self::priority(viewer_position, view_direction, *b)
+            .partial_cmp(&Self::priority(viewer_position, view_direction, *a)).unwrap());
+        to_evict.truncate(self.settings.max_evictions_per_frame);
+        self.stats.evicted_last_frame = to_evict.len();
+        for position in to_evict {
+            let chunk = world.remove_chunk(position).unwrap();
+            if chunk.must_save && self.save.is_some() {
+                match self.send(Job::Save(position, chunk.chunk_data)) {
+                    Ok(()) => {
+                        self.stages.insert(position, ChunkStage::Saving);
+                    }
+                    Err(Job::Save(_, chunk_data)) => self.keep_unsaved(world, position, chunk_data),
+                    Err(Job::Load(_)) => unreachable!(),
+                }
+            }
+        }
+
+        // Chunks that finished loading after they left the radius are dropped as they are
+        let stale: Vec<_> = self.ready.keys()
+            .filter(|position| !self.in_radius(viewer, **position, margin))
+            .cloned()
+            .collect();
+        for position in stale {
+            self.ready.remove(&position);
+            self.stages.remove(&position);
+        }
+
+        // Insert the closest finished chunks
+        let mut ready: Vec<_> = self.ready.keys().cloned().collect();
+        ready.sort_by(|a, b| Self::priority(viewer_position, view_direction, *a)
+            .partial_cmp(&Self::priority(viewer_position, view_direction, *b)).unwrap());
+        ready.truncate(self.settings.max_inserts_per_frame);
+        self.stats.inserted_last_frame = ready.len();
+        for position in ready {
+            let chunk_data = self.ready.remove(&position).unwrap();
+            self.stages.remove(&position);
+            world.insert_chunk(position, chunk_data);
+        }
+
+        // Request the missing chunks, closest and most in view first
+        let in_flight = self.stages.values().filter(|stage| **stage != ChunkStage::Saving).count();
+        let budget = self.settings.max_in_flight.saturating_sub(in_flight);
+        if budget > 0 && !self.workers_stopped {
+            let (radius, vertical_radius) = (self.settings.radius, self.settings.vertical_radius);
+            let mut missing = vec![];
+            for dx in -radius..=radius {
+                for dy in -vertical_radius..=vertical_radius {
+                    for dz in -radius..=radius {
+                        let position = viewer.offset(dx, dy, dz);
+                        if self.in_radius(viewer, position, 0) && !world.is_loaded(position) && !self.stages.contains_key(&position) {
+                            missing.push(position);
+                        }
+                    }
+                }
+            }
+            missing.sort_by(|a, b| Self::priority(viewer_position, view_direction, *a)
+                .partial_cmp(&Self::priority(viewer_position, view_direction, *b)).unwrap());
+            for position in missing.into_iter().take(budget) {
+                if self.send(Job::Load(position)).is_err() {
+                    break;
+                }
+                self.stages.insert(position, ChunkStage::Requested);
+            }
+        }
+
+        self.stats.loaded_chunks = world.chunks().count();
+        let mut stage_counts = HashMap::new();
+        for stage in self.stages.values() {
+            *stage_counts.entry(*stage).or_insert(0) += 1;
+        }
+        self.stats.stage_counts = stage_counts;
+    }
+
+    /// Synchronously saves every modified chunk of the world, e.g. before quitting.
+    pub fn save_all(&self, world: &mut VoxelWorld) -> Result<(), SaveError> {
+        let save = match &self.save {
+            Some(save) => save,
+            None => return Ok(()),
+        };
+        let modified: HashSet<_> = world.chunks()
+            .filter(|(_, chunk)| chunk.must_save)
+            .map(|(position, _)| *position)
+            .collect();
+        save.save_chunks(world.chunks()
+            .filter(|(position, _)| modified.contains(position))
+            .map(|(position, chunk)| (*position, &chunk.chunk_data)))?;
+        for position in modified {
+            world.chunk_mut(position).unwrap().must_save = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChunkStreamer {
+    fn drop(&mut self) {
+        // Dropping the sender stops the workers once they are done with the queued jobs,
+        // so pending saves still reach the disk.
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        for message in self.messages.lock().unwrap().try_iter() {
+            if let WorkerMessage::SaveFailed(position, _, error) = message {
+                log::error!("Failed to save chunk {:?} on shutdown, its changes are lost: {}", position, error);
+            }
+        }
+    }
+}
+
+/// Where the chunk streaming system keeps the world loaded around.
+#[derive(Default)]
+pub struct StreamingViewer {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+}
+
+pub struct ChunkStreamingSystem;
+
+impl<'a> System<'a> for ChunkStreamingSystem {
+    type SystemData = (Write<'a, VoxelWorld>, WriteExpect<'a, ChunkStreamer>, Read<'a, StreamingViewer>);
+
+    fn run(&mut self, (mut world, mut streamer, viewer): Self::SystemData) {
+        streamer.update(&mut world, viewer.position, viewer.direction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct EmptyGenerator;
+
+    impl ChunkGenerator for EmptyGenerator {
+        fn generate(&self, _position: ChunkPosition) -> ChunkData {
+            ChunkData::new()
+        }
+    }
+
+    /// Runs frames for a viewer at `position` until `done` holds, giving the workers time to catch up.
+    fn stream_until<F>(streamer: &mut ChunkStreamer, world: &mut VoxelWorld, position: [f32; 3], done: F)
+        where F: Fn(&ChunkStreamer, &VoxelWorld) -> bool {
+        for _ in 0..1000 {
+            streamer.update(world, position, [0.0, 0.0, 1.0]);
+            if done(streamer, world) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("Streaming did not settle");
+    }
+
+    #[test]
+    fn evicted_chunks_are_saved_and_loaded_back() {
+        let directory = std::env::temp_dir().join(format!("rustyblocks-streaming-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let save = Arc::new(WorldSave::open(&directory).unwrap());
+        let settings = StreamingSettings {
+            radius: 1,
+            vertical_radius: 0,
+            eviction_margin: 0,
+            max_in_flight: 64,
+            max_inserts_per_frame: 64,
+            max_evictions_per_frame: 64,
+            ..Default::default()
+        };
+        let mut streamer = ChunkStreamer::new(settings, Some(save.clone()), Arc::new(EmptyGenerator));
+        let mut world = VoxelWorld::new();
+        let home = [8.0, 8.0, 8.0];
+        let away = [8.0 + 10.0*CHUNK_SIZE as f32, 8.0, 8.0];
+        let settled = |streamer: &ChunkStreamer, world: &VoxelWorld| streamer.stages().count() == 0 && world.chunks().count() == 5;
+
+        stream_until(&mut streamer, &mut world, home, settled);
+        let modified: Vec<_> = world.chunks().map(|(position, _)| *position).collect();
+        for (index, position) in modified.iter().enumerate() {
+            world.set(position.block(1, 2, 3), index as u16 + 1).unwrap();
+        }
+
+        // Walking away evicts every modified chunk, with two workers saving into the same region files
+        stream_until(&mut streamer, &mut world, away, settled);
+        for (index, position) in modified.iter().enumerate() {
+            assert!(!world.is_loaded(*position));
+            let chunk_data = save.load_chunk(*position).unwrap().expect("Evicted chunk was not saved");
+            assert_eq!(chunk_data.get(1, 2, 3), index as u16 + 1);
+        }
+
+        stream_until(&mut streamer, &mut world, home, settled);
+        for (index, position) in modified.iter().enumerate() {
+            assert_eq!(world.get(position.block(1, 2, 3)), index as u16 + 1);
+            assert!(!world.chunk(*position).unwrap().must_save);
+        }
+        assert_eq!(streamer.stats().errors, 0);
+
+        drop(streamer);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn chunks_that_fail_to_save_stay_loaded_until_a_retry_succeeds() {
+        let directory = std::env::temp_dir().join(format!("rustyblocks-streaming-failure-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let save = Arc::new(WorldSave::open(&directory).unwrap());
+        let settings = StreamingSettings {
+            radius: 0,
+            vertical_radius: 0,
+            eviction_margin: 0,
+            save_retry_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut streamer = ChunkStreamer::new(settings, Some(save.clone()), Arc::new(EmptyGenerator));
+        let mut world = VoxelWorld::new();
+        let home = [8.0, 8.0, 8.0];
+        let away = [8.0 + 10.0*CHUNK_SIZE as f32, 8.0, 8.0];
+        let origin = ChunkPosition::new(0, 0, 0);
+        stream_until(&mut streamer, &mut world, home, |_, world| world.is_loaded(origin));
+        world.set(origin.block(4, 5, 6), 7).unwrap();
+
+        // Without its directory, the save fails and the chunk comes back instead of being dropped
+        std::fs::remove_dir_all(&directory).unwrap();
+        stream_until(&mut streamer, &mut world, away, |streamer, world| streamer.stats().errors > 0 && world.is_loaded(origin));
+        for _ in 0..10 {
+            streamer.update(&mut world, away, [0.0, 0.0, 1.0]);
+        }
+        assert!(world.chunk(origin).unwrap().must_save);
+        assert_eq!(world.get(origin.block(4, 5, 6)), 7);
+
+        std::fs::create_dir_all(&directory).unwrap();
+        streamer.settings.save_retry_interval = Duration::from_secs(0);
+        streamer.failed_saves.clear();
+        stream_until(&mut streamer, &mut world, away, |streamer, world| !world.is_loaded(origin) && streamer.stage(origin).is_none());
+        assert_eq!(save.load_chunk(origin).unwrap().expect("Chunk was not saved on retry").get(4, 5, 6), 7);
+
+        drop(streamer);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -3,29 +3,42 @@ use specs::prelude::*;
 
 pub const CHUNK_SIZE : usize = 64;
 
-type BlockData = bool;
+pub type BlockData = crate::base::block::BlockId;
 
-pub struct ChunkData([BlockData; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]);
+pub struct ChunkData(Box<[BlockData]>);
 
 impl ChunkData {
     pub fn new() -> Self {
-        return ChunkData([false; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]);
+        return ChunkData(vec![crate::base::block::AIR; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE].into_boxed_slice());
     }
 
-    pub fn from_array(arr : [bool; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]) -> ChunkData {
-        return ChunkData(arr);
+    pub fn from_array(arr : [BlockData; CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE]) -> ChunkData {
+        return ChunkData(Box::new(arr));
     }
 
-    pub fn set(&mut self,value: BlockData, x: usize, y:usize, z:usize) {
+    pub fn from_vec(blocks: Vec<BlockData>) -> ChunkData {
+        if blocks.len() != CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE {
+            panic!("Invalid chunk data: Expected {} blocks, got {}", CHUNK_SIZE*CHUNK_SIZE*CHUNK_SIZE, blocks.len());
+        }
+        return ChunkData(blocks.into_boxed_slice());
+    }
+
+    pub fn as_slice(&self) -> &[BlockData] {
+        &self.0
+    }
+
+    /// Sets the block at the given position, returning the block that was there before.
+    pub fn set(&mut self,value: BlockData, x: usize, y:usize, z:usize) -> BlockData {
         if x >= CHUNK_SIZE || y >= CHUNK_SIZE || z >= CHUNK_SIZE {
             panic!("Invalid chunk position: Tried to access position ({}, {}, {}) on chunk of size {}",
                 x, y, z, CHUNK_SIZE);
         }
 
-        self.0[Self::position_from_coordinates(x, y, z)] = value;
+        let position = Self::position_from_coordinates(x, y, z);
+        return std::mem::replace(&mut self.0[position], value);
     }
 
-    pub fn get(&mut self, x: usize, y:usize, z:usize) -> BlockData {
+    pub fn get(&self, x: usize, y:usize, z:usize) -> BlockData {
         if x >= CHUNK_SIZE || y >= CHUNK_SIZE || z >= CHUNK_SIZE {
             panic!("Invalid chunk position: Tried to access position ({}, {}, {}) on chunk of size {}",
                    x, y, z, CHUNK_SIZE);
@@ -39,9 +52,9 @@ impl ChunkData {
     }
 
     pub fn coordinates_from_position(pos: usize) ->(usize, usize, usize) {
-        let x = pos%(CHUNK_SIZE*CHUNK_SIZE);
-        let y = (pos - x*CHUNK_SIZE)%CHUNK_SIZE;
-        let z = (pos - y*CHUNK_SIZE - x*CHUNK_SIZE*CHUNK_SIZE)%CHUNK_SIZE;
+        let x = pos/(CHUNK_SIZE*CHUNK_SIZE);
+        let y = (pos/CHUNK_SIZE)%CHUNK_SIZE;
+        let z = pos%CHUNK_SIZE;
         return (x, y, z);
     }
     //TODO
@@ -50,9 +63,17 @@ impl ChunkData {
     //}
 }
 
-struct ChunkComponent {
-    chunk_data : ChunkData,
-    must_rebuild: bool,
+pub struct ChunkComponent {
+    pub chunk_data : ChunkData,
+    pub must_rebuild: bool,
+    /// Set when the chunk was modified since it was loaded or generated, so it must be saved before eviction.
+    pub must_save: bool,
+}
+
+impl ChunkComponent {
+    pub fn new(chunk_data: ChunkData) -> Self {
+        ChunkComponent { chunk_data, must_rebuild: true, must_save: false }
+    }
 }
 
 impl Component for ChunkComponent {
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::base::voxel::{ChunkData, ChunkComponent, CHUNK_SIZE};
+use crate::base::block::{BlockId, AIR};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BlockPosition {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl BlockPosition {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        BlockPosition { x, y, z }
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32, dz: i32) -> Self {
+        BlockPosition::new(self.x + dx, self.y + dy, self.z + dz)
+    }
+
+    pub fn neighbors(&self) -> [BlockPosition; 6] {
+        [
+            self.offset(-1, 0, 0), self.offset(1, 0, 0),
+            self.offset(0, -1, 0), self.offset(0, 1, 0),
+            self.offset(0, 0, -1), self.offset(0, 0, 1),
+        ]
+    }
+
+    pub fn chunk(&self) -> ChunkPosition {
+        let size = CHUNK_SIZE as i32;
+        ChunkPosition::new(self.x.div_euclid(size), self.y.div_euclid(size), self.z.div_euclid(size))
+    }
+
+    /// Position of this block inside its chunk.
+    pub fn local(&self) -> (usize, usize, usize) {
+        let size = CHUNK_SIZE as i32;
+        (self.x.rem_euclid(size) as usize, self.y.rem_euclid(size) as usize, self.z.rem_euclid(size) as usize)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkPosition {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkPosition {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        ChunkPosition { x, y, z }
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32, dz: i32) -> Self {
+        ChunkPosition::new(self.x + dx, self.y + dy, self.z + dz)
+    }
+
+    /// World position of the block at local coordinates `(x, y, z)` of this chunk.
+    pub fn block(&self, x: usize, y: usize, z: usize) -> BlockPosition {
+        let size = CHUNK_SIZE as i32;
+        BlockPosition::new(self.x*size + x as i32, self.y*size + y as i32, self.z*size + z as i32)
+    }
+
+    pub fn origin(&self) -> BlockPosition {
+        self.block(0, 0, 0)
+    }
+}
+
+/// A change made by `VoxelWorld::set` that the neighbors of `position` should hear about.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NeighborNotification {
+    pub position: BlockPosition,
+    pub neighbor: BlockPosition,
+}
+
+#[derive(Default)]
+pub struct VoxelWorld {
+    chunks: HashMap<ChunkPosition, ChunkComponent>,
+    pending_notifications: Vec<NeighborNotification>,
+}
+
+impl VoxelWorld {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert_chunk(&mut self, position: ChunkPosition, chunk_data: ChunkData) -> Option<ChunkComponent> {
+        self.chunks.insert(position, ChunkComponent::new(chunk_data))
+    }
+
+    pub fn remove_chunk(&mut self, position: ChunkPosition) -> Option<ChunkComponent> {
+        self.chunks.remove(&position)
+    }
+
+    pub fn chunk(&self, position: ChunkPosition) -> Option<&ChunkComponent> {
+        self.chunks.get(&position)
+    }
+
+    pub fn chunk_mut(&mut self, position: ChunkPosition) -> Option<&mut ChunkComponent> {
+        self.chunks.get_mut(&position)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkPosition, &ChunkComponent)> {
+        self.chunks.iter()
+    }
+
+    pub fn chunks_mut(&mut self) -> impl Iterator<Item = (&ChunkPosition, &mut ChunkComponent)> {
+        self.chunks.iter_mut()
+    }
+
+    pub fn is_loaded(&self, position: ChunkPosition) -> bool {
+        self.chunks.contains_key(&position)
+    }
+
+    /// Returns the block at `position`, or `AIR` if its chunk is not loaded.
+    pub fn get(&self, position: BlockPosition) -> BlockId {
+        match self.chunks.get(&position.chunk()) {
+            Some(chunk) => {
+                let (x, y, z) = position.local();
+                chunk.chunk_data.get(x, y, z)
+            }
+            None => AIR,
+        }
+    }
+
+    /// Sets the block at `position`, returning the previous block, or `None` if its chunk is not loaded.
+    /// When the block actually changes, the affected chunks are marked for rebuild and a neighbor
+    /// notification is queued for each of the six adjacent blocks.
+    pub fn set(&mut self, position: BlockPosition, block: BlockId) -> Option<BlockId> {
+        let old = self.set_without_notifications(position, block)?;
+        if old != block {
+            for neighbor in position.neighbors().iter() {
+                self.pending_notifications.push(NeighborNotification { position: *neighbor, neighbor: position });
+            }
+        }
+        Some(old)
+    }
+
+    /// Like `set`, but does not queue any neighbor notifications.
+    pub fn set_without_notifications(&mut self, position: BlockPosition, block: BlockId) -> Option<BlockId> {
+        let chunk_position = position.chunk();
+        let (x, y, z) = position.local();
+        let chunk = self.chunks.get_mut(&chunk_position)?;
+        let old = chunk.chunk_data.set(block, x, y, z);
+        if old != block {
+            chunk.must_rebuild = true;
+            chunk.must_save = true;
+            self.mark_border_neighbors(chunk_position, x, y, z);
+        }
+        Some(old)
+    }
+
+    /// Marks the chunks sharing a face with a block at the border of a chunk for rebuild,
+    /// since their meshes depend on it.
+    fn mark_border_neighbors(&mut self, chunk_position: ChunkPosition, x: usize, y: usize, z: usize) -> Vec<ChunkPosition> {
+        self.mark_region_neighbors(chunk_position, (x, y, z), (x, y, z))
+    }
+
+    fn mark_region_neighbors(&mut self, chunk_position: ChunkPosition, min: (usize, usize, usize), max: (usize, usize, usize)) -> Vec<ChunkPosition> {
+        let last = CHUNK_SIZE - 1;
+        let mut neighbors = vec![];
+        if min.0 == 0 { neighbors.push(chunk_position.offset(-1, 0, 0)); }
+        if max.0 == last { neighbors.push(chunk_position.offset(1, 0, 0)); }
+        if min.1 == 0 { neighbors.push(chunk_position.offset(0, -1, 0)); }
+        if max.1 == last { neighbors.push(chunk_position.offset(0, 1, 0)); }
+        if min.2 == 0 { neighbors.push(chunk_position.offset(0, 0, -1)); }
+        if max.2 == last { neighbors.push(chunk_position.offset(0, 0, 1)); }
+        neighbors.retain(|neighbor| self.chunks.contains_key(neighbor));
+        for neighbor in neighbors.iter() {
+            self.chunks.get_mut(neighbor).unwrap().must_rebuild = true;
+        }
+        neighbors
+    }
+
+    /// Marks a chunk whose data was changed directly through `chunk_mut` for rebuild and saving,
+    /// along with the neighbors sharing the changed blocks between the local corners `min` and `max`.
+    /// Returns every chunk that was marked for rebuild. No neighbor notifications are queued.
+    pub fn mark_modified(&mut self, chunk_position: ChunkPosition, min: (usize, usize, usize), max: (usize, usize, usize)) -> Vec<ChunkPosition> {
+        let chunk = match self.chunks.get_mut(&chunk_position) {
+            Some(chunk) => chunk,
+            None => return vec![],
+        };
+        chunk.must_rebuild = true;
+        chunk.must_save = true;
+        let mut marked = self.mark_region_neighbors(chunk_position, min, max);
+        marked.push(chunk_position);
+        marked
+    }
+
+    pub fn take_notifications(&mut self) -> Vec<NeighborNotification> {
+        std::mem::take(&mut self.pending_notifications)
+    }
+
+    /// Takes at most `limit` of the oldest pending notifications, the rest stay queued.
+    pub fn take_notifications_up_to(&mut self, limit: usize) -> Vec<NeighborNotification> {
+        if self.pending_notifications.len() <= limit {
+            return self.take_notifications();
+        }
+        let rest = self.pending_notifications.split_off(limit);
+        std::mem::replace(&mut self.pending_notifications, rest)
+    }
+
+    pub fn pending_notification_count(&self) -> usize {
+        self.pending_notifications.len()
+    }
+}
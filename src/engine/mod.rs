@@ -1,2 +1,3 @@
 pub mod render;
-pub mod core;
\ No newline at end of file
+pub mod core;
+pub mod time_of_day;
\ No newline at end of file
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::format::ClearValue;
+use vulkano::framebuffer::FramebufferAbstract;
+use vulkano::image::AttachmentImage;
+use vulkano::pipeline::vertex::BufferlessVertices;
+use crate::engine::render::error::RenderError;
+use crate::engine::render::post::{post_pipeline, PostContext, PostEffect, PostPipeline, PostTarget};
+use crate::engine::render::render_server::RenderServer;
+use crate::engine::render::settings::BloomSettings;
+
+mod extract_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/bloom_extract.frag"
+    }
+}
+
+mod blur_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/bloom_blur.frag"
+    }
+}
+
+mod composite_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/bloom_composite.frag"
+    }
+}
+
+/// Size of the images the glow is blurred in, half of the frame so that the blur spreads twice as far.
+fn bloom_dimensions(dimensions: [u32; 2]) -> [u32; 2] {
+    [(dimensions[0]/2).max(1), (dimensions[1]/2).max(1)]
+}
+
+/// Makes the parts of the scene brighter than the threshold glow: they are extracted at half resolution,
+/// blurred horizontally then vertically, and added back to the scene.
+pub struct Bloom {
+    context: PostContext,
+    settings: BloomSettings,
+    extract_pipeline: Arc<PostPipeline>,
+    blur_pipeline: Arc<PostPipeline>,
+    composite_pipeline: Arc<PostPipeline>,
+    /// The glow is blurred back and forth between these. Empty until the effect is resized.
+    targets: Vec<PostTarget>,
+    dimensions: [u32; 2],
+}
+
+impl Bloom {
+    pub fn new(context: &PostContext, settings: &BloomSettings) -> Result<Self, RenderError> {
+        let device = &context.device;
+        let extract_shader_module = extract_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let blur_shader_module = blur_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let composite_shader_module = composite_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+
+        Ok(Bloom {
+            context: context.clone(),
+            settings: *settings,
+            extract_pipeline: post_pipeline(device, &context.render_pass, extract_shader_module.main_entry_point())?,
+            blur_pipeline: post_pipeline(device, &context.render_pass, blur_shader_module.main_entry_point())?,
+            composite_pipeline: post_pipeline(device, &context.render_pass, composite_shader_module.main_entry_point())?,
+            targets: vec![],
+            dimensions: [0, 0],
+        })
+    }
+
+    /// Descriptor set sampling `image` for `pipeline`.
+    fn sample(&self, pipeline: &Arc<PostPipeline>, image: &Arc<AttachmentImage>) -> Arc<dyn DescriptorSet + Send + Sync> {
+        let layout = pipeline.descriptor_set_layout(0).unwrap();
+        Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(image.clone(), self.context.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap())
+    }
+}
+
+/// Draws a full screen triangle with `pipeline` into `output`.
+fn draw<Pc>(builder: AutoCommandBufferBuilder, pipeline: &Arc<PostPipeline>, output: &Arc<dyn FramebufferAbstract + Send + Sync>,
+            dimensions: [u32; 2], set: Arc<dyn DescriptorSet + Send + Sync>, push_constants: Pc) -> AutoCommandBufferBuilder {
+    builder.begin_render_pass(output.clone(), false, vec![ClearValue::None])
+        .unwrap()
+        .draw(pipeline.clone(), &RenderServer::viewport_dynamic_state(dimensions),
+              BufferlessVertices { vertices: 3, instances: 1 }, set, push_constants)
+        .unwrap()
+        .end_render_pass()
+        .unwrap()
+}
+
+impl PostEffect for Bloom {
+    fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), RenderError> {
+        let dimensions = bloom_dimensions(dimensions);
+        self.targets = (0..2).map(|_| self.context.create_target(dimensions))
+            .collect::<Result<_, _>>()?;
+        self.dimensions = dimensions;
+        Ok(())
+    }
+
+    fn record(&self, mut builder: AutoCommandBufferBuilder, input: &Arc<AttachmentImage>, output: &Arc<dyn FramebufferAbstract + Send + Sync>,
+              dimensions: [u32; 2]) -> AutoCommandBufferBuilder {
+        let (bright, blurred) = (&self.targets[0], &self.targets[1]);
+        let extract = extract_fragment_shader::ty::PushConstants { threshold: self.settings.threshold };
+        builder = draw(builder, &self.extract_pipeline, &bright.framebuffer, self.dimensions,
+                       self.sample(&self.extract_pipeline, input), extract);
+        let texel = [1.0/self.dimensions[0] as f32, 1.0/self.dimensions[1] as f32];
+        let horizontal = blur_fragment_shader::ty::PushConstants { direction: [texel[0], 0.0] };
+        builder = draw(builder, &self.blur_pipeline, &blurred.framebuffer, self.dimensions,
+                       self.sample(&self.blur_pipeline, &bright.image), horizontal);
+        let vertical = blur_fragment_shader::ty::PushConstants { direction: [0.0, texel[1]] };
+        builder = draw(builder, &self.blur_pipeline, &bright.framebuffer, self.dimensions,
+                       self.sample(&self.blur_pipeline, &blurred.image), vertical);
+
+        let layout = self.composite_pipeline.descriptor_set_layout(0).unwrap();
+        let composite_set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(input.clone(), self.context.sampler.clone())
+            .unwrap()
+            .add_sampled_image(bright.image.clone(), self.context.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap());
+        let composite = composite_fragment_shader::ty::PushConstants { intensity: self.settings.intensity };
+        draw(builder, &self.composite_pipeline, output, dimensions, composite_set, composite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_is_blurred_at_half_resolution() {
+        assert_eq!(bloom_dimensions([800, 600]), [400, 300]);
+        assert_eq!(bloom_dimensions([1, 3]), [1, 1]);
+    }
+}
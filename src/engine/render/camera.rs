@@ -0,0 +1,78 @@
+use cgmath::{Matrix4, Point3, Vector3, InnerSpace, Rad, Angle};
+
+/// A perspective camera. Yaw turns around the y axis, with zero looking towards -z,
+/// and pitch turns up from the horizon.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Point3<f32>,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    /// Vertical field of view.
+    pub fov: Rad<f32>,
+    /// Width over height of the viewport, updated by the render server from the swap chain size.
+    pub aspect: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            position: Point3::new(0.0, 0.0, 0.0),
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            fov: Rad(std::f32::consts::FRAC_PI_3),
+            aspect: 4.0/3.0,
+            near: 0.1,
+            far: 1000.0,
+        }
+    }
+}
+
+impl Camera {
+    pub fn new(position: Point3<f32>, yaw: Rad<f32>, pitch: Rad<f32>) -> Self {
+        Camera { position, yaw, pitch, .. Default::default() }
+    }
+
+    /// Turns the camera to face `target`.
+    pub fn look_at(&mut self, target: Point3<f32>) {
+        let direction = target - self.position;
+        if direction.magnitude2() == 0.0 {
+            return;
+        }
+        let direction = direction.normalize();
+        self.yaw = Rad((-direction.x).atan2(-direction.z));
+        self.pitch = Rad(direction.y.asin());
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        Vector3::new(-sin_yaw*cos_pitch, sin_pitch, -cos_yaw*cos_pitch)
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        Matrix4::look_at_dir(self.position, self.forward(), Vector3::unit_y())
+    }
+
+    /// Projection to Vulkan clip space: y pointing down and depth going from 0 at the near plane to 1 at
+    /// the far plane, or from 1 to 0 with `reverse_z`.
+    pub fn projection_matrix(&self, reverse_z: bool) -> Matrix4<f32> {
+        let focal_length = 1.0/(self.fov.0/2.0).tan();
+        let (depth_scale, depth_offset) = if reverse_z {
+            (self.near/(self.far - self.near), self.near*self.far/(self.far - self.near))
+        } else {
+            (self.far/(self.near - self.far), self.near*self.far/(self.near - self.far))
+        };
+        Matrix4::new(
+            focal_length/self.aspect, 0.0, 0.0, 0.0,
+            0.0, -focal_length, 0.0, 0.0,
+            0.0, 0.0, depth_scale, -1.0,
+            0.0, 0.0, depth_offset, 0.0,
+        )
+    }
+
+    pub fn view_projection_matrix(&self, reverse_z: bool) -> Matrix4<f32> {
+        self.projection_matrix(reverse_z)*self.view_matrix()
+    }
+}
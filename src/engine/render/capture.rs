@@ -0,0 +1,150 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract};
+use vulkano::image::{AttachmentImage, ImageUsage};
+use crate::engine::render::error::RenderError;
+
+/// Color formats that can be read back, all of them 8 bits per channel.
+const CAPTURE_FORMATS: &[Format] = &[
+    Format::R8G8B8A8Unorm,
+    Format::R8G8B8A8Srgb,
+    Format::B8G8R8A8Unorm,
+    Format::B8G8R8A8Srgb,
+];
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    Encoding(png::EncodingError),
+    Decoding(png::DecodingError),
+    /// Only 8 bit RGBA images can be loaded.
+    UnsupportedPng(png::ColorType, png::BitDepth),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Io(error) => write!(f, "I/O error: {}", error),
+            CaptureError::Encoding(error) => write!(f, "PNG encoding error: {}", error),
+            CaptureError::Decoding(error) => write!(f, "PNG decoding error: {}", error),
+            CaptureError::UnsupportedPng(color, depth) => write!(f, "Unsupported PNG of type {:?} with depth {:?}", color, depth),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<io::Error> for CaptureError {
+    fn from(error: io::Error) -> Self {
+        CaptureError::Io(error)
+    }
+}
+
+impl From<png::EncodingError> for CaptureError {
+    fn from(error: png::EncodingError) -> Self {
+        CaptureError::Encoding(error)
+    }
+}
+
+impl From<png::DecodingError> for CaptureError {
+    fn from(error: png::DecodingError) -> Self {
+        CaptureError::Decoding(error)
+    }
+}
+
+/// A rendered frame copied back to CPU memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedImage {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA, 8 bits per channel, rows from top to bottom.
+    pub pixels: Vec<u8>,
+}
+
+impl CapturedImage {
+    /// Converts the raw texels of an image of format `format` to RGBA.
+    pub(crate) fn from_raw(dimensions: [u32; 2], format: Format, mut pixels: Vec<u8>) -> Self {
+        match format {
+            Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+            Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => {}
+            _ => panic!("Unsupported capture format {:?}", format),
+        }
+        CapturedImage { width: dimensions[0], height: dimensions[1], pixels }
+    }
+
+    /// The RGBA value of the pixel at `(x, y)`, counted from the top left corner.
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let index = ((y*self.width + x)*4) as usize;
+        [self.pixels[index], self.pixels[index + 1], self.pixels[index + 2], self.pixels[index + 3]]
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), CaptureError> {
+        let file = File::create(path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+
+    /// Loads an image written by `save_png`.
+    pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+        let decoder = png::Decoder::new(File::open(path)?);
+        let (info, mut reader) = decoder.read_info()?;
+        if info.color_type != png::ColorType::RGBA || info.bit_depth != png::BitDepth::Eight {
+            return Err(CaptureError::UnsupportedPng(info.color_type, info.bit_depth));
+        }
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels)?;
+        Ok(CapturedImage { width: info.width, height: info.height, pixels })
+    }
+}
+
+/// An image the scene can be rendered into instead of a swap chain image, along with the host visible
+/// buffer its content is copied to.
+pub(crate) struct OffscreenTarget {
+    pub color: Arc<AttachmentImage>,
+    pub framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    pub readback: Arc<CpuAccessibleBuffer<[u8]>>,
+    pub format: Format,
+    pub dimensions: [u32; 2],
+}
+
+impl OffscreenTarget {
+    pub fn is_supported(format: Format) -> bool {
+        CAPTURE_FORMATS.contains(&format)
+    }
+
+    /// `render_pass` is the one the last pass of the frame draws through, with a single color attachment.
+    pub fn new(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, dimensions: [u32; 2],
+               format: Format) -> Result<Self, RenderError> {
+        if !Self::is_supported(format) {
+            panic!("Unsupported capture format {:?}", format);
+        }
+        let usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            .. ImageUsage::none()
+        };
+        let color = AttachmentImage::with_usage(device.clone(), dimensions, format, usage)
+            .map_err(RenderError::ImageCreation)?;
+        let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(render_pass.clone())
+            .add(color.clone()).map_err(RenderError::FramebufferCreation)?
+            .build().map_err(RenderError::FramebufferCreation)?);
+        let size = (dimensions[0]*dimensions[1]*4) as usize;
+        let readback = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_destination(), true, (0..size).map(|_| 0u8))
+            .map_err(RenderError::Allocation)?;
+        Ok(OffscreenTarget { color, framebuffer, readback, format, dimensions })
+    }
+}
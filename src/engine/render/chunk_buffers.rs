@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use vulkano::buffer::{BufferSlice, BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DrawIndexedIndirectCommand};
+use vulkano::device::Device;
+use vulkano::memory::DeviceMemoryAllocError;
+use crate::base::mesher::{ChunkMesh, MeshVertex};
+use crate::base::connectivity::ChunkConnectivity;
+use crate::engine::render::mesh::MeshHandle;
+use crate::engine::render::frustum::Aabb;
+
+/// Vertices and indices held by each arena. Meshes larger than this get an arena of their own.
+pub const ARENA_VERTICES: u64 = 1 << 20;
+pub const ARENA_INDICES: u64 = 3 << 19;
+
+/// First fit allocator of ranges within `0..size`.
+#[derive(Clone, Debug)]
+pub struct RangeAllocator {
+    size: u64,
+    /// Free ranges by start, never adjacent to each other.
+    free: BTreeMap<u64, u64>,
+}
+
+impl RangeAllocator {
+    pub fn new(size: u64) -> Self {
+        let mut free = BTreeMap::new();
+        if size > 0 {
+            free.insert(0, size);
+        }
+        RangeAllocator { size, free }
+    }
+
+    /// Returns the start of a free range of `length` elements, or `None` if no free range is large enough.
+    pub fn allocate(&mut self, length: u64) -> Option<u64> {
+        let (start, free_length) = self.free.iter()
+            .find(|(_, free_length)| **free_length >= length)
+            .map(|(start, free_length)| (*start, *free_length))?;
+        self.free.remove(&start);
+        if free_length > length {
+            self.free.insert(start + length, free_length - length);
+        }
+        Some(start)
+    }
+
+    /// Gives back a range returned by `allocate`, merging it with the free ranges around it.
+    pub fn free(&mut self, start: u64, length: u64) {
+        let mut start = start;
+        let mut length = length;
+        let previous = self.free.range(..start).next_back().map(|(start, length)| (*start, *length));
+        if let Some((previous_start, previous_length)) = previous {
+            if previous_start + previous_length == start {
+                self.free.remove(&previous_start);
+                start = previous_start;
+                length += previous_length;
+            }
+        }
+        if let Some(next_length) = self.free.remove(&(start + length)) {
+            length += next_length;
+        }
+        self.free.insert(start, length);
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn free_count(&self) -> u64 {
+        self.free.values().sum()
+    }
+
+    pub fn largest_free(&self) -> u64 {
+        self.free.values().cloned().max().unwrap_or(0)
+    }
+}
+
+struct Arena {
+    vertex_buffer: Arc<DeviceLocalBuffer<[MeshVertex]>>,
+    index_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+    vertices: RangeAllocator,
+    indices: RangeAllocator,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Allocation {
+    arena: usize,
+    vertex_start: u64,
+    vertex_count: u64,
+    index_start: u64,
+    index_count: u64,
+    /// The opaque faces come first, followed by the translucent ones.
+    opaque_index_count: u64,
+    /// Bounds of the mesh vertices, in model space.
+    bounds: Aabb,
+    connectivity: ChunkConnectivity,
+}
+
+/// A mesh waiting in host visible memory to be copied to its allocation.
+struct PendingUpload {
+    handle: MeshHandle,
+    vertices: Arc<CpuAccessibleBuffer<[MeshVertex]>>,
+    indices: Arc<CpuAccessibleBuffer<[u32]>>,
+    allocation: Allocation,
+}
+
+/// A mesh ready to be put under a handle: either staged for upload, or empty with nothing to upload.
+enum StagedMesh {
+    Empty(ChunkConnectivity),
+    Uploaded(PendingUpload),
+}
+
+/// Staged meshes to copy to the arenas, taken out of `ChunkBuffers` so they can be recorded while it is borrowed.
+pub struct Uploads(Vec<(Arc<CpuAccessibleBuffer<[MeshVertex]>>, Arc<CpuAccessibleBuffer<[u32]>>, BufferSlice<[MeshVertex], Arc<DeviceLocalBuffer<[MeshVertex]>>>, BufferSlice<[u32], Arc<DeviceLocalBuffer<[u32]>>>)>);
+
+impl Uploads {
+    /// Records the copies, which must happen before the meshes are drawn.
+    pub fn record(self, mut builder: AutoCommandBufferBuilder) -> AutoCommandBufferBuilder {
+        for (vertices, indices, vertex_destination, index_destination) in self.0 {
+            builder = builder.copy_buffer(vertices, vertex_destination).unwrap()
+                .copy_buffer(indices, index_destination).unwrap();
+        }
+        builder
+    }
+}
+
+/// What is needed to draw one mesh. The vertex buffer is the whole arena, the indices already point
+/// to where the mesh's vertices are in it.
+pub struct ChunkDraw {
+    pub vertex_buffer: Arc<DeviceLocalBuffer<[MeshVertex]>>,
+    /// The opaque faces, `None` if they are all translucent.
+    pub indices: Option<BufferSlice<[u32], Arc<DeviceLocalBuffer<[u32]>>>>,
+    pub translucent_indices: Option<BufferSlice<[u32], Arc<DeviceLocalBuffer<[u32]>>>>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChunkBufferStats {
+    pub meshes: usize,
+    pub arenas: usize,
+    pub bytes_used: u64,
+    pub bytes_allocated: u64,
+    /// Share of the free memory that is not part of the largest free range of its arena, from 0 when the free
+    /// memory is contiguous to close to 1 when it is scattered in small holes.
+    pub fragmentation: f32,
+}
+
+/// Chunk meshes suballocated from a few large device local buffers. Meshes are staged in host visible
+/// memory, and copied by the command buffer of the next frame.
+pub struct ChunkBuffers {
+    device: Arc<Device>,
+    arenas: Vec<Arena>,
+    allocations: HashMap<MeshHandle, Allocation>,
+    /// Meshes without any face, which take no memory and are never drawn. Occlusion culling still needs their connectivity.
+    empty_meshes: HashMap<MeshHandle, ChunkConnectivity>,
+    pending_uploads: Vec<PendingUpload>,
+    /// Allocations freed during a frame, which may still be read by the frames in flight before it.
+    retired: Vec<(u64, Allocation)>,
+    next_handle: u64,
+}
+
+impl ChunkBuffers {
+    pub fn new(device: Arc<Device>) -> Self {
+        ChunkBuffers {
+            device,
+            arenas: vec![],
+            allocations: HashMap::new(),
+            empty_meshes: HashMap::new(),
+            pending_uploads: vec![],
+            retired: vec![],
+            next_handle: 0,
+        }
+    }
+
+    fn create_arena(&self, vertex_count: u64, index_count: u64) -> Result<Arena, DeviceMemoryAllocError> {
+        let vertex_usage = BufferUsage { vertex_buffer: true, transfer_destination: true, .. BufferUsage::none() };
+        let index_usage = BufferUsage { index_buffer: true, transfer_destination: true, .. BufferUsage::none() };
+        Ok(Arena {
+            vertex_buffer: DeviceLocalBuffer::array(self.device.clone(), vertex_count as usize, vertex_usage, self.device.active_queue_families())?,
+            index_buffer: DeviceLocalBuffer::array(self.device.clone(), index_count as usize, index_usage, self.device.active_queue_families())?,
+            vertices: RangeAllocator::new(vertex_count),
+            indices: RangeAllocator::new(index_count),
+        })
+    }
+
+    fn allocate(&mut self, vertex_count: u64, index_count: u64, bounds: Aabb, connectivity: ChunkConnectivity) -> Result<Allocation, DeviceMemoryAllocError> {
+        for (index, arena) in self.arenas.iter_mut().enumerate() {
+            let vertex_start = match arena.vertices.allocate(vertex_count) {
+                Some(start) => start,
+                None => continue,
+            };
+            match arena.indices.allocate(index_count) {
+                Some(index_start) => return Ok(Allocation { arena: index, vertex_start, vertex_count, index_start, index_count, opaque_index_count: index_count, bounds, connectivity }),
+                None => arena.vertices.free(vertex_start, vertex_count),
+            }
+        }
+        let mut arena = self.create_arena(vertex_count.max(ARENA_VERTICES), index_count.max(ARENA_INDICES))?;
+        let vertex_start = arena.vertices.allocate(vertex_count).unwrap();
+        let index_start = arena.indices.allocate(index_count).unwrap();
+        self.arenas.push(arena);
+        Ok(Allocation { arena: self.arenas.len() - 1, vertex_start, vertex_count, index_start, index_count, opaque_index_count: index_count, bounds, connectivity })
+    }
+
+    /// Allocates room for `mesh` and copies it to host visible memory, for `handle`. Empty meshes take no room.
+    fn stage(&mut self, handle: MeshHandle, mesh: &ChunkMesh) -> Result<StagedMesh, DeviceMemoryAllocError> {
+        if mesh.is_empty() {
+            return Ok(StagedMesh::Empty(mesh.connectivity));
+        }
+        let bounds = Aabb::from_points(mesh.vertices.iter().map(|vertex| vertex.position.into())).unwrap();
+        let index_count = mesh.indices.len() + mesh.translucent_indices.len();
+        let mut allocation = self.allocate(mesh.vertices.len() as u64, index_count as u64, bounds, mesh.connectivity)?;
+        allocation.opaque_index_count = mesh.indices.len() as u64;
+        let base_vertex = allocation.vertex_start as u32;
+        let device = &self.device;
+        let buffers = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_source(), false, mesh.vertices.iter().cloned())
+            .and_then(|vertices| {
+                // The draw calls can not offset vertices, so the indices are made relative to the start of the arena
+                let indices = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_source(), false,
+                    mesh.indices.iter().chain(mesh.translucent_indices.iter()).map(|index| index + base_vertex))?;
+                Ok((vertices, indices))
+            });
+        match buffers {
+            Ok((vertices, indices)) => Ok(StagedMesh::Uploaded(PendingUpload { handle, vertices, indices, allocation })),
+            Err(error) => {
+                // Nothing uses the allocation yet, so it is freed right away
+                let arena = &mut self.arenas[allocation.arena];
+                arena.vertices.free(allocation.vertex_start, allocation.vertex_count);
+                arena.indices.free(allocation.index_start, allocation.index_count);
+                Err(error)
+            }
+        }
+    }
+
+    fn insert(&mut self, handle: MeshHandle, staged: StagedMesh) {
+        match staged {
+            StagedMesh::Empty(connectivity) => {
+                self.empty_meshes.insert(handle, connectivity);
+            }
+            StagedMesh::Uploaded(upload) => {
+                self.allocations.insert(handle, upload.allocation);
+                self.pending_uploads.push(upload);
+            }
+        }
+    }
+
+    /// Stages a mesh for upload. It can be drawn right away, as it is copied before the draws of the next frame.
+    /// Empty meshes get a handle too, which draws nothing.
+    pub fn upload(&mut self, mesh: &ChunkMesh) -> Result<MeshHandle, DeviceMemoryAllocError> {
+        let handle = MeshHandle(self.next_handle);
+        let staged = self.stage(handle, mesh)?;
+        self.insert(handle, staged);
+        self.next_handle += 1;
+        Ok(handle)
+    }
+
+    /// Replaces the mesh of `handle`, e.g. after its chunk was remeshed. Returns false if there is no such mesh.
+    /// The previous mesh is kept if the new one can not be allocated.
+    pub fn update(&mut self, handle: MeshHandle, mesh: &ChunkMesh, frame: u64) -> Result<bool, DeviceMemoryAllocError> {
+        if !self.allocations.contains_key(&handle) && !self.empty_meshes.contains_key(&handle) {
+            return Ok(false);
+        }
+        let staged = self.stage(handle, mesh)?;
+        self.remove(handle, frame);
+        self.insert(handle, staged);
+        Ok(true)
+    }
+
+    /// Frees a mesh. Its memory is only reused once the frames in flight during `frame` are done.
+    pub fn remove(&mut self, handle: MeshHandle, frame: u64) -> bool {
+        if self.empty_meshes.remove(&handle).is_some() {
+            return true;
+        }
+        let allocation = match self.allocations.remove(&handle) {
+            Some(allocation) => allocation,
+            None => return false,
+        };
+        self.pending_uploads.retain(|upload| upload.handle != handle);
+        self.retired.push((frame, allocation));
+        true
+    }
+
+    /// Reuses the memory of the meshes removed up to `completed_frame`, once the GPU is done with that frame.
+    pub fn release_retired(&mut self, completed_frame: u64) {
+        let arenas = &mut self.arenas;
+        self.retired.retain(|(frame, allocation)| {
+            if *frame > completed_frame {
+                return true;
+            }
+            let arena = &mut arenas[allocation.arena];
+            arena.vertices.free(allocation.vertex_start, allocation.vertex_count);
+            arena.indices.free(allocation.index_start, allocation.index_count);
+            false
+        });
+    }
+
+    pub fn take_uploads(&mut self) -> Uploads {
+        let arenas = &self.arenas;
+        Uploads(self.pending_uploads.drain(..).map(|upload| {
+            let arena = &arenas[upload.allocation.arena];
+            let allocation = upload.allocation;
+            let vertex_destination = BufferSlice::from_typed_buffer_access(arena.vertex_buffer.clone())
+                .slice(allocation.vertex_start as usize..(allocation.vertex_start + allocation.vertex_count) as usize)
+                .unwrap();
+            let index_destination = BufferSlice::from_typed_buffer_access(arena.index_buffer.clone())
+                .slice(allocation.index_start as usize..(allocation.index_start + allocation.index_count) as usize)
+                .unwrap();
+            (upload.vertices, upload.indices, vertex_destination, index_destination)
+        }).collect())
+    }
+
+    pub fn get(&self, handle: MeshHandle) -> Option<ChunkDraw> {
+        let allocation = self.allocations.get(&handle)?;
+        let arena = &self.arenas[allocation.arena];
+        let slice = |start: u64, end: u64| if start == end {
+            None
+        } else {
+            Some(BufferSlice::from_typed_buffer_access(arena.index_buffer.clone()).slice(start as usize..end as usize).unwrap())
+        };
+        let (start, translucent_start, end) = (allocation.index_start, allocation.index_start + allocation.opaque_index_count,
+                                               allocation.index_start + allocation.index_count);
+        Some(ChunkDraw {
+            vertex_buffer: arena.vertex_buffer.clone(),
+            indices: slice(start, translucent_start),
+            translucent_indices: slice(translucent_start, end),
+        })
+    }
+
+    /// The arena of a mesh along with the command drawing its opaque faces from the arena's buffers.
+    pub fn indirect_command(&self, handle: MeshHandle, first_instance: u32) -> Option<(usize, DrawIndexedIndirectCommand)> {
+        let allocation = self.allocations.get(&handle)?;
+        Some((allocation.arena, DrawIndexedIndirectCommand {
+            index_count: allocation.opaque_index_count as u32,
+            instance_count: 1,
+            first_index: allocation.index_start as u32,
+            vertex_offset: 0,
+            first_instance,
+        }))
+    }
+
+    /// The vertex and index buffers of an arena, for draws that pick the meshes with indirect commands.
+    pub fn arena_buffers(&self, arena: usize) -> (Arc<DeviceLocalBuffer<[MeshVertex]>>, Arc<DeviceLocalBuffer<[u32]>>) {
+        let arena = &self.arenas[arena];
+        (arena.vertex_buffer.clone(), arena.index_buffer.clone())
+    }
+
+    /// Bounds of the mesh in model space, `None` for empty meshes.
+    pub fn bounds(&self, handle: MeshHandle) -> Option<Aabb> {
+        self.allocations.get(&handle).map(|allocation| allocation.bounds)
+    }
+
+    pub fn connectivity(&self, handle: MeshHandle) -> Option<ChunkConnectivity> {
+        match self.allocations.get(&handle) {
+            Some(allocation) => Some(allocation.connectivity),
+            None => self.empty_meshes.get(&handle).cloned(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.allocations.len() + self.empty_meshes.len()
+    }
+
+    pub fn stats(&self) -> ChunkBufferStats {
+        let vertex_size = std::mem::size_of::<MeshVertex>() as u64;
+        let index_size = std::mem::size_of::<u32>() as u64;
+        let mut stats = ChunkBufferStats { meshes: self.allocations.len(), arenas: self.arenas.len(), .. Default::default() };
+        let (mut free, mut largest_free) = (0, 0);
+        for arena in self.arenas.iter() {
+            stats.bytes_allocated += arena.vertices.size()*vertex_size + arena.indices.size()*index_size;
+            free += arena.vertices.free_count()*vertex_size + arena.indices.free_count()*index_size;
+            largest_free += arena.vertices.largest_free()*vertex_size + arena.indices.largest_free()*index_size;
+        }
+        stats.bytes_used = stats.bytes_allocated - free;
+        if free > 0 {
+            stats.fragmentation = 1.0 - largest_free as f32/free as f32;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_packed_first_fit() {
+        let mut allocator = RangeAllocator::new(100);
+        assert_eq!(allocator.allocate(30), Some(0));
+        assert_eq!(allocator.allocate(1), Some(30));
+        assert_eq!(allocator.allocate(19), Some(31));
+        allocator.free(0, 30);
+        // The first range large enough is used, even if a later one would fit better
+        assert_eq!(allocator.allocate(20), Some(0));
+        assert_eq!(allocator.allocate(10), Some(20));
+        assert_eq!(allocator.free_count(), 50);
+    }
+
+    #[test]
+    fn ranges_fit_exactly() {
+        let mut allocator = RangeAllocator::new(64);
+        assert_eq!(allocator.allocate(65), None);
+        assert_eq!(allocator.allocate(64), Some(0));
+        assert_eq!(allocator.free_count(), 0);
+        assert_eq!(allocator.allocate(1), None);
+        allocator.free(0, 64);
+        assert_eq!(allocator.largest_free(), 64);
+
+        assert_eq!(allocator.allocate(16), Some(0));
+        assert_eq!(allocator.allocate(16), Some(16));
+        allocator.free(0, 16);
+        assert_eq!(allocator.allocate(17), Some(32));
+        assert_eq!(allocator.allocate(16), Some(0));
+        assert_eq!(allocator.allocate(15), Some(49));
+        assert_eq!(allocator.allocate(1), None);
+    }
+
+    #[test]
+    fn freed_ranges_are_merged_with_their_neighbors() {
+        let mut allocator = RangeAllocator::new(40);
+        let ranges: Vec<_> = (0..4).map(|_| allocator.allocate(10).unwrap()).collect();
+        assert_eq!(ranges, vec![0, 10, 20, 30]);
+
+        allocator.free(10, 10);
+        allocator.free(30, 10);
+        assert_eq!(allocator.largest_free(), 10);
+        assert_eq!(allocator.allocate(20), None);
+        // Merged with both the previous and the next free range
+        allocator.free(20, 10);
+        assert_eq!(allocator.largest_free(), 30);
+        assert_eq!(allocator.allocate(30), Some(10));
+        allocator.free(10, 30);
+        allocator.free(0, 10);
+        assert_eq!(allocator.largest_free(), 40);
+        assert_eq!(allocator.free_count(), allocator.size());
+    }
+
+    #[test]
+    fn empty_allocators_are_exhausted() {
+        let mut allocator = RangeAllocator::new(0);
+        assert_eq!(allocator.allocate(1), None);
+        assert_eq!(allocator.largest_free(), 0);
+    }
+}
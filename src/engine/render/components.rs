@@ -12,7 +12,28 @@ impl Component for ModelComponent {
     type Storage = VecStorage<Self>;
 }
 
+/// An entity the world can be seen from. The camera system picks the first active one
+/// and stores it in the `ActiveCamera` resource, which is what gets handed to the render server.
+pub struct CameraComponent {
+    pub camera : crate::engine::render::camera::Camera,
+    pub active : bool,
+}
+
+impl Component for CameraComponent {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Default)]
+pub struct ActiveCamera(pub Option<crate::engine::render::camera::Camera>);
+
+pub struct CameraSystem;
+
+impl<'a> System<'a> for CameraSystem {
+    type SystemData = (Write<'a, ActiveCamera>, ReadStorage<'a, CameraComponent>);
 
-struct CameraComponent {
-    camera : Camera,
+    fn run(&mut self, (mut active_camera, cameras): Self::SystemData) {
+        active_camera.0 = cameras.join()
+            .find(|camera| camera.active)
+            .map(|camera| camera.camera);
+    }
 }
@@ -0,0 +1,93 @@
+use std::fmt;
+use vulkano::command_buffer::{BuildError, CommandBufferExecError};
+use vulkano::descriptor::descriptor_set::{PersistentDescriptorSetBuildError, PersistentDescriptorSetError};
+use vulkano::device::DeviceCreationError;
+use vulkano::framebuffer::{FramebufferCreationError, RenderPassCreationError};
+use vulkano::image::ImageCreationError;
+use vulkano::instance::{InstanceCreationError, SupportedExtensionsError};
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::sampler::SamplerCreationError;
+use vulkano::pipeline::{ComputePipelineCreationError, GraphicsPipelineCreationError};
+use vulkano::swapchain::{AcquireError, CapabilitiesError, SwapchainCreationError};
+use vulkano::sync::FlushError;
+use vulkano::OomError;
+use crate::engine::render::settings::DeviceSelection;
+
+/// Why the render server could not be created, or could not render a frame.
+#[derive(Debug)]
+pub enum RenderError {
+    GlfwInit(glfw::InitError),
+    WindowCreation,
+    /// GLFW could not tell which instance extensions are needed to present, usually because
+    /// no Vulkan loader is installed.
+    MissingInstanceExtensions,
+    ExtensionQuery(SupportedExtensionsError),
+    InstanceCreation(InstanceCreationError),
+    /// The raw `VkResult` of `glfwCreateWindowSurface`.
+    SurfaceCreation(vk_sys::Result),
+    /// No device matched the selection. Lists every device found along with why it was rejected.
+    NoSuitableDevice { selection: DeviceSelection, rejected: Vec<(String, String)> },
+    DeviceCreation(DeviceCreationError),
+    SurfaceCapabilities(CapabilitiesError),
+    SwapchainCreation(SwapchainCreationError),
+    NoDepthFormat,
+    RenderPassCreation(RenderPassCreationError),
+    ShaderCreation(OomError),
+    PipelineCreation(GraphicsPipelineCreationError),
+    ComputePipelineCreation(ComputePipelineCreationError),
+    ImageCreation(ImageCreationError),
+    FramebufferCreation(FramebufferCreationError),
+    SamplerCreation(SamplerCreationError),
+    Allocation(DeviceMemoryAllocError),
+    DescriptorSet(PersistentDescriptorSetError),
+    DescriptorSetBuild(PersistentDescriptorSetBuildError),
+    ImageAcquisition(AcquireError),
+    CommandBufferCreation(OomError),
+    CommandBufferBuild(BuildError),
+    Execution(CommandBufferExecError),
+    Flush(FlushError),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::GlfwInit(error) => write!(f, "Failed to initialize GLFW: {:?}", error),
+            RenderError::WindowCreation => write!(f, "Failed to create window"),
+            RenderError::MissingInstanceExtensions => write!(f, "Vulkan is not available to present to windows"),
+            RenderError::ExtensionQuery(error) => write!(f, "Failed to query Vulkan extensions: {}", error),
+            RenderError::InstanceCreation(error) => write!(f, "Failed to create Vulkan instance: {}", error),
+            RenderError::SurfaceCreation(result) => write!(f, "Failed to create window surface, error code {}", result),
+            RenderError::NoSuitableDevice { selection, rejected } => {
+                write!(f, "No suitable device for {:?}", selection)?;
+                if rejected.is_empty() {
+                    return write!(f, ", no Vulkan device found");
+                }
+                for (name, reason) in rejected.iter() {
+                    write!(f, "\n  {}: {}", name, reason)?;
+                }
+                Ok(())
+            }
+            RenderError::DeviceCreation(error) => write!(f, "Failed to create logical device: {}", error),
+            RenderError::SurfaceCapabilities(error) => write!(f, "Failed to get surface capabilities: {}", error),
+            RenderError::SwapchainCreation(error) => write!(f, "Failed to create swap chain: {}", error),
+            RenderError::NoDepthFormat => write!(f, "The device supports none of the depth formats"),
+            RenderError::RenderPassCreation(error) => write!(f, "Failed to create render pass: {}", error),
+            RenderError::ShaderCreation(error) => write!(f, "Failed to create shader module: {}", error),
+            RenderError::PipelineCreation(error) => write!(f, "Failed to create graphics pipeline: {}", error),
+            RenderError::ComputePipelineCreation(error) => write!(f, "Failed to create compute pipeline: {}", error),
+            RenderError::ImageCreation(error) => write!(f, "Failed to create image: {}", error),
+            RenderError::FramebufferCreation(error) => write!(f, "Failed to create framebuffer: {}", error),
+            RenderError::SamplerCreation(error) => write!(f, "Failed to create sampler: {}", error),
+            RenderError::Allocation(error) => write!(f, "Failed to allocate device memory: {}", error),
+            RenderError::DescriptorSet(error) => write!(f, "Failed to add to descriptor set: {}", error),
+            RenderError::DescriptorSetBuild(error) => write!(f, "Failed to build descriptor set: {}", error),
+            RenderError::ImageAcquisition(error) => write!(f, "Failed to acquire next image: {}", error),
+            RenderError::CommandBufferCreation(error) => write!(f, "Failed to create command buffer: {}", error),
+            RenderError::CommandBufferBuild(error) => write!(f, "Failed to build command buffer: {}", error),
+            RenderError::Execution(error) => write!(f, "Failed to execute command buffer: {}", error),
+            RenderError::Flush(error) => write!(f, "Failed to flush frame: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
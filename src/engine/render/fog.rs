@@ -0,0 +1,103 @@
+use cgmath::{Point3, Vector3, ElementWise};
+use crate::base::block::BlockRegistry;
+use crate::base::world::{BlockPosition, VoxelWorld};
+use crate::engine::render::settings::FogSettings;
+use crate::engine::render::sky::Sky;
+
+/// How opaque the fog is at its full distance. Exponential fog never quite reaches 1.
+const FULL_FOG: f32 = 0.99;
+
+/// The fog of one frame, as the shaders see it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fog {
+    pub color: Vector3<f32>,
+    /// Multiplies the color of everything, to tint it under fluids.
+    pub tint: Vector3<f32>,
+    /// Distance at which the fog starts.
+    pub start: f32,
+    pub density: f32,
+    pub underwater: bool,
+    /// How far the screen wobbles, as a fraction of its size.
+    pub distortion: f32,
+}
+
+impl Fog {
+    /// Fog colored like the horizon of `sky`, or by the fluid the camera is in. `fluid_color` is the color of that fluid.
+    pub fn new(settings: &FogSettings, sky: &Sky, fluid_color: Option<Vector3<f32>>) -> Self {
+        let clear = Fog { color: sky.horizon_color, tint: Vector3::new(1.0, 1.0, 1.0), start: 0.0, density: 0.0, underwater: false, distortion: 0.0 };
+        if !settings.enabled {
+            return clear;
+        }
+        let fog = |distance: f32, start: f32| {
+            let start = distance*start;
+            // Such that 1 - exp(-((distance - start)*density)^2) is FULL_FOG
+            let density = (-(1.0 - FULL_FOG).ln()).sqrt()/(distance - start).max(std::f32::EPSILON);
+            (start, density)
+        };
+        match fluid_color {
+            Some(fluid_color) => {
+                let (start, density) = fog(settings.underwater_distance, 0.0);
+                // Lit like everything else, so that it darkens at night
+                let light = sky.ambient_color + sky.light_color;
+                Fog { color: fluid_color.mul_element_wise(light), tint: fluid_color, start, density, underwater: true, distortion: settings.underwater_distortion }
+            }
+            None => {
+                let (start, density) = fog(settings.distance, settings.start);
+                Fog { start, density, .. clear }
+            }
+        }
+    }
+
+    /// How much of the color of something `distance` away is replaced by the fog, as computed by the shaders.
+    pub fn factor(&self, distance: f32) -> f32 {
+        let fogged = (distance - self.start).max(0.0)*self.density;
+        1.0 - (-fogged*fogged).exp()
+    }
+}
+
+/// Color of the fluid at `position`, if it is in one.
+pub fn fluid_color_at(world: &VoxelWorld, registry: &BlockRegistry, position: Point3<f32>) -> Option<Vector3<f32>> {
+    let block = world.get(BlockPosition::new(position.x.floor() as i32, position.y.floor() as i32, position.z.floor() as i32));
+    registry.get(block)
+        .filter(|definition| definition.fluid)
+        .map(|definition| Vector3::from(definition.color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::block::BlockDefinition;
+    use crate::engine::time_of_day::TimeOfDay;
+
+    #[test]
+    fn fog_hides_everything_at_its_distance() {
+        let settings = FogSettings::default();
+        let fog = Fog::new(&settings, &Sky::default(), None);
+        assert_eq!(fog.factor(settings.distance*settings.start), 0.0);
+        assert!((fog.factor(settings.distance) - FULL_FOG).abs() < 1e-3);
+        assert_eq!(fog.color, Sky::default().horizon_color);
+        assert!(!fog.underwater);
+    }
+
+    #[test]
+    fn fog_is_denser_in_fluids() {
+        let settings = FogSettings::default();
+        let sky = Sky::at(&TimeOfDay::at(0.5));
+        let water = Vector3::new(0.2, 0.4, 0.8);
+        let (air, underwater) = (Fog::new(&settings, &sky, None), Fog::new(&settings, &sky, Some(water)));
+        assert!(underwater.factor(20.0) > air.factor(20.0));
+        assert_eq!(underwater.tint, water);
+        assert!(underwater.underwater);
+    }
+
+    #[test]
+    fn camera_medium_is_the_block_it_is_in() {
+        let mut registry = BlockRegistry::new();
+        let water = registry.register(BlockDefinition::new("water").fluid().with_color([0.2, 0.4, 0.8]));
+        let mut world = VoxelWorld::new();
+        world.insert_chunk(crate::base::world::ChunkPosition::new(0, 0, 0), crate::base::voxel::ChunkData::new());
+        world.set(BlockPosition::new(3, 2, 1), water);
+        assert_eq!(fluid_color_at(&world, &registry, Point3::new(3.5, 2.9, 1.2)), Some(Vector3::new(0.2, 0.4, 0.8)));
+        assert_eq!(fluid_color_at(&world, &registry, Point3::new(3.5, 3.1, 1.2)), None);
+    }
+}
@@ -0,0 +1,140 @@
+use cgmath::{Matrix4, Point3, Vector4, Transform};
+
+/// Axis aligned bounding box.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+        Aabb { min, max }
+    }
+
+    /// Smallest box containing all the points, or `None` if there are none.
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Aabb::new(first, first), |bounds, point| Aabb::new(
+            Point3::new(bounds.min.x.min(point.x), bounds.min.y.min(point.y), bounds.min.z.min(point.z)),
+            Point3::new(bounds.max.x.max(point.x), bounds.max.y.max(point.y), bounds.max.z.max(point.z)),
+        )))
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            Point3::new(min.x, min.y, min.z), Point3::new(max.x, min.y, min.z),
+            Point3::new(min.x, max.y, min.z), Point3::new(max.x, max.y, min.z),
+            Point3::new(min.x, min.y, max.z), Point3::new(max.x, min.y, max.z),
+            Point3::new(min.x, max.y, max.z), Point3::new(max.x, max.y, max.z),
+        ]
+    }
+
+    /// Box containing this one once transformed by `transform`.
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Self {
+        Aabb::from_points(self.corners().iter().map(|corner| transform.transform_point(*corner))).unwrap()
+    }
+}
+
+/// The six planes bounding what a camera sees, pointing inwards.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frustum {
+    /// `(a, b, c, d)` such that `a*x + b*y + c*z + d >= 0` inside the frustum, with `(a, b, c)` normalized.
+    pub planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes of a view projection matrix to Vulkan clip space, where visible points satisfy
+    /// `-w <= x <= w`, `-w <= y <= w` and `0 <= z <= w`. This holds with reverse-Z too, which only swaps
+    /// which of the two depth planes is the near one.
+    pub fn from_matrix(view_projection: &Matrix4<f32>) -> Self {
+        let row = |index: usize| Vector4::new(view_projection.x[index], view_projection.y[index], view_projection.z[index], view_projection.w[index]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let normalize = |plane: Vector4<f32>| {
+            let length = (plane.x*plane.x + plane.y*plane.y + plane.z*plane.z).sqrt();
+            if length > 0.0 { plane/length } else { plane }
+        };
+        Frustum {
+            planes: [
+                normalize(w + x),
+                normalize(w - x),
+                normalize(w + y),
+                normalize(w - y),
+                normalize(z),
+                normalize(w - z),
+            ],
+        }
+    }
+
+    pub fn contains_point(&self, point: Point3<f32>) -> bool {
+        self.planes.iter().all(|plane| plane.x*point.x + plane.y*point.y + plane.z*point.z + plane.w >= 0.0)
+    }
+
+    /// Conservative test: false only if the box is entirely outside of one of the planes. Boxes near
+    /// the frustum corners may be reported as intersecting while they are not.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal
+            let x = if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x };
+            let y = if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y };
+            let z = if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z };
+            plane.x*x + plane.y*y + plane.z*z + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Rad;
+    use crate::engine::render::camera::Camera;
+
+    fn camera() -> Camera {
+        // Looking towards -z from the origin
+        Camera { near: 0.1, far: 100.0, aspect: 1.0, fov: Rad(std::f32::consts::FRAC_PI_2), .. Camera::default() }
+    }
+
+    fn unit_box(center: Point3<f32>) -> Aabb {
+        Aabb::new(center - cgmath::Vector3::new(0.5, 0.5, 0.5), center + cgmath::Vector3::new(0.5, 0.5, 0.5))
+    }
+
+    #[test]
+    fn boxes_in_front_are_visible() {
+        for reverse_z in [false, true].iter() {
+            let frustum = Frustum::from_matrix(&camera().view_projection_matrix(*reverse_z));
+            assert!(frustum.intersects_aabb(&unit_box(Point3::new(0.0, 0.0, -10.0))));
+            assert!(frustum.contains_point(Point3::new(0.0, 0.0, -1.0)));
+        }
+    }
+
+    #[test]
+    fn boxes_outside_are_culled() {
+        for reverse_z in [false, true].iter() {
+            let frustum = Frustum::from_matrix(&camera().view_projection_matrix(*reverse_z));
+            // Behind, beyond the far plane, and off to each side of the 90 degrees field of view
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(0.0, 0.0, 10.0))));
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(0.0, 0.0, -200.0))));
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(20.0, 0.0, -10.0))));
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(-20.0, 0.0, -10.0))));
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(0.0, 20.0, -10.0))));
+            assert!(!frustum.intersects_aabb(&unit_box(Point3::new(0.0, -20.0, -10.0))));
+        }
+    }
+
+    #[test]
+    fn boxes_crossing_a_plane_are_visible() {
+        let frustum = Frustum::from_matrix(&camera().view_projection_matrix(false));
+        // The right plane passes through x = 10 at z = -10
+        assert!(frustum.intersects_aabb(&unit_box(Point3::new(10.2, 0.0, -10.0))));
+        assert!(frustum.intersects_aabb(&Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0))));
+    }
+
+    #[test]
+    fn transformed_boxes_contain_their_corners() {
+        let aabb = Aabb::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 2.0, 3.0));
+        let moved = aabb.transformed(&Matrix4::from_translation(cgmath::Vector3::new(10.0, 0.0, -5.0)));
+        assert_eq!(moved, Aabb::new(Point3::new(10.0, 0.0, -5.0), Point3::new(11.0, 2.0, -2.0)));
+    }
+}
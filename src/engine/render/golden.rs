@@ -0,0 +1,276 @@
+use std::path::{Path, PathBuf};
+use cgmath::{Matrix4, Point3, Rad};
+use crate::base::block::{BlockRegistry, BlockDefinition};
+use crate::base::generator::{ChunkGenerator, HeightmapGenerator};
+use crate::base::mesher::culled_mesher;
+use crate::base::voxel::{ChunkData, CHUNK_SIZE};
+use crate::base::world::{VoxelWorld, ChunkPosition};
+use crate::engine::render::camera::Camera;
+use crate::engine::render::capture::{CapturedImage, CaptureError};
+use crate::engine::render::render_server::RenderServer;
+use crate::engine::render::settings::{RenderSettings, DeviceSelection, DevicePreference, ValidationSettings};
+use crate::engine::render::error::RenderError;
+
+/// Size of the rendered golden images.
+pub const GOLDEN_DIMENSIONS: [u32; 2] = [256, 256];
+
+/// Largest color difference, as computed by `color_delta`, between two pixels considered the same.
+pub const DEFAULT_PIXEL_THRESHOLD: f32 = 0.1;
+/// Fraction of the pixels that may differ before an image is considered different, which absorbs
+/// rasterization differences between drivers along edges.
+pub const DEFAULT_MISMATCH_TOLERANCE: f32 = 0.002;
+
+/// A fixed scene rendered and compared against `<name>.png` in the reference directory.
+pub struct GoldenScene {
+    pub name: &'static str,
+    /// Uploads and draws the scene and sets the camera.
+    pub setup: fn(&mut RenderServer),
+}
+
+fn registry() -> BlockRegistry {
+    let mut registry = BlockRegistry::new();
+    registry.register(BlockDefinition::new("stone").with_color([0.5, 0.5, 0.5]));
+    registry.register(BlockDefinition::new("dirt").with_color([0.45, 0.3, 0.15]));
+    registry.register(BlockDefinition::new("grass").with_color([0.3, 0.6, 0.2]));
+    registry.register(BlockDefinition::new("glass").translucent(0.4).with_color([0.6, 0.8, 0.9]));
+    registry.register(BlockDefinition::new("red_glass").translucent(0.6).with_color([0.9, 0.2, 0.2]));
+    return registry;
+}
+
+fn draw_chunk(server: &mut RenderServer, world: &VoxelWorld, registry: &BlockRegistry, position: ChunkPosition) {
+    let mesh = culled_mesher::generate_mesh(world, position, registry);
+    let handle = server.upload_mesh(&mesh).expect("Failed to upload the chunk mesh");
+    let origin = position.origin();
+    server.draw_mesh(handle, Matrix4::from_translation([origin.x as f32, origin.y as f32, origin.z as f32].into()));
+}
+
+fn single_cube(server: &mut RenderServer) {
+    let registry = registry();
+    let mut chunk_data = ChunkData::new();
+    chunk_data.set(registry.id_of("stone").unwrap(), 0, 0, 0);
+    let mut world = VoxelWorld::new();
+    world.insert_chunk(ChunkPosition::new(0, 0, 0), chunk_data);
+    draw_chunk(server, &world, &registry, ChunkPosition::new(0, 0, 0));
+
+    let mut camera = Camera::new(Point3::new(2.0, 2.0, 3.0), Rad(0.0), Rad(0.0));
+    camera.look_at(Point3::new(0.5, 0.5, 0.5));
+    server.set_camera(&camera);
+}
+
+fn terrain_chunk(server: &mut RenderServer) {
+    let registry = registry();
+    let generator = HeightmapGenerator::new(0, registry.id_of("stone").unwrap(), registry.id_of("dirt").unwrap(), registry.id_of("grass").unwrap());
+    let position = ChunkPosition::new(0, 0, 0);
+    let mut world = VoxelWorld::new();
+    world.insert_chunk(position, generator.generate(position));
+    draw_chunk(server, &world, &registry, position);
+
+    let size = CHUNK_SIZE as f32;
+    let mut camera = Camera::new(Point3::new(-0.5*size, 1.5*size, -0.5*size), Rad(0.0), Rad(0.0));
+    camera.look_at(Point3::new(0.5*size, 0.25*size, 0.5*size));
+    server.set_camera(&camera);
+}
+
+/// Layers of two kinds of glass on a stone floor, in front of a stone pillar seen through them. The faces
+/// between the layers are kept, so the result depends on the order they are blended in.
+fn translucent_stack(server: &mut RenderServer) {
+    let registry = registry();
+    let stone = registry.id_of("stone").unwrap();
+    let (glass, red_glass) = (registry.id_of("glass").unwrap(), registry.id_of("red_glass").unwrap());
+    let mut chunk_data = ChunkData::new();
+    for x in 0..3 {
+        for z in 0..3 {
+            chunk_data.set(stone, x, 0, z);
+        }
+    }
+    for y in 1..5 {
+        chunk_data.set(if y % 2 == 0 { red_glass } else { glass }, 1, y, 1);
+        chunk_data.set(stone, 0, y, 0);
+    }
+    let mut world = VoxelWorld::new();
+    world.insert_chunk(ChunkPosition::new(0, 0, 0), chunk_data);
+    draw_chunk(server, &world, &registry, ChunkPosition::new(0, 0, 0));
+
+    let mut camera = Camera::new(Point3::new(6.0, 5.0, 7.0), Rad(0.0), Rad(0.0));
+    camera.look_at(Point3::new(1.5, 2.0, 1.5));
+    server.set_camera(&camera);
+}
+
+pub const GOLDEN_SCENES: &[GoldenScene] = &[
+    GoldenScene { name: "single_cube", setup: single_cube },
+    GoldenScene { name: "terrain_chunk", setup: terrain_chunk },
+    GoldenScene { name: "translucent_stack", setup: translucent_stack },
+];
+
+/// Perceptual difference between two colors, from 0 for identical colors to 1 for the most different ones.
+/// Differences are measured in YIQ space, weighting brightness more than hue like the eye does.
+pub fn color_delta(a: [u8; 4], b: [u8; 4]) -> f32 {
+    // Blended over white, so that transparent pixels compare by how they would look
+    let blend = |pixel: [u8; 4]| {
+        let alpha = pixel[3] as f32/255.0;
+        let channel = |value: u8| 255.0 + (value as f32 - 255.0)*alpha;
+        (channel(pixel[0]), channel(pixel[1]), channel(pixel[2]))
+    };
+    let ((r1, g1, b1), (r2, g2, b2)) = (blend(a), blend(b));
+    let (dr, dg, db) = (r1 - r2, g1 - g2, b1 - b2);
+    let y = dr*0.29889531 + dg*0.58662247 + db*0.11448223;
+    let i = dr*0.59597799 - dg*0.27417610 - db*0.32180189;
+    let q = dr*0.21147017 - dg*0.52261711 + db*0.31114694;
+    // 35215 is the largest possible delta
+    (0.5053*y*y + 0.299*i*i + 0.1957*q*q)/35215.0
+}
+
+pub struct ImageComparison {
+    pub mismatched_pixels: usize,
+    pub total_pixels: usize,
+    /// The expected image faded to gray, with the mismatched pixels in red.
+    pub diff: CapturedImage,
+}
+
+impl ImageComparison {
+    pub fn mismatch_ratio(&self) -> f32 {
+        self.mismatched_pixels as f32/self.total_pixels.max(1) as f32
+    }
+}
+
+/// Compares two images pixel by pixel, counting the pixels whose `color_delta` exceeds `threshold`.
+/// Images of different sizes mismatch on every pixel.
+pub fn compare_images(expected: &CapturedImage, actual: &CapturedImage, threshold: f32) -> ImageComparison {
+    let total_pixels = (expected.width*expected.height) as usize;
+    if expected.width != actual.width || expected.height != actual.height {
+        return ImageComparison {
+            mismatched_pixels: total_pixels.max((actual.width*actual.height) as usize),
+            total_pixels,
+            diff: expected.clone(),
+        };
+    }
+    let mut mismatched_pixels = 0;
+    let mut diff = Vec::with_capacity(expected.pixels.len());
+    for y in 0..expected.height {
+        for x in 0..expected.width {
+            let (expected_pixel, actual_pixel) = (expected.pixel(x, y), actual.pixel(x, y));
+            if color_delta(expected_pixel, actual_pixel) > threshold {
+                mismatched_pixels += 1;
+                diff.extend_from_slice(&[255, 0, 0, 255]);
+            } else {
+                let gray = (expected_pixel[0] as u32 + expected_pixel[1] as u32 + expected_pixel[2] as u32)/3;
+                let faded = (255 - (255 - gray)/4) as u8;
+                diff.extend_from_slice(&[faded, faded, faded, 255]);
+            }
+        }
+    }
+    ImageComparison {
+        mismatched_pixels,
+        total_pixels,
+        diff: CapturedImage { width: expected.width, height: expected.height, pixels: diff },
+    }
+}
+
+#[derive(Debug)]
+pub enum GoldenFailure {
+    /// There is no reference image for the scene yet. The rendered image was written to `actual`.
+    MissingReference { scene: &'static str, actual: PathBuf },
+    Mismatch { scene: &'static str, mismatch_ratio: f32, actual: PathBuf, diff: PathBuf },
+    Io { scene: &'static str, error: CaptureError },
+    Render { scene: &'static str, error: RenderError },
+}
+
+/// Renders every golden scene headless and compares it against the references in `reference_directory`.
+/// The rendered and diff images of failing scenes are written to `output_directory`.
+/// With `bless` set, the references are overwritten by the rendered images instead.
+pub fn run_golden_tests(reference_directory: &Path, output_directory: &Path, bless: bool) -> Vec<GoldenFailure> {
+    // References are made with lavapipe, prefer it over whatever else is installed
+    let settings = RenderSettings {
+        device: DeviceSelection::Prefer(DevicePreference::Software),
+        validation: ValidationSettings { panic_on_error: true, .. ValidationSettings::default() },
+        .. RenderSettings::default()
+    };
+    let mut failures = vec![];
+    for scene in GOLDEN_SCENES.iter() {
+        // Each scene gets its own server so that no meshes or state leak between scenes
+        let mut server = match RenderServer::headless(settings.clone(), GOLDEN_DIMENSIONS) {
+            Ok(server) => server,
+            Err(error) => {
+                failures.push(GoldenFailure::Render { scene: scene.name, error });
+                continue;
+            }
+        };
+        (scene.setup)(&mut server);
+        let image = match server.capture() {
+            Ok(image) => image,
+            Err(error) => {
+                failures.push(GoldenFailure::Render { scene: scene.name, error });
+                continue;
+            }
+        };
+
+        let reference_path = reference_directory.join(format!("{}.png", scene.name));
+        if bless {
+            if let Err(error) = std::fs::create_dir_all(reference_directory).map_err(CaptureError::from)
+                .and_then(|_| image.save_png(&reference_path)) {
+                failures.push(GoldenFailure::Io { scene: scene.name, error });
+            }
+            continue;
+        }
+
+        let actual_path = output_directory.join(format!("{}.actual.png", scene.name));
+        let save_actual = || std::fs::create_dir_all(output_directory).map_err(CaptureError::from)
+            .and_then(|_| image.save_png(&actual_path));
+        if !reference_path.exists() {
+            failures.push(match save_actual() {
+                Ok(()) => GoldenFailure::MissingReference { scene: scene.name, actual: actual_path.clone() },
+                Err(error) => GoldenFailure::Io { scene: scene.name, error },
+            });
+            continue;
+        }
+        let reference = match CapturedImage::load_png(&reference_path) {
+            Ok(reference) => reference,
+            Err(error) => {
+                failures.push(GoldenFailure::Io { scene: scene.name, error });
+                continue;
+            }
+        };
+        let comparison = compare_images(&reference, &image, DEFAULT_PIXEL_THRESHOLD);
+        if comparison.mismatch_ratio() <= DEFAULT_MISMATCH_TOLERANCE {
+            continue;
+        }
+        let diff_path = output_directory.join(format!("{}.diff.png", scene.name));
+        let result = save_actual().and_then(|_| comparison.diff.save_png(&diff_path));
+        failures.push(match result {
+            Ok(()) => GoldenFailure::Mismatch { scene: scene.name, mismatch_ratio: comparison.mismatch_ratio(), actual: actual_path.clone(), diff: diff_path },
+            Err(error) => GoldenFailure::Io { scene: scene.name, error },
+        });
+    }
+    return failures;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs a Vulkan driver, which can be a software one such as lavapipe, so it only runs with
+    /// `cargo test -- --ignored`. Run with `RUSTYBLOCKS_BLESS=1` to regenerate the references after
+    /// an intended rendering change.
+    #[test]
+    #[ignore]
+    fn rendering_matches_golden_images() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let bless = std::env::var_os("RUSTYBLOCKS_BLESS").is_some();
+        let failures = run_golden_tests(&root.join("tests/golden"), &root.join("target/golden"), bless);
+        assert!(failures.is_empty(), "Golden image tests failed: {:#?}", failures);
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let image = CapturedImage { width: 2, height: 1, pixels: vec![10, 20, 30, 255, 200, 100, 0, 255] };
+        let comparison = compare_images(&image, &image, DEFAULT_PIXEL_THRESHOLD);
+        assert_eq!(comparison.mismatched_pixels, 0);
+    }
+
+    #[test]
+    fn color_delta_ignores_small_differences() {
+        assert!(color_delta([100, 100, 100, 255], [102, 101, 100, 255]) < DEFAULT_PIXEL_THRESHOLD);
+        assert!(color_delta([0, 0, 0, 255], [255, 255, 255, 255]) > 0.9);
+        assert!(color_delta([255, 0, 0, 255], [0, 0, 255, 255]) > DEFAULT_PIXEL_THRESHOLD);
+    }
+}
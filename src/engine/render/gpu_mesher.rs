@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DrawIndexedIndirectCommand};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::pipeline_layout::PipelineLayout;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::pipeline::ComputePipeline;
+use crate::base::block::{BlockId, BlockRegistry, AIR};
+use crate::base::mesher::{ChunkMesh, MeshVertex};
+use crate::base::voxel::CHUNK_SIZE;
+use crate::base::world::{ChunkPosition, VoxelWorld};
+use crate::engine::render::error::RenderError;
+
+mod mesher_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/engine/render/shaders/chunk_mesher.comp"
+    }
+}
+
+/// Invocations per workgroup along each axis, the `local_size` of the shader.
+const WORKGROUP_SIZE: u32 = 4;
+/// Size of the chunk along with the border of blocks taken from its neighbors.
+const PADDED_SIZE: usize = CHUNK_SIZE + 2;
+
+type MesherPipeline = ComputePipeline<PipelineLayout<mesher_shader::Layout>>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GpuMeshHandle(u64);
+
+/// A chunk meshed on the GPU. How many faces it has is only known on the GPU, so it is drawn
+/// indirectly with `command`, along with the shared quad indices.
+#[derive(Clone)]
+pub(crate) struct GpuMesh {
+    pub vertex_buffer: Arc<DeviceLocalBuffer<[MeshVertex]>>,
+    pub command: Arc<DeviceLocalBuffer<[DrawIndexedIndirectCommand]>>,
+}
+
+struct MeshingJob {
+    set: Arc<dyn DescriptorSet + Send + Sync>,
+    /// The command the mesh's command is reset to before meshing, with no indices yet.
+    initial_command: Arc<CpuAccessibleBuffer<[DrawIndexedIndirectCommand]>>,
+    command: Arc<DeviceLocalBuffer<[DrawIndexedIndirectCommand]>>,
+    block_count: u32,
+}
+
+/// Chunks waiting to be meshed, taken out of `GpuMesher` so they can be recorded while it is borrowed.
+pub struct MeshingJobs {
+    pipeline: Arc<MesherPipeline>,
+    max_faces: u32,
+    jobs: Vec<MeshingJob>,
+}
+
+impl MeshingJobs {
+    /// Records the meshing, which must happen before the meshes are drawn.
+    pub fn record(self, mut builder: AutoCommandBufferBuilder) -> AutoCommandBufferBuilder {
+        let workgroups = CHUNK_SIZE as u32/WORKGROUP_SIZE;
+        for job in self.jobs {
+            let push_constants = mesher_shader::ty::PushConstants {
+                max_faces: self.max_faces,
+                block_count: job.block_count,
+            };
+            builder = builder.copy_buffer(job.initial_command, job.command)
+                .unwrap()
+                .dispatch([workgroups, workgroups, workgroups], self.pipeline.clone(), job.set, push_constants)
+                .unwrap();
+        }
+        builder
+    }
+}
+
+/// Meshes chunks with a compute shader emitting the same faces as `culled_mesher`. Each mesh has room
+/// for `max_faces` faces, the ones past that are dropped.
+pub(crate) struct GpuMesher {
+    device: Arc<Device>,
+    pipeline: Arc<MesherPipeline>,
+    /// Indices of `max_faces` quads, laid out like the quads of `culled_mesher`.
+    quad_indices: Arc<CpuAccessibleBuffer<[u32]>>,
+    max_faces: u32,
+    meshes: HashMap<GpuMeshHandle, GpuMesh>,
+    jobs: Vec<MeshingJob>,
+    next_handle: u64,
+}
+
+impl GpuMesher {
+    pub fn new(device: &Arc<Device>, max_faces: u32) -> Result<Self, RenderError> {
+        let shader = mesher_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let pipeline = ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+            .map_err(RenderError::ComputePipelineCreation)?;
+        let quad_indices = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::index_buffer(), false, quad_indices(max_faces))
+            .map_err(RenderError::Allocation)?;
+        Ok(GpuMesher {
+            device: device.clone(),
+            pipeline: Arc::new(pipeline),
+            quad_indices,
+            max_faces,
+            meshes: HashMap::new(),
+            jobs: vec![],
+            next_handle: 0,
+        })
+    }
+
+    /// Queues the chunk at `position` for meshing. Its neighbors are looked up in the world like `culled_mesher` does,
+    /// so it is meshed as the world is now.
+    pub fn mesh(&mut self, world: &VoxelWorld, position: ChunkPosition, registry: &BlockRegistry) -> Result<GpuMeshHandle, RenderError> {
+        let storage = BufferUsage { storage_buffer: true, .. BufferUsage::none() };
+        let blocks = CpuAccessibleBuffer::from_iter(self.device.clone(), storage, false, padded_blocks(world, position).into_iter())
+            .map_err(RenderError::Allocation)?;
+        let block_table = CpuAccessibleBuffer::from_iter(self.device.clone(), storage, false, registry.iter().map(|(_, block)| {
+            [block.color[0], block.color[1], block.color[2], if block.opaque { 1.0 } else { 0.0 }]
+        })).map_err(RenderError::Allocation)?;
+        let face_layers = CpuAccessibleBuffer::from_iter(self.device.clone(), storage, false, (0..registry.len()*6).map(|index| {
+            registry.texture_layer((index/6) as BlockId, index%6)
+        })).map_err(RenderError::Allocation)?;
+        let vertex_usage = BufferUsage { storage_buffer: true, vertex_buffer: true, transfer_source: true, .. BufferUsage::none() };
+        let vertex_buffer = DeviceLocalBuffer::array(self.device.clone(), self.max_faces as usize*4, vertex_usage, self.device.active_queue_families())
+            .map_err(RenderError::Allocation)?;
+        let command_usage = BufferUsage { storage_buffer: true, indirect_buffer: true, transfer_source: true, transfer_destination: true, .. BufferUsage::none() };
+        let command = DeviceLocalBuffer::array(self.device.clone(), 1, command_usage, self.device.active_queue_families())
+            .map_err(RenderError::Allocation)?;
+        let initial_command = DrawIndexedIndirectCommand { index_count: 0, instance_count: 1, first_index: 0, vertex_offset: 0, first_instance: 0 };
+        let initial_command = CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage::transfer_source(), false, std::iter::once(initial_command))
+            .map_err(RenderError::Allocation)?;
+
+        let layout = self.pipeline.descriptor_set_layout(0).expect("The mesher shader has no descriptor set");
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(blocks)
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(block_table)
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(vertex_buffer.clone())
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(command.clone())
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(face_layers)
+            .map_err(RenderError::DescriptorSet)?
+            .build()
+            .map_err(RenderError::DescriptorSetBuild)?);
+
+        let handle = GpuMeshHandle(self.next_handle);
+        self.next_handle += 1;
+        self.jobs.push(MeshingJob { set, initial_command, command: command.clone(), block_count: registry.len() as u32 });
+        self.meshes.insert(handle, GpuMesh { vertex_buffer, command });
+        Ok(handle)
+    }
+
+    /// Frees a mesh. Frames in flight drawing it keep its buffers alive until they are done.
+    pub fn remove(&mut self, handle: GpuMeshHandle) -> bool {
+        self.meshes.remove(&handle).is_some()
+    }
+
+    pub fn get(&self, handle: GpuMeshHandle) -> Option<&GpuMesh> {
+        self.meshes.get(&handle)
+    }
+
+    pub fn quad_indices(&self) -> Arc<CpuAccessibleBuffer<[u32]>> {
+        self.quad_indices.clone()
+    }
+
+    pub fn take_jobs(&mut self) -> MeshingJobs {
+        MeshingJobs { pipeline: self.pipeline.clone(), max_faces: self.max_faces, jobs: self.jobs.drain(..).collect() }
+    }
+}
+
+/// Indices of `faces` quads of four vertices each, as two counter clockwise triangles.
+fn quad_indices(faces: u32) -> impl ExactSizeIterator<Item = u32> {
+    (0..faces*6).map(|index| {
+        let (quad, corner) = (index/6, index%6);
+        quad*4 + [0, 1, 2, 0, 2, 3][corner as usize]
+    })
+}
+
+/// The blocks of the chunk and of the layer of blocks around it, in the order the shader reads them.
+fn padded_blocks(world: &VoxelWorld, position: ChunkPosition) -> Vec<u32> {
+    let chunk = world.chunk(position);
+    let origin = position.origin();
+    let mut blocks = Vec::with_capacity(PADDED_SIZE*PADDED_SIZE*PADDED_SIZE);
+    let size = CHUNK_SIZE as i32;
+    for x in -1..=size {
+        for y in -1..=size {
+            for z in -1..=size {
+                let inside = x >= 0 && y >= 0 && z >= 0 && x < size && y < size && z < size;
+                let block = if inside {
+                    chunk.map_or(AIR, |chunk| chunk.chunk_data.get(x as usize, y as usize, z as usize))
+                } else {
+                    world.get(origin.offset(x, y, z))
+                };
+                blocks.push(block as u32);
+            }
+        }
+    }
+    blocks
+}
+
+/// Reads back a mesh made by `GpuMesher` into a `ChunkMesh`, once the GPU is done with it.
+pub(crate) fn read_mesh(vertices: &CpuAccessibleBuffer<[MeshVertex]>, command: &CpuAccessibleBuffer<[DrawIndexedIndirectCommand]>) -> ChunkMesh {
+    let faces = command.read().expect("Failed to read back mesh command")[0].index_count/6;
+    let vertices = vertices.read().expect("Failed to read back mesh vertices");
+    ChunkMesh {
+        vertices: vertices[..faces as usize*4].to_vec(),
+        indices: quad_indices(faces).collect(),
+        .. Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::base::block::BlockDefinition;
+    use crate::base::mesher::culled_mesher;
+    use crate::base::voxel::ChunkData;
+    use crate::engine::render::render_server::RenderServer;
+    use crate::engine::render::settings::{RenderSettings, DeviceSelection, DevicePreference};
+    use super::*;
+
+    /// A face as its corners and normal, rounded to integers, along with its color and texture layer.
+    fn face_set(mesh: &ChunkMesh) -> HashSet<(Vec<[i32; 3]>, [i32; 3], [u32; 3], u32)> {
+        mesh.indices.chunks(6).map(|quad| {
+            let corners = [quad[0], quad[1], quad[2], quad[5]].iter().map(|index| {
+                let position = mesh.vertices[*index as usize].position;
+                [position[0].round() as i32, position[1].round() as i32, position[2].round() as i32]
+            }).collect();
+            let vertex = mesh.vertices[quad[0] as usize];
+            let normal = [vertex.normal[0] as i32, vertex.normal[1] as i32, vertex.normal[2] as i32];
+            let color = [vertex.color[0].to_bits(), vertex.color[1].to_bits(), vertex.color[2].to_bits()];
+            (corners, normal, color, vertex.layer)
+        }).collect()
+    }
+
+    /// Needs a Vulkan driver, which can be a software one such as lavapipe, so it only runs with
+    /// `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn gpu_mesher_matches_culled_mesher() {
+        let mut registry = BlockRegistry::new();
+        let stone = registry.register(BlockDefinition::new("stone").with_face_textures("stone_top", "stone", "stone"));
+        let glass = registry.register(BlockDefinition::new("glass").with_color([0.6, 0.8, 1.0]).transparent());
+        let mut world = VoxelWorld::new();
+        let position = ChunkPosition::new(0, 0, 0);
+        let mut chunk_data = ChunkData::new();
+        let mut neighbor_data = ChunkData::new();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    // A scattered layer, so that most blocks show most of their faces
+                    let block = match (x*7 + y*13 + z*5) % 11 {
+                        _ if y >= 16 => AIR,
+                        0 | 1 => stone,
+                        2 => glass,
+                        // Ids missing from the registry are drawn magenta
+                        3 if y == 0 => 200,
+                        _ => AIR,
+                    };
+                    chunk_data.set(block, x, y, z);
+                    if (x + y + z) % 3 == 0 {
+                        neighbor_data.set(stone, x, y, z);
+                    }
+                }
+            }
+        }
+        world.insert_chunk(position, chunk_data);
+        world.insert_chunk(position.offset(1, 0, 0), neighbor_data);
+
+        let settings = RenderSettings {
+            device: DeviceSelection::Prefer(DevicePreference::Software),
+            gpu_mesh_max_faces: 1 << 18,
+            .. Default::default()
+        };
+        let mut server = RenderServer::headless(settings, [64, 64]).unwrap_or_else(|error| panic!("{}", error));
+        let handle = server.mesh_chunk_on_gpu(&world, position, &registry).unwrap_or_else(|error| panic!("{}", error));
+        let gpu_mesh = server.read_gpu_mesh(handle).unwrap();
+        let cpu_mesh = culled_mesher::generate_mesh(&world, position, &registry);
+
+        assert_eq!(gpu_mesh.indices.len(), cpu_mesh.indices.len());
+        assert!(face_set(&gpu_mesh) == face_set(&cpu_mesh), "The GPU mesh has different faces than the CPU one");
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::Arc;
+use cgmath::Matrix4;
+use vulkano::buffer::{BufferAccess, BufferSlice, BufferUsage, CpuBufferPool, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DrawIndexedIndirectCommand, DynamicState};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::pipeline_layout::PipelineLayout;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, DeviceOwned};
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::memory::pool::StdMemoryPool;
+use vulkano::pipeline::ComputePipeline;
+use crate::base::world::ChunkPosition;
+use crate::engine::render::chunk_buffers::ChunkBuffers;
+use crate::engine::render::error::RenderError;
+use crate::engine::render::frustum::Frustum;
+use crate::engine::render::mesh::MeshHandle;
+use crate::engine::render::render_server::MeshPipeline;
+
+mod cull_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/engine/render/shaders/chunk_cull.comp"
+    }
+}
+
+/// Invocations per workgroup of the culling shader, its `local_size_x`.
+const CULL_WORKGROUP_SIZE: u32 = 64;
+
+type CullPipeline = ComputePipeline<PipelineLayout<cull_shader::Layout>>;
+type PoolChunk<T> = CpuBufferPoolChunk<T, Arc<StdMemoryPool>>;
+
+/// What the shaders know about each chunk drawn, laid out like `ChunkDrawInfo` in std430.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ChunkDrawInfo {
+    pub model: [[f32; 4]; 4],
+    /// World space bounds of the mesh, for culling on the GPU.
+    pub bounds_min: [f32; 4],
+    pub bounds_max: [f32; 4],
+    /// The group of draws the chunk belongs to, and where the commands of that group start.
+    pub group: u32,
+    pub group_start: u32,
+    _padding: [u32; 2],
+}
+
+/// Consecutive draws reading from the same arena, issued by a single indirect draw.
+#[derive(Copy, Clone, Debug)]
+struct DrawGroup {
+    arena: usize,
+    start: usize,
+    count: usize,
+}
+
+/// Buffers written by the culling shader, one set per frame in flight. They only grow.
+#[derive(Clone)]
+struct CullTargets {
+    commands: Arc<DeviceLocalBuffer<[DrawIndexedIndirectCommand]>>,
+    counters: Arc<DeviceLocalBuffer<[u32]>>,
+}
+
+struct GpuCulling {
+    pipeline: Arc<CullPipeline>,
+    uniforms: CpuBufferPool<cull_shader::ty::CullUniforms>,
+    targets: Vec<Option<CullTargets>>,
+}
+
+/// The culling pass of one frame.
+struct CullDispatch {
+    set: Arc<dyn DescriptorSet + Send + Sync>,
+    targets: CullTargets,
+    draw_count: u32,
+    group_count: usize,
+}
+
+/// The draws of one frame, ready to be recorded.
+pub(crate) struct IndirectBatch {
+    draw_infos: Arc<PoolChunk<ChunkDrawInfo>>,
+    commands: Arc<PoolChunk<DrawIndexedIndirectCommand>>,
+    groups: Vec<DrawGroup>,
+    culling: Option<CullDispatch>,
+}
+
+/// Draws chunks with one `draw_indexed_indirect` per arena, the model matrices being read from a buffer
+/// of per chunk draw info indexed by the first instance of each command. Commands can optionally be
+/// frustum culled and compacted by a compute shader.
+pub(crate) struct IndirectDraws {
+    pipeline: Arc<MeshPipeline>,
+    draw_infos: CpuBufferPool<ChunkDrawInfo>,
+    commands: CpuBufferPool<DrawIndexedIndirectCommand>,
+    culling: Option<GpuCulling>,
+}
+
+impl IndirectDraws {
+    /// Whether the device was created with the features indirect draws rely on.
+    pub fn is_supported(device: &Arc<Device>) -> bool {
+        let features = device.enabled_features();
+        features.multi_draw_indirect && features.draw_indirect_first_instance
+    }
+
+    /// `slots` is the number of frames that may be recorded before the first one is done.
+    pub fn new(device: &Arc<Device>, pipeline: Arc<MeshPipeline>, gpu_culling: bool, slots: usize) -> Result<Self, RenderError> {
+        let culling = if gpu_culling {
+            let shader = cull_shader::Shader::load(device.clone())
+                .map_err(RenderError::ShaderCreation)?;
+            let pipeline = ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+                .map_err(RenderError::ComputePipelineCreation)?;
+            Some(GpuCulling {
+                pipeline: Arc::new(pipeline),
+                uniforms: CpuBufferPool::uniform_buffer(device.clone()),
+                targets: (0..slots).map(|_| None).collect(),
+            })
+        } else {
+            None
+        };
+        let command_usage = BufferUsage { indirect_buffer: true, storage_buffer: true, .. BufferUsage::none() };
+        Ok(IndirectDraws {
+            pipeline,
+            draw_infos: CpuBufferPool::new(device.clone(), BufferUsage { storage_buffer: true, .. BufferUsage::none() }),
+            commands: CpuBufferPool::new(device.clone(), command_usage),
+            culling,
+        })
+    }
+
+    /// Whether frustum culling, when enabled, happens on the GPU, in which case the draw list should not be culled beforehand.
+    pub fn culls_on_gpu(&self) -> bool {
+        self.culling.is_some()
+    }
+
+    /// Builds the draw info and commands of the draw list. Draws of meshes that no longer exist are dropped.
+    /// When culling on the GPU, the draws are culled against `frustum`, or not at all without one.
+    pub fn prepare(&mut self, meshes: &ChunkBuffers, draw_list: &[(MeshHandle, Matrix4<f32>, Option<ChunkPosition>)],
+                   frustum: Option<&Frustum>, slot: usize) -> IndirectBatch {
+        let mut draws: Vec<_> = draw_list.iter().filter_map(|(handle, model, _)| {
+            let (arena, command) = meshes.indirect_command(*handle, 0)?;
+            let bounds = meshes.bounds(*handle)?.transformed(model);
+            Some((arena, command, *model, bounds))
+        }).collect();
+        draws.sort_by_key(|(arena, _, _, _)| *arena);
+
+        let mut groups: Vec<DrawGroup> = vec![];
+        let mut draw_infos = Vec::with_capacity(draws.len());
+        let mut commands = Vec::with_capacity(draws.len());
+        for (index, (arena, command, model, bounds)) in draws.into_iter().enumerate() {
+            match groups.last_mut() {
+                Some(group) if group.arena == arena => group.count += 1,
+                _ => groups.push(DrawGroup { arena, start: index, count: 1 }),
+            }
+            let group = groups.last().unwrap();
+            draw_infos.push(ChunkDrawInfo {
+                model: model.into(),
+                bounds_min: bounds.min.to_homogeneous().into(),
+                bounds_max: bounds.max.to_homogeneous().into(),
+                group: (groups.len() - 1) as u32,
+                group_start: group.start as u32,
+                .. Default::default()
+            });
+            commands.push(DrawIndexedIndirectCommand { first_instance: index as u32, .. command });
+        }
+
+        let draw_count = commands.len();
+        let draw_infos = Arc::new(self.draw_infos.chunk(draw_infos).expect("Failed to allocate chunk draw info"));
+        let commands = Arc::new(self.commands.chunk(commands).expect("Failed to allocate indirect commands"));
+        let culling = match (self.culling.as_mut(), frustum) {
+            (Some(culling), Some(frustum)) if draw_count > 0 => Some(culling.prepare(frustum, &draw_infos, &commands, draw_count, groups.len(), slot)
+                .expect("Failed to allocate culling buffers")),
+            _ => None,
+        };
+        IndirectBatch { draw_infos, commands, groups, culling }
+    }
+}
+
+impl GpuCulling {
+    fn targets(&mut self, device: &Arc<Device>, draw_count: usize, group_count: usize, slot: usize) -> Result<CullTargets, DeviceMemoryAllocError> {
+        if let Some(targets) = &self.targets[slot] {
+            if targets.commands.len() >= draw_count && targets.counters.len() >= group_count {
+                return Ok(targets.clone());
+            }
+        }
+        let command_usage = BufferUsage { indirect_buffer: true, storage_buffer: true, transfer_destination: true, .. BufferUsage::none() };
+        let counter_usage = BufferUsage { storage_buffer: true, transfer_destination: true, .. BufferUsage::none() };
+        let targets = CullTargets {
+            commands: DeviceLocalBuffer::array(device.clone(), draw_count.next_power_of_two(), command_usage, device.active_queue_families())?,
+            counters: DeviceLocalBuffer::array(device.clone(), group_count.next_power_of_two(), counter_usage, device.active_queue_families())?,
+        };
+        self.targets[slot] = Some(targets.clone());
+        Ok(targets)
+    }
+
+    fn prepare(&mut self, frustum: &Frustum, draw_infos: &Arc<PoolChunk<ChunkDrawInfo>>, commands: &Arc<PoolChunk<DrawIndexedIndirectCommand>>,
+               draw_count: usize, group_count: usize, slot: usize) -> Result<CullDispatch, DeviceMemoryAllocError> {
+        let device = self.pipeline.device().clone();
+        let targets = self.targets(&device, draw_count, group_count, slot)?;
+        let mut planes = [[0.0; 4]; 6];
+        for (plane, frustum_plane) in planes.iter_mut().zip(frustum.planes.iter()) {
+            *plane = (*frustum_plane).into();
+        }
+        let uniforms = self.uniforms.next(cull_shader::ty::CullUniforms { planes })?;
+        let layout = self.pipeline.descriptor_set_layout(0).unwrap();
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(uniforms)
+            .unwrap()
+            .add_buffer(draw_infos.clone())
+            .unwrap()
+            .add_buffer(commands.clone())
+            .unwrap()
+            .add_buffer(targets.commands.clone())
+            .unwrap()
+            .add_buffer(targets.counters.clone())
+            .unwrap()
+            .build()
+            .unwrap());
+        Ok(CullDispatch { set, targets, draw_count: draw_count as u32, group_count })
+    }
+}
+
+impl IndirectBatch {
+    /// Indirect draws issued when recording this batch, before any culling on the GPU.
+    pub fn draw_call_count(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Records the culling pass, which has to happen outside of the render pass.
+    pub fn record_culling(&self, builder: AutoCommandBufferBuilder, indirect: &IndirectDraws) -> AutoCommandBufferBuilder {
+        let (dispatch, culling) = match (&self.culling, &indirect.culling) {
+            (Some(dispatch), Some(culling)) => (dispatch, culling),
+            _ => return builder,
+        };
+        let workgroups = (dispatch.draw_count + CULL_WORKGROUP_SIZE - 1)/CULL_WORKGROUP_SIZE;
+        // Zeroed commands draw no instances, which is what the slots past the visible draws of each group hold
+        let commands = BufferSlice::from_typed_buffer_access(dispatch.targets.commands.clone())
+            .slice(0..dispatch.draw_count as usize)
+            .unwrap();
+        let counters = BufferSlice::from_typed_buffer_access(dispatch.targets.counters.clone())
+            .slice(0..dispatch.group_count)
+            .unwrap();
+        builder.fill_buffer(commands, 0)
+            .unwrap()
+            .fill_buffer(counters, 0)
+            .unwrap()
+            .dispatch([workgroups, 1, 1], culling.pipeline.clone(), dispatch.set.clone(), cull_shader::ty::PushConstants { draw_count: dispatch.draw_count })
+            .unwrap()
+    }
+
+    /// Records one indirect draw per group, inside of the render pass. `texture_set` and `lighting_set` are bound
+    /// as sets 1 and 2, as for the graphics pipeline.
+    pub fn record_draws<B>(&self, mut builder: AutoCommandBufferBuilder, indirect: &IndirectDraws, meshes: &ChunkBuffers,
+                           dynamic_state: &DynamicState, camera_buffer: B, texture_set: &Arc<dyn DescriptorSet + Send + Sync>,
+                           lighting_set: &Arc<dyn DescriptorSet + Send + Sync>) -> AutoCommandBufferBuilder
+        where B: BufferAccess + Send + Sync + 'static {
+        if self.groups.is_empty() {
+            return builder;
+        }
+        let layout = indirect.pipeline.descriptor_set_layout(0).unwrap();
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(camera_buffer)
+            .unwrap()
+            .add_buffer(self.draw_infos.clone())
+            .unwrap()
+            .build()
+            .unwrap());
+        for group in self.groups.iter() {
+            let (vertex_buffer, index_buffer) = meshes.arena_buffers(group.arena);
+            let range = group.start..group.start + group.count;
+            builder = match &self.culling {
+                Some(dispatch) => builder.draw_indexed_indirect(indirect.pipeline.clone(), dynamic_state, vertex_buffer, index_buffer,
+                                                                 BufferSlice::from_typed_buffer_access(dispatch.targets.commands.clone()).slice(range).unwrap(),
+                                                                 (set.clone(), texture_set.clone(), lighting_set.clone()), ()),
+                None => builder.draw_indexed_indirect(indirect.pipeline.clone(), dynamic_state, vertex_buffer, index_buffer,
+                                                      BufferSlice::from_typed_buffer_access(self.commands.clone()).slice(range).unwrap(),
+                                                      (set.clone(), texture_set.clone(), lighting_set.clone()), ()),
+            }.unwrap();
+        }
+        builder
+    }
+}
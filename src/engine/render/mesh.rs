@@ -0,0 +1,6 @@
+use crate::base::mesher::MeshVertex;
+
+vulkano::impl_vertex!(MeshVertex, position, normal, uv, color, layer);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeshHandle(pub(crate) u64);
@@ -1,3 +1,22 @@
 pub mod render_system;
 pub mod components;
-pub mod render_server;
\ No newline at end of file
+pub mod render_server;
+pub mod mesh;
+pub mod settings;
+pub mod camera;
+pub mod stats;
+pub mod capture;
+pub mod golden;
+pub mod error;
+pub mod validation;
+pub mod chunk_buffers;
+pub mod frustum;
+pub mod occlusion;
+pub mod indirect;
+pub mod gpu_mesher;
+pub mod textures;
+pub mod shadows;
+pub mod sky;
+pub mod fog;
+pub mod post;
+pub mod bloom;
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use cgmath::Point3;
+use crate::base::connectivity::{ChunkConnectivity, Face, ALL_FACES};
+use crate::base::voxel::CHUNK_SIZE;
+use crate::base::world::{BlockPosition, ChunkPosition};
+use crate::engine::render::frustum::Aabb;
+
+/// The chunk containing a world space point.
+pub fn chunk_containing(point: Point3<f32>) -> ChunkPosition {
+    BlockPosition::new(point.x.floor() as i32, point.y.floor() as i32, point.z.floor() as i32).chunk()
+}
+
+/// World space bounds of a chunk.
+pub fn chunk_bounds(position: ChunkPosition) -> Aabb {
+    let origin = position.origin();
+    let min = Point3::new(origin.x as f32, origin.y as f32, origin.z as f32);
+    let size = CHUNK_SIZE as f32;
+    Aabb::new(min, Point3::new(min.x + size, min.y + size, min.z + size))
+}
+
+fn bit(face: Face) -> u8 {
+    1 << (face as u8)
+}
+
+/// Walks from `camera_chunk` to the chunks that may be visible from it: a chunk is entered through one face
+/// and left through another only if its connectivity links the two, and the walk never turns back towards
+/// a direction it already went. Chunks missing from `connectivity` are open, the walk stays within the
+/// box enclosing them and the camera. `in_view` can stop the walk at chunks outside of the frustum.
+pub fn visible_chunks<F>(camera_chunk: ChunkPosition, connectivity: &HashMap<ChunkPosition, ChunkConnectivity>, in_view: F) -> HashSet<ChunkPosition>
+    where F: Fn(ChunkPosition) -> bool {
+    let (mut min, mut max) = (camera_chunk, camera_chunk);
+    for position in connectivity.keys() {
+        min = ChunkPosition::new(min.x.min(position.x), min.y.min(position.y), min.z.min(position.z));
+        max = ChunkPosition::new(max.x.max(position.x), max.y.max(position.y), max.z.max(position.z));
+    }
+    let inside = |position: ChunkPosition| {
+        position.x >= min.x && position.y >= min.y && position.z >= min.z
+            && position.x <= max.x && position.y <= max.y && position.z <= max.z
+    };
+
+    let mut visible = HashSet::new();
+    visible.insert(camera_chunk);
+    // Each entry is a chunk, the face it was entered through, and the directions taken to reach it
+    let mut queue = VecDeque::new();
+    queue.push_back((camera_chunk, None, 0u8));
+    while let Some((position, entry, directions)) = queue.pop_front() {
+        let chunk = connectivity.get(&position).cloned().unwrap_or_default();
+        for exit in ALL_FACES.iter() {
+            if directions & bit(exit.opposite()) != 0 {
+                continue;
+            }
+            if let Some(entry) = entry {
+                if !chunk.connects(entry, *exit) {
+                    continue;
+                }
+            }
+            let (dx, dy, dz) = exit.offset();
+            let neighbor = position.offset(dx, dy, dz);
+            if !inside(neighbor) || visible.contains(&neighbor) || !in_view(neighbor) {
+                continue;
+            }
+            visible.insert(neighbor);
+            queue.push_back((neighbor, Some(exit.opposite()), directions | bit(*exit)));
+        }
+    }
+    return visible;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A horizontal slab of chunks at `y`, each connecting only the faces in `connect`.
+    fn slab(y: i32, connect: &[(Face, Face)]) -> HashMap<ChunkPosition, ChunkConnectivity> {
+        let mut connectivity = ChunkConnectivity::none();
+        for (a, b) in connect.iter() {
+            connectivity.connect(*a, *b);
+        }
+        let mut chunks = HashMap::new();
+        for x in -2..=2 {
+            for z in -2..=2 {
+                chunks.insert(ChunkPosition::new(x, y, z), connectivity);
+            }
+        }
+        chunks
+    }
+
+    #[test]
+    fn solid_layer_hides_what_is_below() {
+        let mut chunks = slab(0, &[]);
+        chunks.extend(slab(-1, &[]));
+        chunks.extend(slab(-2, &[(Face::PosX, Face::NegX)]));
+        let visible = visible_chunks(ChunkPosition::new(0, 1, 0), &chunks, |_| true);
+        assert!(visible.contains(&ChunkPosition::new(0, 0, 0)));
+        assert!(visible.contains(&ChunkPosition::new(2, 0, -2)));
+        assert!(!visible.contains(&ChunkPosition::new(0, -1, 0)));
+        assert!(!visible.contains(&ChunkPosition::new(0, -2, 0)));
+    }
+
+    #[test]
+    fn shafts_reveal_what_is_below() {
+        let mut chunks = slab(0, &[]);
+        chunks.insert(ChunkPosition::new(1, 0, 1), ChunkConnectivity::default());
+        chunks.extend(slab(-1, &[(Face::PosY, Face::NegY)]));
+        let visible = visible_chunks(ChunkPosition::new(1, 1, 1), &chunks, |_| true);
+        assert!(visible.contains(&ChunkPosition::new(1, -1, 1)));
+        assert!(!visible.contains(&ChunkPosition::new(0, -1, 1)));
+    }
+
+    #[test]
+    fn chunks_out_of_view_stop_the_walk() {
+        let chunks = slab(0, &[(Face::PosX, Face::NegX)]);
+        let visible = visible_chunks(ChunkPosition::new(-2, 0, 0), &chunks, |position| position.x < 0);
+        assert!(visible.contains(&ChunkPosition::new(-1, 0, 0)));
+        assert!(!visible.contains(&ChunkPosition::new(0, 0, 0)));
+        assert!(!visible.contains(&ChunkPosition::new(1, 0, 0)));
+    }
+}
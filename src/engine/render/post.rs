@@ -0,0 +1,321 @@
+use std::sync::Arc;
+use cgmath::Vector3;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::{ClearValue, Format};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::shader::GraphicsEntryPointAbstract;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use crate::engine::render::error::RenderError;
+use crate::engine::render::render_server::RenderServer;
+use crate::engine::render::settings::{PostSettings, Tonemapping};
+
+mod post_vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/engine/render/shaders/post.vert"
+    }
+}
+
+mod tonemap_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/tonemap.frag"
+    }
+}
+
+/// Format of the scene and of the images post effects render into, which holds colors brighter than white.
+pub const HDR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+/// Output formats that encode colors to sRGB when written to.
+const SRGB_FORMATS: &[Format] = &[
+    Format::B8G8R8A8Srgb,
+    Format::R8G8B8A8Srgb,
+    Format::A8B8G8R8SrgbPack32,
+];
+
+pub fn is_srgb(format: Format) -> bool {
+    SRGB_FORMATS.contains(&format)
+}
+
+/// Narkowicz's fit of the ACES filmic curve, as computed by the shaders.
+fn aces(color: f32) -> f32 {
+    ((color*(2.51*color + 0.03))/(color*(2.43*color + 0.59) + 0.14)).max(0.0).min(1.0)
+}
+
+/// Maps a linear HDR color to a displayable linear color, as computed by the tonemapping pass.
+pub fn tonemap(color: Vector3<f32>, exposure: f32, tonemapping: Tonemapping) -> Vector3<f32> {
+    let color = color*exposure;
+    let map = |color: f32| match tonemapping {
+        Tonemapping::Aces => aces(color),
+        Tonemapping::Reinhard => color/(1.0 + color),
+        Tonemapping::Clamp => color.max(0.0).min(1.0),
+    };
+    Vector3::new(map(color.x), map(color.y), map(color.z))
+}
+
+pub(crate) type PostPipeline = GraphicsPipeline<BufferlessDefinition, Box<dyn PipelineLayoutAbstract + Send + Sync + 'static>, Arc<dyn RenderPassAbstract + Send + Sync + 'static>>;
+
+/// Pipeline drawing a full screen triangle into `render_pass`, shaded by `fragment_entry`.
+pub(crate) fn post_pipeline<Fs>(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, fragment_entry: Fs)
+-> Result<Arc<PostPipeline>, RenderError>
+    where Fs: GraphicsEntryPointAbstract<SpecializationConstants = ()>, Fs::PipelineLayout: Clone + Send + Sync + 'static {
+    let vert_shader_module = post_vertex_shader::Shader::load(device.clone())
+        .map_err(RenderError::ShaderCreation)?;
+    let pipeline = GraphicsPipeline::start()
+        .vertex_input(BufferlessDefinition)
+        .vertex_shader(vert_shader_module.main_entry_point(), ())
+        .triangle_list()
+        .primitive_restart(false)
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fragment_entry, ())
+        .cull_mode_disabled()
+        .blend_pass_through()
+        .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+        .build(device.clone())
+        .map_err(RenderError::PipelineCreation)?;
+    Ok(Arc::new(pipeline))
+}
+
+/// What post effects are built with.
+#[derive(Clone)]
+pub struct PostContext {
+    pub device: Arc<Device>,
+    /// Render pass with a single `HDR_FORMAT` color attachment that effects render into.
+    pub render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    /// Linear sampler clamping to the edges, for reading the images of the chain.
+    pub sampler: Arc<Sampler>,
+}
+
+/// An `HDR_FORMAT` image that can be rendered into through `PostContext::render_pass`, then sampled.
+pub struct PostTarget {
+    pub image: Arc<AttachmentImage>,
+    pub framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+}
+
+impl PostContext {
+    pub fn create_target(&self, dimensions: [u32; 2]) -> Result<PostTarget, RenderError> {
+        let usage = ImageUsage {
+            color_attachment: true,
+            sampled: true,
+            .. ImageUsage::none()
+        };
+        let image = AttachmentImage::with_usage(self.device.clone(), dimensions, HDR_FORMAT, usage)
+            .map_err(RenderError::ImageCreation)?;
+        let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(self.render_pass.clone())
+            .add(image.clone()).map_err(RenderError::FramebufferCreation)?
+            .build().map_err(RenderError::FramebufferCreation)?);
+        Ok(PostTarget { image, framebuffer })
+    }
+}
+
+/// A full screen effect applied to the HDR image of the scene before it is tonemapped, added to the
+/// render server with `RenderServer::add_post_effect`.
+pub trait PostEffect {
+    /// Creates whatever the effect needs for frames of `dimensions`. Called before the first frame and
+    /// whenever the size of the frames changes.
+    fn resize(&mut self, _dimensions: [u32; 2]) -> Result<(), RenderError> {
+        Ok(())
+    }
+
+    /// Records the effect, reading `input` and writing every pixel of `output`, a framebuffer of
+    /// `PostContext::render_pass`. Both are `dimensions` sized.
+    fn record(&self, builder: AutoCommandBufferBuilder, input: &Arc<AttachmentImage>, output: &Arc<dyn FramebufferAbstract + Send + Sync>,
+              dimensions: [u32; 2]) -> AutoCommandBufferBuilder;
+}
+
+/// The scene and the depth buffer it is drawn with.
+struct SceneTarget {
+    color: Arc<AttachmentImage>,
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+}
+
+/// Takes the scene, rendered in HDR, through the post effects then tonemaps it into the output image.
+pub(crate) struct PostChain {
+    context: PostContext,
+    scene_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    depth_format: Format,
+    /// `None` until the chain is first resized.
+    scene: Option<SceneTarget>,
+    /// Images the effects render into, in turns.
+    buffers: Vec<PostTarget>,
+    effects: Vec<Box<dyn PostEffect>>,
+    dimensions: [u32; 2],
+    output_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    tonemap_pipeline: Arc<PostPipeline>,
+    /// Set when the output format does not encode to sRGB by itself.
+    encode_srgb: bool,
+}
+
+impl PostChain {
+    /// `scene_pass` draws the scene into an `HDR_FORMAT` color attachment and a `depth_format` depth attachment.
+    /// The chain ends in images of `output_format`, which get linear colors encoded to sRGB.
+    pub fn new(device: &Arc<Device>, scene_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, depth_format: Format,
+               output_format: Format) -> Result<Self, RenderError> {
+        let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        ).map_err(RenderError::RenderPassCreation)?);
+        let output_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: output_format,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        ).map_err(RenderError::RenderPassCreation)?);
+
+        let frag_shader_module = tonemap_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let tonemap_pipeline = post_pipeline(device, &output_pass, frag_shader_module.main_entry_point())?;
+
+        let clamp = SamplerAddressMode::ClampToEdge;
+        let sampler = Sampler::new(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                   clamp, clamp, clamp, 0.0, 1.0, 0.0, 0.0)
+            .map_err(RenderError::SamplerCreation)?;
+
+        Ok(PostChain {
+            context: PostContext { device: device.clone(), render_pass, sampler },
+            scene_pass: scene_pass.clone(),
+            depth_format,
+            scene: None,
+            buffers: vec![],
+            effects: vec![],
+            dimensions: [0, 0],
+            output_pass,
+            tonemap_pipeline,
+            encode_srgb: !is_srgb(output_format),
+        })
+    }
+
+    pub fn context(&self) -> &PostContext {
+        &self.context
+    }
+
+    /// Render pass the output images are drawn through, with a single color attachment.
+    pub fn output_pass(&self) -> &Arc<dyn RenderPassAbstract + Send + Sync> {
+        &self.output_pass
+    }
+
+    /// Appends `effect` to the chain, after the effects already in it.
+    pub fn push(&mut self, effect: Box<dyn PostEffect>) {
+        self.effects.push(effect);
+        // Resized along with everything else before the next frame
+        self.dimensions = [0, 0];
+    }
+
+    /// Sizes the scene, the buffers and the effects for frames of `dimensions`, if they are not already.
+    pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), RenderError> {
+        if self.dimensions == dimensions {
+            return Ok(());
+        }
+        let usage = ImageUsage {
+            color_attachment: true,
+            sampled: true,
+            .. ImageUsage::none()
+        };
+        let color = AttachmentImage::with_usage(self.context.device.clone(), dimensions, HDR_FORMAT, usage)
+            .map_err(RenderError::ImageCreation)?;
+        let depth = AttachmentImage::transient(self.context.device.clone(), dimensions, self.depth_format)
+            .map_err(RenderError::ImageCreation)?;
+        let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(self.scene_pass.clone())
+            .add(color.clone()).map_err(RenderError::FramebufferCreation)?
+            .add(depth).map_err(RenderError::FramebufferCreation)?
+            .build().map_err(RenderError::FramebufferCreation)?);
+        self.scene = Some(SceneTarget { color, framebuffer });
+        // Each effect reads the output of the previous one, so two buffers are enough
+        self.buffers = (0..self.effects.len().min(2)).map(|_| self.context.create_target(dimensions))
+            .collect::<Result<_, _>>()?;
+        for effect in self.effects.iter_mut() {
+            effect.resize(dimensions)?;
+        }
+        self.dimensions = dimensions;
+        Ok(())
+    }
+
+    /// Framebuffer of `scene_pass` the scene is drawn into.
+    pub fn scene_framebuffer(&self) -> Arc<dyn FramebufferAbstract + Send + Sync> {
+        self.scene.as_ref().expect("Post chain used before being resized").framebuffer.clone()
+    }
+
+    /// Records the effects then the tonemapping into `output`, a framebuffer of `output_pass`, after the scene was drawn.
+    /// The tonemapping wobbles the image by `distortion`, animated by `time` in seconds.
+    pub fn record(&self, mut builder: AutoCommandBufferBuilder, settings: &PostSettings, distortion: f32, time: f32,
+                  output: Arc<dyn FramebufferAbstract + Send + Sync>) -> AutoCommandBufferBuilder {
+        let scene = self.scene.as_ref().expect("Post chain used before being resized");
+        let mut input = scene.color.clone();
+        for (index, effect) in self.effects.iter().enumerate() {
+            let buffer = &self.buffers[index % 2];
+            builder = effect.record(builder, &input, &buffer.framebuffer, self.dimensions);
+            input = buffer.image.clone();
+        }
+
+        let layout = self.tonemap_pipeline.descriptor_set_layout(0).unwrap();
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(input, self.context.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap());
+        let push_constants = tonemap_fragment_shader::ty::PushConstants {
+            exposure: settings.exposure,
+            tonemapping: settings.tonemapping as u32,
+            encode_srgb: self.encode_srgb as u32,
+            distortion,
+            time,
+        };
+        builder.begin_render_pass(output, false, vec![ClearValue::None])
+            .unwrap()
+            .draw(self.tonemap_pipeline.clone(), &RenderServer::viewport_dynamic_state(self.dimensions),
+                  BufferlessVertices { vertices: 3, instances: 1 }, set, push_constants)
+            .unwrap()
+            .end_render_pass()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tonemapping_keeps_colors_displayable() {
+        for &tonemapping in [Tonemapping::Aces, Tonemapping::Reinhard, Tonemapping::Clamp].iter() {
+            let mut previous = 0.0;
+            for step in 0..=100 {
+                let color = tonemap(Vector3::new(1.0, 1.0, 1.0), step as f32*0.5, tonemapping).x;
+                assert!(color >= previous && color <= 1.0, "{:?} is not monotonic at {}", tonemapping, step);
+                previous = color;
+            }
+            assert_eq!(tonemap(Vector3::new(0.0, 0.0, 0.0), 1.0, tonemapping).x, 0.0);
+        }
+        assert!(tonemap(Vector3::new(50.0, 0.0, 0.0), 1.0, Tonemapping::Aces).x > 0.99);
+    }
+
+    #[test]
+    fn exposure_scales_before_the_curve() {
+        let color = Vector3::new(0.2, 0.5, 4.0);
+        assert_eq!(tonemap(color, 2.0, Tonemapping::Reinhard), tonemap(color*2.0, 1.0, Tonemapping::Reinhard));
+        assert!(tonemap(color, 0.5, Tonemapping::Aces).z < tonemap(color, 1.0, Tonemapping::Aces).z);
+    }
+}
@@ -8,29 +8,99 @@ use vulkano::VulkanObject;
 use glfw::{Context};
 use std::ptr::null;
 use std::borrow::Borrow;
-use vulkano::instance::PhysicalDevice;
-use vulkano::swapchain::{SupportedPresentModes, PresentMode, Capabilities, Swapchain, CompositeAlpha, FullscreenExclusive, Surface, acquire_next_image};
+use vulkano::instance::{PhysicalDevice, PhysicalDeviceType};
+use vulkano::swapchain::{SupportedPresentModes, PresentMode, Capabilities, Swapchain, CompositeAlpha, FullscreenExclusive, Surface, acquire_next_image, AcquireError, SwapchainCreationError};
 use vulkano::image::{SwapchainImage, ImageUsage};
-use vulkano::sync::{SharingMode, GpuFuture};
+use vulkano::format::Format;
+use vulkano::pipeline::depth_stencil::{DepthStencil, Compare};
+use vulkano::sync::{SharingMode, GpuFuture, FlushError, FenceSignalFuture};
+use std::time::Instant;
 use vulkano::device::{DeviceExtensions, Device};
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::pipeline::GraphicsPipeline;
-use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::shader::GraphicsEntryPointAbstract;
+use vulkano::pipeline::vertex::SingleBufferDefinition;
 use vulkano::framebuffer::{RenderPassAbstract, Subpass, FramebufferAbstract, Framebuffer};
 use vulkano::descriptor::PipelineLayoutAbstract;
 use std::ops::{Deref, DerefMut};
-use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState};
+use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DrawIndexedIndirectCommand, DynamicState};
 use vulkano::pipeline::raster::DepthBiasControl::Dynamic;
+use cgmath::{Matrix4, Vector3, EuclideanSpace, MetricSpace};
+use std::collections::HashMap;
+use vulkano::buffer::{BufferSlice, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::DescriptorSet;
+use crate::base::mesher::{ChunkMesh, MeshVertex};
+use crate::base::block::BlockRegistry;
+use crate::base::connectivity::ChunkConnectivity;
+use crate::base::world::{ChunkPosition, VoxelWorld};
+use crate::engine::render::mesh::MeshHandle;
+use crate::engine::render::chunk_buffers::{ChunkBuffers, ChunkBufferStats, ChunkDraw, Uploads};
+use crate::engine::render::frustum::Frustum;
+use crate::engine::render::occlusion;
+use crate::engine::render::indirect::{IndirectBatch, IndirectDraws};
+use crate::engine::render::gpu_mesher::{self, GpuMesher, GpuMeshHandle, MeshingJobs};
+use crate::engine::render::textures::BlockTextureArray;
+use crate::engine::render::shadows::{ShadowFrame, ShadowMaps, MAX_CASCADES};
+use crate::engine::render::sky::{Sky, SkyPass};
+use crate::engine::render::fog::{self, Fog};
+use crate::engine::render::post::{PostChain, PostContext, PostEffect, HDR_FORMAT};
+use crate::engine::render::bloom::Bloom;
+use crate::engine::render::settings::{RenderSettings, DeviceSelection, DevicePreference, ValidationSettings, FogSettings, PostSettings};
+use crate::engine::render::validation::{self, ValidationErrors};
+use crate::engine::render::error::RenderError;
+use crate::engine::render::camera::Camera;
+use crate::engine::render::stats::FrameStats;
+use crate::engine::render::capture::{CapturedImage, CaptureError, OffscreenTarget};
+use crate::engine::time_of_day::TimeOfDay;
 
-#[cfg(all(debug_assertions))]
-const ENABLE_VALIDATION_LAYERS: bool = true;
-#[cfg(not(debug_assertions))]
-const ENABLE_VALIDATION_LAYERS: bool = false;
+/// Depth formats by order of preference, floating point first as they work best with reverse-Z.
+const DEPTH_FORMATS: &[Format] = &[
+    Format::D32Sfloat,
+    Format::D32Sfloat_S8Uint,
+    Format::D24Unorm_S8Uint,
+    Format::X8_D24UnormPack32,
+    Format::D16Unorm,
+];
+
+/// Color format of headless render servers, which have no swap chain to pick one from.
+const HEADLESS_COLOR_FORMAT: Format = Format::R8G8B8A8Srgb;
 
-const VALIDATION_LAYERS: &[&str] = &[
-    "VK_LAYER_LUNARG_standard_validation"
+/// Swap chain formats by order of preference, those encoding to sRGB first so that the tonemapped colors
+/// are gamma corrected by the hardware.
+const SWAP_CHAIN_FORMATS: &[Format] = &[
+    Format::B8G8R8A8Srgb,
+    Format::R8G8B8A8Srgb,
+    Format::B8G8R8A8Unorm,
+    Format::R8G8B8A8Unorm,
 ];
 
+const SCREENSHOT_DIRECTORY: &str = "screenshots";
+
+
+mod vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/engine/render/shaders/default_shader.vert"
+    }
+}
+
+mod indirect_vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/engine/render/shaders/chunk_indirect.vert"
+    }
+}
+
+mod fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/default_shader.frag"
+    }
+}
+
+pub(crate) type MeshPipeline = GraphicsPipeline<SingleBufferDefinition<MeshVertex>, Box<dyn PipelineLayoutAbstract + Send + Sync + 'static>, Arc<dyn RenderPassAbstract + Send + Sync + 'static>>;
+
 //TODO, FIXME
 struct Window (glfw::Window);
 impl Deref for Window {
@@ -48,14 +118,29 @@ impl DerefMut for Window {
 }
 unsafe impl Send for Window{}
 unsafe impl Sync for Window{}
-pub struct RenderServer {
+
+/// Everything that only exists when rendering to a window.
+struct WindowTarget {
     glfw : glfw::Glfw,
     events: Receiver<(f64, glfw::WindowEvent)>,
 
     surface: Arc<vulkano::swapchain::Surface<Window>>,
 
+    swap_chain: Arc<vulkano::swapchain::Swapchain<Window>>,
+    swap_chain_images: Vec<Arc<vulkano::image::SwapchainImage<Window>>>,
+    swap_chain_framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    /// Set when the swap chain no longer matches the window, it is rebuilt before the next frame.
+    recreate_swap_chain: bool,
+}
+
+pub struct RenderServer {
+    settings: RenderSettings,
+    /// `None` for headless render servers, which can only render through `capture`.
+    window: Option<WindowTarget>,
+
     instance : Arc<vulkano::instance::Instance>,
     debug_callback: Option<vulkano::instance::debug::DebugCallback>,
+    validation_errors: ValidationErrors,
 
     physical_device_index: usize,
     device: Arc<vulkano::device::Device>,
@@ -63,21 +148,62 @@ pub struct RenderServer {
     graphics_queue: Arc<vulkano::device::Queue>,
     present_queue: Arc<vulkano::device::Queue>,
 
-    swap_chain: Arc<vulkano::swapchain::Swapchain<Window>>,
-    swap_chain_images: Vec<Arc<vulkano::image::SwapchainImage<Window>>>,
-
+    /// Format of the swap chain or of the offscreen target, which the post chain ends in.
+    color_format: Format,
+    /// Draws the scene into the HDR target of the post chain.
     render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
-    graphics_pipeline: Arc<GraphicsPipeline<BufferlessDefinition, Box<dyn PipelineLayoutAbstract + Send + Sync + 'static>, Arc<dyn RenderPassAbstract + Send + Sync + 'static>>>,
+    post: PostChain,
+    graphics_pipeline: Arc<MeshPipeline>,
+    /// Blends the translucent faces over what is already drawn, without writing depth.
+    translucent_pipeline: Arc<MeshPipeline>,
+    dynamic_state: DynamicState,
+    /// `None` when chunks are drawn one recorded draw at a time.
+    indirect: Option<IndirectDraws>,
 
-    swap_chain_framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
-    command_buffers: Vec<Arc<AutoCommandBuffer>>,
+    /// Target of `capture`, kept around as long as the size does not change.
+    offscreen: Option<OffscreenTarget>,
+    screenshot_requested: bool,
+
+    camera: Camera,
+    camera_uniforms: CpuBufferPool<vertex_shader::ty::CameraUniforms>,
+
+    meshes: ChunkBuffers,
+    /// Meshes to draw next frame, with the chunk they belong to if drawn with `draw_chunk`.
+    draw_list: Vec<(MeshHandle, Matrix4<f32>, Option<ChunkPosition>)>,
+    /// Whether culling already removed the hidden meshes from the draw list, e.g. for a screenshot of the frame.
+    draw_list_culled: bool,
+    /// Chunks without a mesh that occlusion culling should know about next frame.
+    meshless_chunks: Vec<(ChunkPosition, ChunkConnectivity)>,
+    /// Created the first time a chunk is meshed on the GPU.
+    gpu_mesher: Option<GpuMesher>,
+    gpu_draw_list: Vec<(GpuMeshHandle, Matrix4<f32>)>,
+
+    /// Binds the block textures, which it keeps alive.
+    texture_set: Arc<dyn DescriptorSet + Send + Sync>,
+    shadow_maps: ShadowMaps,
+    shadow_uniforms: CpuBufferPool<fragment_shader::ty::ShadowUniforms>,
+    sky: Sky,
+    sky_pass: SkyPass,
+    lighting_uniforms: CpuBufferPool<fragment_shader::ty::LightingUniforms>,
+    fog_uniforms: CpuBufferPool<fragment_shader::ty::FogUniforms>,
+    /// Color of the fluid the camera is in, if any.
+    camera_fluid: Option<Vector3<f32>>,
+    /// Start of the clock animating the distortion under fluids.
+    created: Instant,
+
+    /// End of the last submitted frame, which the next one is chained after.
+    previous_frame_end: Option<Box<dyn GpuFuture>>,
+    /// Fence of the frame last submitted in each slot, waited for before the slot is reused.
+    frame_fences: Vec<Option<Arc<FenceSignalFuture<Box<dyn GpuFuture>>>>>,
+    frame_index: usize,
+    frame_stats: FrameStats,
 }
 
 impl RenderServer {
-    fn get_required_extensions(glfw : &glfw::Glfw) -> Result<vulkano::instance::InstanceExtensions, vulkano_glfw::VulkanoGlfwError> {
+    fn get_required_extensions(glfw : &glfw::Glfw) -> Result<vulkano::instance::InstanceExtensions, RenderError> {
         let exts = glfw.get_required_instance_extensions();
         if exts.is_none() {
-            return Err(vulkano_glfw::VulkanoGlfwError::NoExtensions);
+            return Err(RenderError::MissingInstanceExtensions);
         }
         let iter = exts.unwrap().into_iter().map(|s| {
             let new_c_string = CString::new(s);
@@ -89,34 +215,40 @@ impl RenderServer {
         Ok(extensions)
     }
 
-    fn device_extensions() -> DeviceExtensions {
+    /// Headless rendering needs no surface extension. Debug utils are only asked for when available,
+    /// as software implementations such as lavapipe may be installed without them.
+    fn headless_extensions() -> Result<vulkano::instance::InstanceExtensions, RenderError> {
+        let supported_extensions = vulkano::instance::InstanceExtensions::supported_by_core()
+            .map_err(RenderError::ExtensionQuery)?;
+        Ok(vulkano::instance::InstanceExtensions {
+            ext_debug_utils: supported_extensions.ext_debug_utils,
+            .. vulkano::instance::InstanceExtensions::none()
+        })
+    }
+
+    fn device_extensions(windowed: bool) -> DeviceExtensions {
         DeviceExtensions {
-            khr_swapchain: true,
+            khr_swapchain: windowed,
             .. vulkano::device::DeviceExtensions::none()
         }
     }
 
-    fn check_validation_layer_support() -> bool {
-        let layers: Vec<_> = vulkano::instance::layers_list().unwrap().map(|l|
-                l.name().to_owned())
-            .collect();
-        VALIDATION_LAYERS.iter().all(|layer_name|
-            layers.contains(&layer_name.to_string()))
-    }
-
     fn check_device_extension_support(device: &PhysicalDevice) -> bool {
         let avaliable_extensions = DeviceExtensions::supported_by_device(*device);
-        let device_extensions = Self::device_extensions();
+        let device_extensions = Self::device_extensions(true);
         return avaliable_extensions.intersection(&device_extensions) == device_extensions;
     }
 
-    fn create_instance(glfw: &glfw::Glfw) -> Arc<vulkano::instance::Instance> {
-        if ENABLE_VALIDATION_LAYERS && !Self::check_validation_layer_support() {
-            println!("Validation layers requested, but not avaliable!")
+    fn create_instance(required_extensions: &vulkano::instance::InstanceExtensions, validation: &ValidationSettings) -> Result<Arc<vulkano::instance::Instance>, RenderError> {
+        let validation_layer = if validation.enabled { validation::choose_validation_layer() } else { None };
+        match validation_layer {
+            Some(layer) => log::info!("Using validation layer {}", layer),
+            None if validation.enabled => log::warn!("Validation layers requested, but not avaliable!"),
+            None => {}
         }
         let supported_extensions = vulkano::instance::InstanceExtensions::supported_by_core()
-            .expect("Failed to retrieve supported extensions");
-        println!("Supported extensions: {:?}", supported_extensions);
+            .map_err(RenderError::ExtensionQuery)?;
+        log::debug!("Supported extensions: {:?}", supported_extensions);
 
         let app_info = vulkano::instance::ApplicationInfo {
             application_name: Some("Rusty Blocks".into()),
@@ -125,41 +257,13 @@ impl RenderServer {
             engine_version: Some(vulkano::instance::Version { major: 0, minor: 1, patch: 0 }),
         };
 
-        let required_extensions = Self::get_required_extensions(glfw)
-            .expect("Unable to get required extensions");
-        if ENABLE_VALIDATION_LAYERS && Self::check_validation_layer_support() {
-            vulkano::instance::Instance::new(Some(&app_info), &required_extensions, VALIDATION_LAYERS.iter().cloned())
-                .expect("Failed to create Vulkan instance")
-        }
-        else {
-            vulkano::instance::Instance::new(Some(&app_info), &required_extensions, None)
-                .expect("Failed to create Vulkan instance")
-        }
+        vulkano::instance::Instance::new(Some(&app_info), required_extensions, validation_layer)
+            .map_err(RenderError::InstanceCreation)
     }
 
-    fn create_debug_callback(instance: &Arc<vulkano::instance::Instance>) -> Option<vulkano::instance::debug::DebugCallback> {
-        if !ENABLE_VALIDATION_LAYERS {
-            return None;
-        }
-
-        let msg_types = vulkano::instance::debug::MessageType {
-            general: true,
-            validation: true,
-            performance: true
-        };
-        let msg_severities = vulkano::instance::debug::MessageSeverity {
-            error: true,
-            warning: true,
-            information: true,
-            verbose: true
-        };
-        vulkano::instance::debug::DebugCallback::new(instance, msg_severities, msg_types, |msg| {
-            println!("validation layer {:?}", msg.description);
-        }).ok()
-    }
-
-    fn create_logical_device(instance: &Arc<vulkano::instance::Instance>, device_index: usize)
-        -> (Arc<vulkano::device::Device>, Arc<vulkano::device::Queue>, Arc<vulkano::device::Queue>) {
+    /// The device must have been picked by `select_device`, which makes sure it has a graphics queue.
+    fn create_logical_device(instance: &Arc<vulkano::instance::Instance>, device_index: usize, device_extensions: &DeviceExtensions)
+        -> Result<(Arc<vulkano::device::Device>, Arc<vulkano::device::Queue>, Arc<vulkano::device::Queue>), RenderError> {
         let physical_device = vulkano::instance::PhysicalDevice::from_index(instance, device_index).unwrap();
         let queue_family = physical_device.queue_families().find(|&q| {
             q.supports_graphics()
@@ -167,16 +271,25 @@ impl RenderServer {
 
         let queue_priority = 1.0;
 
-        let (device, mut queues) = vulkano::device::Device::new(physical_device, &vulkano::device::Features::none(), &Self::device_extensions(),
+        // Enabled when available, indirect drawing falls back to recorded draws and textures to plain filtering otherwise
+        let supported_features = physical_device.supported_features();
+        let features = vulkano::device::Features {
+            multi_draw_indirect: supported_features.multi_draw_indirect,
+            draw_indirect_first_instance: supported_features.draw_indirect_first_instance,
+            sampler_anisotropy: supported_features.sampler_anisotropy,
+            .. vulkano::device::Features::none()
+        };
+
+        let (device, mut queues) = vulkano::device::Device::new(physical_device, &features, device_extensions,
         [(queue_family, queue_priority)].iter().cloned())
-            .expect("Failed to create logical vulkan device");
+            .map_err(RenderError::DeviceCreation)?;
 
         let graphics_queue = queues.next().unwrap();
         let present_queue = queues.next().unwrap_or_else(|| graphics_queue.clone());
-        return (device, graphics_queue, present_queue);
+        return Ok((device, graphics_queue, present_queue));
     }
 
-    fn create_surface(instance: &Arc<vulkano::instance::Instance>, window : Window) -> Arc<vulkano::swapchain::Surface<Window>> {
+    fn create_surface(instance: &Arc<vulkano::instance::Instance>, window : Window) -> Result<Arc<vulkano::swapchain::Surface<Window>>, RenderError> {
         let mut internal_surface: vk_sys::SurfaceKHR = 0;
         let result = unsafe {
             glfw::ffi::glfwCreateWindowSurface(
@@ -188,44 +301,93 @@ impl RenderServer {
         };
 
         if result != vk_sys::SUCCESS {
-            panic!("Unable to create vulkan surface");
+            return Err(RenderError::SurfaceCreation(result));
         }
-        Arc::new(unsafe{(vulkano::swapchain::Surface::from_raw_surface(instance.clone(), internal_surface, window))})
+        Ok(Arc::new(unsafe{(vulkano::swapchain::Surface::from_raw_surface(instance.clone(), internal_surface, window))}))
     }
 
-    fn pick_physical_device(instance: &Arc<vulkano::instance::Instance>,surface: &Arc<Surface<Window>>) -> usize {
-        vulkano::instance::PhysicalDevice::enumerate(instance)
-            .position(|device| Self::is_device_suitable(surface,&device))
-            .unwrap()
+    /// Picks the device to use according to `selection`, among the devices for which `check` finds no reason to reject them.
+    fn select_device<F>(instance: &Arc<vulkano::instance::Instance>, selection: &DeviceSelection, check: F) -> Result<usize, RenderError>
+        where F: Fn(&PhysicalDevice) -> Result<(), String> {
+        let mut rejected = vec![];
+        let mut suitable = vec![];
+        for device in PhysicalDevice::enumerate(instance) {
+            log::debug!("Found {} queue families for device {}", device.queue_families().count(), device.name());
+            let selected = match selection {
+                DeviceSelection::Prefer(_) => true,
+                DeviceSelection::Name(name) => device.name().to_lowercase().contains(&name.to_lowercase()),
+                DeviceSelection::Index(index) => device.index() == *index,
+            };
+            if !selected {
+                rejected.push((device.name(), "not selected".to_owned()));
+                continue;
+            }
+            match check(&device) {
+                Ok(()) => suitable.push(device),
+                Err(reason) => rejected.push((device.name(), reason)),
+            }
+        }
+        let preferred = match selection {
+            DeviceSelection::Prefer(preference) => {
+                let preferred_type = match preference {
+                    DevicePreference::Discrete => PhysicalDeviceType::DiscreteGpu,
+                    DevicePreference::Integrated => PhysicalDeviceType::IntegratedGpu,
+                    DevicePreference::Software => PhysicalDeviceType::Cpu,
+                };
+                suitable.iter().find(|device| device.ty() == preferred_type)
+            }
+            _ => None,
+        };
+        match preferred.or(suitable.first()) {
+            Some(device) => Ok(device.index()),
+            None => Err(RenderError::NoSuitableDevice { selection: selection.clone(), rejected }),
+        }
     }
 
-    fn is_device_suitable(surface:&Arc<Surface<Window>>, device: &vulkano::instance::PhysicalDevice) -> bool {
-        println!("Found {} queue families for device {}", device.queue_families().count(), device.name());
-        let mut queue_suitable = false;
-        for (id, queue_family) in device.queue_families().enumerate() {
-            if queue_family.supports_graphics()  && surface.is_supported(queue_family).unwrap() {
-                queue_suitable = true;
-            }
+    fn has_graphics_queue(device: &PhysicalDevice) -> Result<(), String> {
+        if device.queue_families().any(|queue_family| queue_family.supports_graphics()) {
+            Ok(())
+        } else {
+            Err("no graphics queue".to_owned())
         }
+    }
 
-        let extensions_supported = Self::check_device_extension_support(device);
+    /// Without a surface any device that can draw will do, including software implementations.
+    fn pick_headless_device(instance: &Arc<vulkano::instance::Instance>, selection: &DeviceSelection) -> Result<usize, RenderError> {
+        Self::select_device(instance, selection, Self::has_graphics_queue)
+    }
 
-        let swap_chain_adequate = if extensions_supported {
-            let capabilities = surface.capabilities(*device)
-                .expect("Failed to get surface capabilities");
-            !capabilities.supported_formats.is_empty() &&
-                capabilities.present_modes.iter().next().is_some()
-        } else {
-            false
-        };
+    fn pick_physical_device(instance: &Arc<vulkano::instance::Instance>, surface: &Arc<Surface<Window>>, selection: &DeviceSelection) -> Result<usize, RenderError> {
+        Self::select_device(instance, selection, |device| Self::check_device_suitability(surface, device))
+    }
+
+    /// Returns why the device cannot render to the surface, if it cannot.
+    fn check_device_suitability(surface:&Arc<Surface<Window>>, device: &PhysicalDevice) -> Result<(), String> {
+        let queue_suitable = device.queue_families().any(|queue_family|
+            queue_family.supports_graphics() && surface.is_supported(queue_family).unwrap_or(false));
+        if !queue_suitable {
+            return Err("no graphics queue able to present to the window".to_owned());
+        }
+
+        if !Self::check_device_extension_support(device) {
+            return Err("VK_KHR_swapchain is not supported".to_owned());
+        }
 
-        return queue_suitable && extensions_supported && swap_chain_adequate;
+        let capabilities = surface.capabilities(*device)
+            .map_err(|error| format!("failed to get surface capabilities: {}", error))?;
+        if capabilities.supported_formats.is_empty() || capabilities.present_modes.iter().next().is_none() {
+            return Err("no surface format or present mode".to_owned());
+        }
+        return Ok(());
     }
 
     fn choose_swap_surface_format(avaliable_formats: &[(vulkano::format::Format, vulkano::swapchain::ColorSpace)]) -> (vulkano::format::Format, vulkano::swapchain::ColorSpace) {
-        *avaliable_formats.iter().find(|(format, color_space)|
-            *format == vulkano::format::Format::B8G8R8A8Unorm && *color_space == vulkano::swapchain::ColorSpace::SrgbNonLinear
-        ).unwrap_or_else(|| &avaliable_formats[0])
+        *SWAP_CHAIN_FORMATS.iter()
+            .filter_map(|preferred| avaliable_formats.iter().find(|(format, color_space)|
+                format == preferred && *color_space == vulkano::swapchain::ColorSpace::SrgbNonLinear
+            ))
+            .next()
+            .unwrap_or_else(|| &avaliable_formats[0])
     }
 
     fn choose_swap_present_mode(avaliable_modes: SupportedPresentModes) -> PresentMode {
@@ -238,12 +400,12 @@ impl RenderServer {
         }
     }
 
-    fn choose_swap_extent(capabilities: &Capabilities) -> [u32;2] {
+    fn choose_swap_extent(capabilities: &Capabilities, window: &Window) -> [u32;2] {
         if let Some(current_extent) = capabilities.current_extent {
             return current_extent;
         } else {
-            //TODO change this to a configuration file
-            let mut actual_extent = [800, 600];
+            let (width, height) = window.get_framebuffer_size();
+            let mut actual_extent = [width.max(0) as u32, height.max(0) as u32];
             actual_extent[0] = actual_extent[0].max(capabilities.min_image_extent[0]).min(capabilities.max_image_extent[0]);
             actual_extent[1] = actual_extent[1].max(capabilities.min_image_extent[1]).min(capabilities.max_image_extent[1]);
             return actual_extent;
@@ -257,14 +419,14 @@ impl RenderServer {
         device: &Arc<vulkano::device::Device>,
         graphics_queue: &Arc<vulkano::device::Queue>,
         present_queue: &Arc<vulkano::device::Queue>,
-    ) -> (Arc<vulkano::swapchain::Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>) {
+    ) -> Result<(Arc<vulkano::swapchain::Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>), RenderError> {
         let physical_device = PhysicalDevice::from_index(instance, physical_device_index).unwrap();
         let capabilities = surface.capabilities(physical_device)
-            .expect("Failed to get surfaqce capabilities");
+            .map_err(RenderError::SurfaceCapabilities)?;
 
         let surface_format = Self::choose_swap_surface_format(&capabilities.supported_formats);
         let present_mode = Self::choose_swap_present_mode(capabilities.present_modes);
-        let extent = Self::choose_swap_extent(&capabilities);
+        let extent = Self::choose_swap_extent(&capabilities, surface.window());
 
         let mut image_count = capabilities.min_image_count + 1;
         if capabilities.max_image_count.is_some() && image_count > capabilities.max_image_count.unwrap() {
@@ -282,7 +444,7 @@ impl RenderServer {
             graphics_queue.into()
         };
 
-        let (swap_chain, images) = Swapchain::new(
+        Swapchain::new(
             device.clone(),
             surface.clone(),
             image_count,
@@ -297,164 +459,961 @@ impl RenderServer {
             FullscreenExclusive::Default,
             true,
             surface_format.1
-        ).expect("Failed to create swap chain");
-
-        (swap_chain, images)
+        ).map_err(RenderError::SwapchainCreation)
     }
 
-    fn create_graphics_pipeline(device: &Arc<Device>, swap_chain_extent: [u32; 2], render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>)
-    -> Arc<GraphicsPipeline<BufferlessDefinition, Box<dyn PipelineLayoutAbstract + Send + Sync + 'static>, Arc<dyn RenderPassAbstract + Send + Sync + 'static>>> {
-        mod vertex_shader {
-            vulkano_shaders::shader! {
-                ty: "vertex",
-                path: "src/engine/render/shaders/default_shader.vert"
-            }
-        }
-
-        mod fragment_shader {
-            vulkano_shaders::shader! {
-                ty: "fragment",
-                path: "src/engine/render/shaders/default_shader.frag"
-            }
-        }
+    fn choose_depth_format(physical_device: PhysicalDevice) -> Result<Format, RenderError> {
+        DEPTH_FORMATS.iter()
+            .find(|format| format.properties(physical_device).optimal_tiling_features.depth_stencil_attachment)
+            .cloned()
+            .ok_or(RenderError::NoDepthFormat)
+    }
 
-        let vert_shader_module = vertex_shader::Shader::load(device.clone())
-            .expect("failed to create vertex shader module!");
+    /// The pipeline chunks are drawn with, `vertex_entry` being where the vertex shader gets the models from.
+    /// Translucent pipelines blend by the alpha of the faces and test depth without writing it.
+    /// The viewport is dynamic, so the pipeline does not need to be rebuilt when the window is resized.
+    fn create_mesh_pipeline<Vs>(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, reverse_z: bool, translucent: bool, vertex_entry: Vs)
+    -> Result<Arc<MeshPipeline>, RenderError>
+        where Vs: GraphicsEntryPointAbstract<SpecializationConstants = ()>, Vs::PipelineLayout: Clone + Send + Sync + 'static {
         let frag_shader_module = fragment_shader::Shader::load(device.clone())
-            .expect("failed to create fragment shader module");
-
-        let dimensions = [swap_chain_extent[0] as f32, swap_chain_extent[1] as f32];
-        let viewport = Viewport {
-            origin: [0.0, 0.0],
-            dimensions,
-            depth_range: 0.0 .. 1.0,
-        };
+            .map_err(RenderError::ShaderCreation)?;
 
-        return Arc::new(GraphicsPipeline::start()
-            .vertex_input(BufferlessDefinition {})
-            .vertex_shader(vert_shader_module.main_entry_point(), ())
+        let builder = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<MeshVertex>()
+            .vertex_shader(vertex_entry, ())
             .triangle_list()
             .primitive_restart(false)
-            .viewports(vec![viewport])
+            .viewports_dynamic_scissors_irrelevant(1)
             .fragment_shader(frag_shader_module.main_entry_point(), ())
             .depth_clamp(false)
             .polygon_mode_fill()
             .line_width(1.0)
             .cull_mode_back()
-            .front_face_clockwise()
-            .blend_pass_through()
+            // Meshes are wound counter clockwise, which ends up clockwise once the y axis is flipped to Vulkan's convention
+            .front_face_clockwise();
+        let builder = if translucent { builder.blend_alpha_blending() } else { builder.blend_pass_through() };
+        let pipeline = builder
+            .depth_stencil(DepthStencil {
+                depth_compare: if reverse_z { Compare::Greater } else { Compare::Less },
+                depth_write: !translucent,
+                .. DepthStencil::simple_depth_test()
+            })
             .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
             .build(device.clone())
-            .unwrap());
+            .map_err(RenderError::PipelineCreation)?;
+        return Ok(Arc::new(pipeline));
     }
 
-    fn create_render_pass(device: &Arc<Device>, color_format: vulkano::format::Format) -> Arc<dyn RenderPassAbstract + Send + Sync> {
-        Arc::new(vulkano::single_pass_renderpass!(device.clone(),
+    /// Draws meshes with the model matrix pushed for each draw.
+    fn create_graphics_pipeline(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, reverse_z: bool, translucent: bool)
+    -> Result<Arc<MeshPipeline>, RenderError> {
+        let vert_shader_module = vertex_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        Self::create_mesh_pipeline(device, render_pass, reverse_z, translucent, vert_shader_module.main_entry_point())
+    }
+
+    /// Draws meshes with the models coming from the per chunk draw info of indirect draws.
+    fn create_indirect_pipeline(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, reverse_z: bool)
+    -> Result<Arc<MeshPipeline>, RenderError> {
+        let vert_shader_module = indirect_vertex_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        Self::create_mesh_pipeline(device, render_pass, reverse_z, false, vert_shader_module.main_entry_point())
+    }
+
+    fn create_render_pass(device: &Arc<Device>, color_format: vulkano::format::Format, depth_format: Format) -> Result<Arc<dyn RenderPassAbstract + Send + Sync>, RenderError> {
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
             attachments: {
                 color: {
                     load: Clear,
                     store: Store,
                     format: color_format,
                     samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: DontCare,
+                    format: depth_format,
+                    samples: 1,
                 }
             },
             pass: {
                 color: [color],
-                depth_stencil: {}
+                depth_stencil: {depth}
             }
-        ).unwrap())
+        ).map_err(RenderError::RenderPassCreation)?;
+        Ok(Arc::new(render_pass))
     }
 
+    /// Creates the framebuffers the post chain ends in, `render_pass` being its output pass.
     fn create_framebuffers(swap_chain_images: &[Arc<SwapchainImage<Window>>], render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>)
-    -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
+    -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, RenderError> {
         swap_chain_images.iter().map(|image| {
             let fba : Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(render_pass.clone())
-                .add(image.clone()).unwrap()
-                .build().unwrap());
-            fba
+                .add(image.clone()).map_err(RenderError::FramebufferCreation)?
+                .build().map_err(RenderError::FramebufferCreation)?);
+            Ok(fba)
             }
-        ).collect::<Vec<_>>()
+        ).collect()
+    }
+
+    pub(crate) fn viewport_dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+        DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0 .. 1.0,
+            }]),
+            .. DynamicState::none()
+        }
+    }
+
+    /// Rebuilds the swap chain and everything sized after it for the current window size.
+    /// Returns false if the swap chain could not be recreated yet, e.g. while the window is being resized.
+    fn recreate_swap_chain(&mut self) -> Result<bool, RenderError> {
+        let window = self.window.as_mut().expect("Headless render servers have no swap chain");
+        let (width, height) = window.surface.window().get_framebuffer_size();
+        let dimensions = [width.max(0) as u32, height.max(0) as u32];
+        let (swap_chain, swap_chain_images) = match window.swap_chain.recreate_with_dimensions(dimensions) {
+            Ok(result) => result,
+            Err(SwapchainCreationError::UnsupportedDimensions) => return Ok(false),
+            Err(error) => return Err(RenderError::SwapchainCreation(error)),
+        };
+        let swap_chain_framebuffers = Self::create_framebuffers(&swap_chain_images, self.post.output_pass())?;
+        window.swap_chain = swap_chain;
+        window.swap_chain_images = swap_chain_images;
+        window.swap_chain_framebuffers = swap_chain_framebuffers;
+        window.recreate_swap_chain = false;
+        self.dynamic_state = Self::viewport_dynamic_state(window.swap_chain.dimensions());
+        let camera = self.camera;
+        self.set_camera(&camera);
+        return Ok(true);
+    }
+
+    /// Size of the images frames are rendered to: the swap chain, or the offscreen target when headless.
+    fn target_dimensions(&self) -> [u32; 2] {
+        match &self.window {
+            Some(window) => window.swap_chain.dimensions(),
+            None => self.offscreen.as_ref().expect("Headless render server without an offscreen target").dimensions,
+        }
+    }
+
+    fn camera_uniforms(&self) -> vertex_shader::ty::CameraUniforms {
+        let view = self.camera.view_matrix();
+        let projection = self.camera.projection_matrix(self.settings.reverse_z);
+        vertex_shader::ty::CameraUniforms {
+            view: view.into(),
+            projection: projection.into(),
+            view_projection: (projection*view).into(),
+            position: self.camera.position.to_homogeneous().into(),
+        }
+    }
+
+    fn shadow_uniforms(&self, shadows: &ShadowFrame) -> fragment_shader::ty::ShadowUniforms {
+        let count = shadows.cascades.len();
+        let mut uniforms = fragment_shader::ty::ShadowUniforms {
+            cascades: [Matrix4::from_scale(1.0).into(); MAX_CASCADES],
+            splits: [0.0; MAX_CASCADES],
+            texel_sizes: [0.0; MAX_CASCADES],
+        };
+        for (index, cascade) in shadows.cascades.iter().enumerate() {
+            uniforms.cascades[index] = cascade.atlas_view_projection(index, count).into();
+            uniforms.splits[index] = cascade.split;
+            uniforms.texel_sizes[index] = cascade.texel_size;
+        }
+        uniforms
+    }
+
+    fn lighting_uniforms(&self) -> fragment_shader::ty::LightingUniforms {
+        let color = |color: Vector3<f32>| [color.x, color.y, color.z, 1.0];
+        fragment_shader::ty::LightingUniforms {
+            light_direction: self.sky.light_direction().extend(0.0).into(),
+            light_color: color(self.sky.light_color),
+            ambient_color: color(self.sky.ambient_color),
+        }
+    }
+
+    fn fog(&self) -> Fog {
+        Fog::new(&self.settings.fog, &self.sky, self.camera_fluid)
+    }
+
+    fn fog_uniforms(&self, fog: &Fog) -> fragment_shader::ty::FogUniforms {
+        let color = |color: Vector3<f32>| [color.x, color.y, color.z, 1.0];
+        fragment_shader::ty::FogUniforms {
+            color: color(fog.color),
+            tint: color(fog.tint),
+            parameters: [fog.start, fog.density, 0.0, 0.0],
+        }
+    }
+
+    /// Fits the shadow cascades to the camera. Must be called before the draw list is culled.
+    fn prepare_shadows(&self) -> ShadowFrame {
+        self.shadow_maps.prepare(&self.camera, self.sky.light_direction(), &self.meshes, &self.draw_list)
     }
 
-    fn create_command_buffers(&mut self) {
+    /// Records the render pass drawing the draw list, through `batch` when drawing indirectly, after the uploads
+    /// and GPU meshing it depends on and the shadow pass, then the post chain ending in `framebuffer`.
+    /// The post chain must already be sized for the frame. The returned builder can be extended with commands
+    /// that use the rendered image.
+    fn record_scene(&self, uploads: Uploads, meshing: Option<MeshingJobs>, batch: Option<&IndirectBatch>, shadows: &ShadowFrame,
+                    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>, dynamic_state: &DynamicState) -> Result<AutoCommandBufferBuilder, RenderError> {
         let queue_family = self.graphics_queue.family();
-        self.command_buffers = self.swap_chain_framebuffers.iter().map(|framebuffer| {
-            let vertices = BufferlessVertices {vertices: 3, instances: 1};
-            Arc::new(AutoCommandBufferBuilder::primary_simultaneous_use(self.device.clone(), queue_family)
-                .unwrap()
-                .begin_render_pass(framebuffer.clone(), false, vec![[0.0, 0.0, 0.0, 1.0].into()])
-                .unwrap()
-                .draw(self.graphics_pipeline.clone(), &DynamicState::none(), vertices, (), ())
-                .unwrap()
-                .end_render_pass()
-                .unwrap()
-                .build()
-                .unwrap())
+        let camera_buffer = self.camera_uniforms.next(self.camera_uniforms())
+            .map_err(RenderError::Allocation)?;
+        let layout = self.graphics_pipeline.descriptor_set_layout(0).unwrap();
+        let camera_set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(camera_buffer.clone())
+            .map_err(RenderError::DescriptorSet)?
+            .build()
+            .map_err(RenderError::DescriptorSetBuild)?);
+        let shadow_buffer = self.shadow_uniforms.next(self.shadow_uniforms(shadows))
+            .map_err(RenderError::Allocation)?;
+        let lighting_buffer = self.lighting_uniforms.next(self.lighting_uniforms())
+            .map_err(RenderError::Allocation)?;
+        let fog = self.fog();
+        let fog_buffer = self.fog_uniforms.next(self.fog_uniforms(&fog))
+            .map_err(RenderError::Allocation)?;
+        let lighting_layout = self.graphics_pipeline.descriptor_set_layout(2).unwrap();
+        let lighting_set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(PersistentDescriptorSet::start(lighting_layout.clone())
+            .add_buffer(shadow_buffer)
+            .map_err(RenderError::DescriptorSet)?
+            .add_sampled_image(self.shadow_maps.map.clone(), self.shadow_maps.sampler.clone())
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(lighting_buffer)
+            .map_err(RenderError::DescriptorSet)?
+            .add_buffer(fog_buffer)
+            .map_err(RenderError::DescriptorSet)?
+            .build()
+            .map_err(RenderError::DescriptorSetBuild)?);
+        let indirect = match (batch, self.indirect.as_ref()) {
+            (Some(batch), Some(indirect)) => Some((batch, indirect)),
+            _ => None,
+        };
+
+        let builder = AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), queue_family)
+            .map_err(RenderError::CommandBufferCreation)?;
+        let mut builder = uploads.record(builder);
+        if let Some(meshing) = meshing {
+            builder = meshing.record(builder);
+        }
+        if let Some((batch, indirect)) = indirect {
+            builder = batch.record_culling(builder, indirect);
+        }
+        builder = self.shadow_maps.record(builder, shadows, &self.meshes, self.gpu_mesher.as_ref(), &self.gpu_draw_list);
+        // Under fluids the fog hides the sky
+        let clear_color = if fog.underwater { [fog.color.x, fog.color.y, fog.color.z, 1.0] } else { self.sky.clear_color() };
+        builder = builder.begin_render_pass(self.post.scene_framebuffer(), false, vec![clear_color.into(), self.depth_clear_value().into()])
+            .unwrap();
+        if !fog.underwater {
+            builder = self.sky_pass.record(builder, dynamic_state, &self.sky, &self.camera, self.settings.reverse_z);
+        }
+
+        match indirect {
+            Some((batch, indirect)) => builder = batch.record_draws(builder, indirect, &self.meshes, dynamic_state, camera_buffer, &self.texture_set, &lighting_set),
+            None => for (handle, model, _) in self.draw_list.iter() {
+                let (vertex_buffer, indices) = match self.meshes.get(*handle) {
+                    Some(ChunkDraw { vertex_buffer, indices: Some(indices), .. }) => (vertex_buffer, indices),
+                    _ => continue,
+                };
+                let push_constants = vertex_shader::ty::PushConstants {
+                    model: (*model).into(),
+                };
+                builder = builder.draw_indexed(self.graphics_pipeline.clone(), dynamic_state,
+                                               vertex_buffer, indices, (camera_set.clone(), self.texture_set.clone(), lighting_set.clone()), push_constants)
+                    .unwrap();
+            },
+        }
+        if let Some(gpu_mesher) = self.gpu_mesher.as_ref() {
+            for (handle, model) in self.gpu_draw_list.iter() {
+                let mesh = match gpu_mesher.get(*handle) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                let push_constants = vertex_shader::ty::PushConstants {
+                    model: (*model).into(),
+                };
+                builder = builder.draw_indexed_indirect(self.graphics_pipeline.clone(), dynamic_state, mesh.vertex_buffer.clone(),
+                                                        gpu_mesher.quad_indices(), mesh.command.clone(), (camera_set.clone(), self.texture_set.clone(), lighting_set.clone()), push_constants)
+                    .unwrap();
+            }
+        }
+        // Translucent faces come last, farthest meshes first, so each is blended over what is behind it
+        for (vertex_buffer, indices, model) in self.translucent_draws() {
+            let push_constants = vertex_shader::ty::PushConstants {
+                model: model.into(),
+            };
+            builder = builder.draw_indexed(self.translucent_pipeline.clone(), dynamic_state,
+                                           vertex_buffer, indices, (camera_set.clone(), self.texture_set.clone(), lighting_set.clone()), push_constants)
+                .unwrap();
+        }
+        builder = builder.end_render_pass()
+            .unwrap();
+        Ok(self.post.record(builder, &self.settings.post, fog.distortion, self.created.elapsed().as_secs_f32(), framebuffer))
+    }
+
+    /// The translucent faces of the draw list, sorted from the farthest mesh to the closest. Faces within
+    /// a mesh are not sorted.
+    fn translucent_draws(&self) -> Vec<(Arc<DeviceLocalBuffer<[MeshVertex]>>, BufferSlice<[u32], Arc<DeviceLocalBuffer<[u32]>>>, Matrix4<f32>)> {
+        let mut draws: Vec<_> = self.draw_list.iter().filter_map(|(handle, model, _)| {
+            let mesh = self.meshes.get(*handle)?;
+            let indices = mesh.translucent_indices?;
+            let bounds = self.meshes.bounds(*handle)?.transformed(model);
+            let distance = bounds.min.midpoint(bounds.max).distance2(self.camera.position);
+            Some((distance, (mesh.vertex_buffer, indices, *model)))
         }).collect();
+        draws.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        draws.into_iter().map(|(_, draw)| draw).collect()
+    }
+
+    /// Removes from the draw list the chunks hidden behind other chunks.
+    fn cull_occluded_chunks(&mut self) {
+        let mut connectivity: HashMap<_, _> = self.meshless_chunks.iter().cloned().collect();
+        for (handle, _, position) in self.draw_list.iter() {
+            if let (Some(position), Some(chunk)) = (position, self.meshes.connectivity(*handle)) {
+                connectivity.insert(*position, chunk);
+            }
+        }
+        if connectivity.is_empty() {
+            return;
+        }
+        let frustum = if self.settings.frustum_culling {
+            Some(Frustum::from_matrix(&self.camera.view_projection_matrix(self.settings.reverse_z)))
+        } else {
+            None
+        };
+        let camera_chunk = occlusion::chunk_containing(self.camera.position);
+        let visible = occlusion::visible_chunks(camera_chunk, &connectivity, |position| {
+            frustum.map_or(true, |frustum| frustum.intersects_aabb(&occlusion::chunk_bounds(position)))
+        });
+        self.draw_list.retain(|(_, _, position)| position.map_or(true, |position| visible.contains(&position)));
+    }
+
+    /// Removes the hidden meshes from the draw list and updates the culling stats. Only the first call
+    /// after the draw list is cleared culls, so a frame rendered twice reports the stats of the whole list.
+    fn cull_draw_list(&mut self) {
+        if self.draw_list_culled {
+            return;
+        }
+        self.draw_list_culled = true;
+        self.frame_stats.total_chunks = self.draw_list.len();
+        if self.settings.occlusion_culling {
+            self.cull_occluded_chunks();
+        }
+        let culls_on_gpu = self.settings.frustum_culling && self.indirect.as_ref().map_or(false, |indirect| indirect.culls_on_gpu());
+        if self.settings.frustum_culling && !culls_on_gpu {
+            let frustum = Frustum::from_matrix(&self.camera.view_projection_matrix(self.settings.reverse_z));
+            let meshes = &self.meshes;
+            self.draw_list.retain(|(handle, model, _)| match meshes.bounds(*handle) {
+                Some(bounds) => frustum.intersects_aabb(&bounds.transformed(model)),
+                None => false,
+            });
+        }
+        self.frame_stats.visible_chunks = if culls_on_gpu { None } else { Some(self.draw_list.len()) };
+    }
+
+    /// Builds the indirect draws of the draw list, if drawing indirectly. Culling buffers are per `slot`,
+    /// which must not be in use by a frame in flight.
+    fn prepare_indirect_batch(&mut self, slot: usize) -> Option<IndirectBatch> {
+        let frustum = if self.settings.frustum_culling {
+            Some(Frustum::from_matrix(&self.camera.view_projection_matrix(self.settings.reverse_z)))
+        } else {
+            None
+        };
+        let (meshes, draw_list) = (&self.meshes, &self.draw_list);
+        let batch = self.indirect.as_mut().map(|indirect| indirect.prepare(meshes, draw_list, frustum.as_ref(), slot));
+        self.frame_stats.draw_calls = batch.as_ref().map_or(self.draw_list.len(), |batch| batch.draw_call_count());
+        batch
+    }
+
+    fn create_command_buffer(&mut self, image_index: usize) -> Result<AutoCommandBuffer, RenderError> {
+        let shadows = self.prepare_shadows();
+        self.cull_draw_list();
+        let batch = self.prepare_indirect_batch(self.frame_index % self.frame_fences.len());
+        let uploads = self.meshes.take_uploads();
+        let meshing = self.gpu_mesher.as_mut().map(|mesher| mesher.take_jobs());
+        let window = self.window.as_ref().expect("Headless render servers have no swap chain");
+        self.post.resize(window.swap_chain.dimensions())?;
+        self.record_scene(uploads, meshing, batch.as_ref(), &shadows, window.swap_chain_framebuffers[image_index].clone(), &self.dynamic_state)?
+            .build()
+            .map_err(RenderError::CommandBufferBuild)
+    }
+
+    fn depth_clear_value(&self) -> f32 {
+        if self.settings.reverse_z { 0.0 } else { 1.0 }
     }
 
-    fn draw_frame(&mut self) {
-        let (image_index,acquisition_suboptimal, acquire_future) = acquire_next_image(self.swap_chain.clone(), None).unwrap();
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
+    }
+
+    fn draw_frame(&mut self) -> Result<(), RenderError> {
+        let frame_start = Instant::now();
+        self.frame_stats.begin_frame(frame_start);
+        let gpu_wait_time = self.wait_for_frame_slot()?;
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            self.save_screenshot();
+        }
+        self.submit_frame()?;
+        self.frame_stats.end_frame(frame_start.elapsed() - gpu_wait_time, gpu_wait_time);
+        self.check_validation_errors();
+        Ok(())
+    }
+
+    /// Validation errors reported since the last call.
+    pub fn take_validation_errors(&self) -> Vec<String> {
+        match self.validation_errors.lock() {
+            Ok(mut errors) => std::mem::take(&mut *errors),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Changes which debug messages are logged. Validation layers can not be enabled or disabled
+    /// once the render server is created, so `enabled` is ignored.
+    pub fn set_validation_settings(&mut self, validation: ValidationSettings) {
+        self.settings.validation = ValidationSettings { enabled: self.settings.validation.enabled, .. validation };
+        self.debug_callback = None;
+        self.debug_callback = validation::create_debug_callback(&self.instance, &self.settings.validation, &self.validation_errors);
+    }
+
+    fn check_validation_errors(&self) {
+        if !self.settings.validation.panic_on_error {
+            return;
+        }
+        let errors = self.take_validation_errors();
+        if !errors.is_empty() {
+            panic!("Vulkan validation errors:\n{}", errors.join("\n"));
+        }
+    }
+
+    fn submit_frame(&mut self) -> Result<(), RenderError> {
+        if self.window.as_ref().unwrap().recreate_swap_chain && !self.recreate_swap_chain()? {
+            self.clear_draw_list();
+            return Ok(());
+        }
+
+        let swap_chain = self.window.as_ref().unwrap().swap_chain.clone();
+        let (image_index, acquisition_suboptimal, acquire_future) = match acquire_next_image(swap_chain.clone(), None) {
+            Ok(result) => result,
+            Err(AcquireError::OutOfDate) => {
+                self.window.as_mut().unwrap().recreate_swap_chain = true;
+                self.clear_draw_list();
+                return Ok(());
+            }
+            Err(error) => return Err(RenderError::ImageAcquisition(error)),
+        };
+        if acquisition_suboptimal {
+            self.window.as_mut().unwrap().recreate_swap_chain = true;
+        }
+
+        let command_buffer = self.create_command_buffer(image_index);
+        self.clear_draw_list();
+        let command_buffer = command_buffer?;
+
+        let mut previous_frame_end = self.previous_frame_end.take()
+            .unwrap_or_else(|| Box::new(vulkano::sync::now(self.device.clone())));
+        previous_frame_end.cleanup_finished();
+        let frame: Box<dyn GpuFuture> = Box::new(previous_frame_end
+            .join(acquire_future)
+            .then_execute(self.graphics_queue.clone(), command_buffer)
+            .map_err(RenderError::Execution)?
+            .then_swapchain_present(self.present_queue.clone(), swap_chain, image_index));
+
+        let slot = self.frame_index % self.frame_fences.len();
+        match frame.then_signal_fence_and_flush() {
+            Ok(fence) => {
+                let fence = Arc::new(fence);
+                self.frame_fences[slot] = Some(fence.clone());
+                self.previous_frame_end = Some(Box::new(fence));
+            }
+            Err(FlushError::OutOfDate) => {
+                self.window.as_mut().unwrap().recreate_swap_chain = true;
+                self.previous_frame_end = Some(Box::new(vulkano::sync::now(self.device.clone())));
+            }
+            Err(error) => return Err(RenderError::Flush(error)),
+        }
+        self.frame_index += 1;
+        Ok(())
+    }
+
+    /// Renders the draw list into the offscreen target and copies the result back, blocking until it is done.
+    /// The draw list is culled but not cleared, so the frame drawn next reuses its culling.
+    fn render_offscreen(&mut self) -> Result<CapturedImage, RenderError> {
+        let dimensions = self.target_dimensions();
+        if self.offscreen.as_ref().map(|target| target.dimensions != dimensions).unwrap_or(true) {
+            let target = OffscreenTarget::new(&self.device, self.post.output_pass(), dimensions, self.color_format)?;
+            self.offscreen = Some(target);
+        }
+        self.post.resize(dimensions)?;
+        let shadows = self.prepare_shadows();
+        self.cull_draw_list();
+        // Captures use the slot past those of the frames in flight, which may still be running
+        let batch = self.prepare_indirect_batch(self.frame_fences.len());
+        let uploads = self.meshes.take_uploads();
+        let meshing = self.gpu_mesher.as_mut().map(|mesher| mesher.take_jobs());
+        let target = self.offscreen.as_ref().unwrap();
+        let command_buffer = self.record_scene(uploads, meshing, batch.as_ref(), &shadows, target.framebuffer.clone(), &Self::viewport_dynamic_state(dimensions))?
+            .copy_image_to_buffer(target.color.clone(), target.readback.clone())
+            .unwrap()
+            .build()
+            .map_err(RenderError::CommandBufferBuild)?;
+
+        let previous_frame_end = self.previous_frame_end.take()
+            .unwrap_or_else(|| Box::new(vulkano::sync::now(self.device.clone())));
+        let result = previous_frame_end
+            .then_execute(self.graphics_queue.clone(), command_buffer)
+            .map_err(RenderError::Execution)
+            .and_then(|future| future.then_signal_fence_and_flush().map_err(RenderError::Flush))
+            .and_then(|fence| fence.wait(None).map_err(RenderError::Flush));
+        self.previous_frame_end = Some(Box::new(vulkano::sync::now(self.device.clone())));
+        // Everything submitted so far is done
+        self.meshes.release_retired(self.frame_index as u64);
+        result?;
+
+        let pixels = target.readback.read().expect("Failed to read back captured image").to_vec();
+        Ok(CapturedImage::from_raw(dimensions, target.format, pixels))
+    }
+
+    /// Renders the meshes drawn since the last frame into an image instead of the window, like a frame would.
+    /// This is how headless render servers render.
+    pub fn capture(&mut self) -> Result<CapturedImage, RenderError> {
+        let image = self.render_offscreen();
+        self.clear_draw_list();
+        self.check_validation_errors();
+        image
+    }
+
+    /// Saves the frame about to be drawn to the screenshots directory.
+    fn save_screenshot(&mut self) {
+        if !OffscreenTarget::is_supported(self.color_format) {
+            log::warn!("Screenshots are not supported with swap chain format {:?}", self.color_format);
+            return;
+        }
+        let image = match self.render_offscreen() {
+            Ok(image) => image,
+            Err(error) => {
+                log::error!("Failed to render screenshot: {}", error);
+                return;
+            }
+        };
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        let path = std::path::Path::new(SCREENSHOT_DIRECTORY).join(format!("screenshot-{}.png", timestamp));
+        let result = std::fs::create_dir_all(SCREENSHOT_DIRECTORY)
+            .map_err(CaptureError::from)
+            .and_then(|_| image.save_png(&path));
+        match result {
+            Ok(()) => log::info!("Saved screenshot to {}", path.display()),
+            Err(error) => log::error!("Failed to save screenshot: {}", error),
+        }
+    }
+
+    /// Blocks until the frame that last used the current frame slot is done on the GPU,
+    /// so that no more than `frames_in_flight` frames are queued. Returns how long it waited.
+    fn wait_for_frame_slot(&mut self) -> Result<std::time::Duration, RenderError> {
+        let start = Instant::now();
+        let slot = self.frame_index % self.frame_fences.len();
+        if let Some(fence) = self.frame_fences[slot].take() {
+            fence.wait(None).map_err(RenderError::Flush)?;
+            // Frames complete in order, so every frame up to the one that last used this slot is done
+            let completed_frame = (self.frame_index - self.frame_fences.len()) as u64;
+            self.meshes.release_retired(completed_frame);
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Waits for every frame in flight, even after one of them failed. Returns the first failure.
+    fn wait_idle(&mut self) -> Result<(), RenderError> {
+        let mut result = Ok(());
+        for fence in self.frame_fences.iter_mut() {
+            if let Some(fence) = fence.take() {
+                if let Err(error) = fence.wait(None) {
+                    result = result.and(Err(RenderError::Flush(error)));
+                }
+            }
+        }
+        self.previous_frame_end = None;
+        result
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+
+    /// Flags the swap chain for recreation on framebuffer resizes, and takes a screenshot when F12 is pressed.
+    fn handle_window_events(&mut self) {
+        let window = self.window.as_mut().unwrap();
+        for (_, event) in glfw::flush_messages(&window.events) {
+            match event {
+                glfw::WindowEvent::FramebufferSize(_, _) => window.recreate_swap_chain = true,
+                glfw::WindowEvent::Key(glfw::Key::F12, _, glfw::Action::Press, _) => self.screenshot_requested = true,
+                _ => {}
+            }
+        }
+    }
+
+    fn is_minimized(&self) -> bool {
+        let (width, height) = self.window.as_ref().unwrap().surface.window().get_framebuffer_size();
+        width == 0 || height == 0
+    }
+
+    /// Uploads a mesh to the GPU. It can be drawn as soon as this returns, the upload happens at the start of the next frame.
+    /// Empty meshes get a handle that draws nothing.
+    pub fn upload_mesh(&mut self, mesh: &ChunkMesh) -> Result<MeshHandle, RenderError> {
+        self.meshes.upload(mesh).map_err(RenderError::Allocation)
+    }
+
+    /// Replaces a mesh, e.g. after its chunk was remeshed. Returns false if there is no such mesh.
+    /// The previous mesh stays in place if the new one can not be allocated.
+    pub fn update_mesh(&mut self, handle: MeshHandle, mesh: &ChunkMesh) -> Result<bool, RenderError> {
+        self.meshes.update(handle, mesh, self.frame_index as u64).map_err(RenderError::Allocation)
+    }
+
+    pub fn remove_mesh(&mut self, handle: MeshHandle) -> bool {
+        self.meshes.remove(handle, self.frame_index as u64)
+    }
+
+    pub fn mesh_memory_stats(&self) -> ChunkBufferStats {
+        self.meshes.stats()
+    }
+
+    /// Sets the camera used from the next frame on. Its aspect ratio is overridden to match the window,
+    /// or the offscreen image when headless.
+    pub fn set_camera(&mut self, camera: &Camera) {
+        let dimensions = self.target_dimensions();
+        self.camera = Camera {
+            aspect: dimensions[0] as f32/dimensions[1] as f32,
+            .. *camera
+        };
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// Draws a mesh with the given model transform during the next frame.
+    pub fn draw_mesh(&mut self, handle: MeshHandle, model: Matrix4<f32>) {
+        self.draw_list.push((handle, model, None));
+    }
+
+    /// Draws the mesh of the chunk at `position` during the next frame, placed at the chunk origin.
+    /// Unlike `draw_mesh`, it is skipped when occlusion culling finds the chunk hidden.
+    pub fn draw_chunk(&mut self, handle: MeshHandle, position: ChunkPosition) {
+        let origin = position.origin();
+        let model = Matrix4::from_translation(Vector3::new(origin.x as f32, origin.y as f32, origin.z as f32));
+        self.draw_list.push((handle, model, Some(position)));
+    }
+
+    /// Tells occlusion culling about a chunk with nothing to draw during the next frame, such as one buried
+    /// in solid rock. Chunks neither drawn nor described are assumed to hide nothing.
+    pub fn describe_meshless_chunk(&mut self, position: ChunkPosition, connectivity: ChunkConnectivity) {
+        self.meshless_chunks.push((position, connectivity));
+    }
+
+    fn clear_draw_list(&mut self) {
+        self.draw_list.clear();
+        self.draw_list_culled = false;
+        self.meshless_chunks.clear();
+        self.gpu_draw_list.clear();
+    }
+
+    /// Moves the sun, moon and stars to `time`, which also changes the sky and the light the chunks are lit with.
+    pub fn set_time_of_day(&mut self, time: &TimeOfDay) {
+        self.sky = Sky::at(time);
+    }
+
+    pub fn sky(&self) -> &Sky {
+        &self.sky
+    }
+
+    /// The fog settings, which take effect from the next frame on.
+    pub fn fog_mut(&mut self) -> &mut FogSettings {
+        &mut self.settings.fog
+    }
+
+    /// Exposure and tonemapping, which take effect from the next frame on.
+    pub fn post_mut(&mut self) -> &mut PostSettings {
+        &mut self.settings.post
+    }
+
+    /// What post effects are created with.
+    pub fn post_context(&self) -> &PostContext {
+        self.post.context()
+    }
+
+    /// Appends `effect` to the post effects applied to the scene before it is tonemapped, after bloom.
+    pub fn add_post_effect(&mut self, effect: Box<dyn PostEffect>) {
+        self.post.push(effect);
+    }
+
+    /// Checks whether the camera is inside a fluid, which switches the fog to its underwater look.
+    /// Call it after moving the camera or changing the blocks around it.
+    pub fn update_camera_medium(&mut self, world: &VoxelWorld, registry: &BlockRegistry) {
+        self.camera_fluid = fog::fluid_color_at(world, registry, self.camera.position);
+    }
+
+    /// Loads the textures of the blocks of `registry` from `texture_directory`. Until then, without a texture
+    /// directory, and for faces without a texture, blocks are drawn in their plain color.
+    pub fn set_block_textures(&mut self, registry: &BlockRegistry) -> Result<(), RenderError> {
+        let anisotropy = Self::texture_anisotropy(&self.instance, self.physical_device_index, &self.device, &self.settings);
+        let textures = BlockTextureArray::load(&self.graphics_queue, self.settings.texture_directory.as_deref(), registry.texture_names(), anisotropy)?;
+        self.texture_set = Self::create_texture_set(&self.graphics_pipeline, &textures);
+        return Ok(());
+    }
+
+    fn texture_anisotropy(instance: &Arc<vulkano::instance::Instance>, physical_device_index: usize, device: &Arc<Device>, settings: &RenderSettings) -> f32 {
+        if !device.enabled_features().sampler_anisotropy {
+            return 1.0;
+        }
+        let limit = PhysicalDevice::from_index(instance, physical_device_index).unwrap().limits().max_sampler_anisotropy();
+        return settings.max_anisotropy.max(1.0).min(limit);
+    }
+
+    fn create_texture_set(graphics_pipeline: &Arc<MeshPipeline>, textures: &BlockTextureArray) -> Arc<dyn DescriptorSet + Send + Sync> {
+        let layout = graphics_pipeline.descriptor_set_layout(1).unwrap();
+        Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_sampled_image(textures.image.clone(), textures.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap())
+    }
+
+    /// Meshes a chunk with a compute shader instead of on the CPU, during the next frame. The mesh has the same faces
+    /// as the one of `culled_mesher`, up to `gpu_mesh_max_faces` of them.
+    pub fn mesh_chunk_on_gpu(&mut self, world: &VoxelWorld, position: ChunkPosition, registry: &BlockRegistry) -> Result<GpuMeshHandle, RenderError> {
+        if self.gpu_mesher.is_none() {
+            self.gpu_mesher = Some(GpuMesher::new(&self.device, self.settings.gpu_mesh_max_faces)?);
+        }
+        self.gpu_mesher.as_mut().unwrap().mesh(world, position, registry)
+    }
+
+    /// Draws a mesh made by `mesh_chunk_on_gpu` with the given model transform during the next frame.
+    /// It is drawn as is, without culling.
+    pub fn draw_gpu_mesh(&mut self, handle: GpuMeshHandle, model: Matrix4<f32>) {
+        self.gpu_draw_list.push((handle, model));
+    }
 
-        let command_buffer = self.command_buffers[image_index].clone();
+    pub fn remove_gpu_mesh(&mut self, handle: GpuMeshHandle) -> bool {
+        self.gpu_mesher.as_mut().map_or(false, |mesher| mesher.remove(handle))
+    }
 
-        let future = acquire_future
+    /// Copies a mesh made by `mesh_chunk_on_gpu` back to the CPU, meshing it first if needed. Blocks until
+    /// the GPU is done, which makes it only fit for tests and debugging.
+    pub fn read_gpu_mesh(&mut self, handle: GpuMeshHandle) -> Option<ChunkMesh> {
+        let mesher = self.gpu_mesher.as_mut()?;
+        let mesh = mesher.get(handle)?.clone();
+        let jobs = mesher.take_jobs();
+        let readback_usage = BufferUsage { transfer_destination: true, .. BufferUsage::none() };
+        let vertices = CpuAccessibleBuffer::from_iter(self.device.clone(), readback_usage, false,
+                                                      (0..mesh.vertex_buffer.len()).map(|_| MeshVertex::default()))
+            .expect("Failed to allocate mesh readback");
+        let command = CpuAccessibleBuffer::from_iter(self.device.clone(), readback_usage, false,
+                                                     std::iter::once(DrawIndexedIndirectCommand { index_count: 0, instance_count: 0, first_index: 0, vertex_offset: 0, first_instance: 0 }))
+            .expect("Failed to allocate mesh readback");
+        let builder = AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), self.graphics_queue.family())
+            .unwrap();
+        let command_buffer = jobs.record(builder)
+            .copy_buffer(mesh.vertex_buffer, vertices.clone())
+            .unwrap()
+            .copy_buffer(mesh.command, command.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let previous_frame_end = self.previous_frame_end.take()
+            .unwrap_or_else(|| Box::new(vulkano::sync::now(self.device.clone())));
+        previous_frame_end
             .then_execute(self.graphics_queue.clone(), command_buffer)
             .unwrap()
-            .then_swapchain_present(self.present_queue.clone(), self.swap_chain.clone(), image_index)
             .then_signal_fence_and_flush()
+            .expect("Failed to flush mesh readback")
+            .wait(None)
             .unwrap();
+        self.previous_frame_end = Some(Box::new(vulkano::sync::now(self.device.clone())));
+        Some(gpu_mesher::read_mesh(&vertices, &command))
+    }
 
-        future.wait(None).unwrap();
+    pub fn new() -> Result<Self, RenderError> {
+        Self::with_settings(RenderSettings::default())
     }
 
-    pub fn new() -> Self {
+    pub fn with_settings(settings: RenderSettings) -> Result<Self, RenderError> {
         //Initializing GLFW
-        let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
+        let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).map_err(RenderError::GlfwInit)?;
         glfw.window_hint(glfw::WindowHint::ClientApi(glfw::ClientApiHint::NoApi));
         let (mut window, events) = glfw.create_window(800, 600, "Rusty Blocks", glfw::WindowMode::Windowed)
-            .expect("Unable to create window");
+            .ok_or(RenderError::WindowCreation)?;
         let mut window = Window(window);
         window.set_key_polling(true);
+        window.set_framebuffer_size_polling(true);
 
         //Initializing Vulkano
-        let instance = Self::create_instance(&glfw);
-        let surface = Self::create_surface(&instance, window);
-        let debug_callback = Self::create_debug_callback(&instance);
-        let physical_device_index = Self::pick_physical_device(&instance, &surface);
-        let (device, graphics_queue, present_queue) = Self::create_logical_device(&instance, physical_device_index);
-        let (swap_chain, swap_chain_images) = Self::create_swap_chain(&instance, &surface, physical_device_index, &device, &graphics_queue, &present_queue);
-        let render_pass = Self::create_render_pass(&device, swap_chain.format());
-        let graphics_pipeline = Self::create_graphics_pipeline(&device, swap_chain.dimensions(), &render_pass);
-        let swap_chain_framebuffers = Self::create_framebuffers(&swap_chain_images, &render_pass);
+        let required_extensions = Self::get_required_extensions(&glfw)?;
+        let instance = Self::create_instance(&required_extensions, &settings.validation)?;
+        let surface = Self::create_surface(&instance, window)?;
+        let validation_errors = ValidationErrors::default();
+        let debug_callback = validation::create_debug_callback(&instance, &settings.validation, &validation_errors);
+        let physical_device_index = Self::pick_physical_device(&instance, &surface, &settings.device)?;
+        let (device, graphics_queue, present_queue) = Self::create_logical_device(&instance, physical_device_index, &Self::device_extensions(true))?;
+        let (swap_chain, swap_chain_images) = Self::create_swap_chain(&instance, &surface, physical_device_index, &device, &graphics_queue, &present_queue)?;
+        let depth_format = Self::choose_depth_format(PhysicalDevice::from_index(&instance, physical_device_index).unwrap())?;
+        let render_pass = Self::create_render_pass(&device, HDR_FORMAT, depth_format)?;
+        let post = PostChain::new(&device, &render_pass, depth_format, swap_chain.format())?;
+        let swap_chain_framebuffers = Self::create_framebuffers(&swap_chain_images, post.output_pass())?;
 
-        let mut render_server= Self{
+        let color_format = swap_chain.format();
+        let dimensions = swap_chain.dimensions();
+        let window = WindowTarget {
             glfw,
             events,
+            surface,
+            swap_chain,
+            swap_chain_images,
+            swap_chain_framebuffers,
+            recreate_swap_chain: false,
+        };
+        Self::from_parts(settings, Some(window), None, instance, debug_callback, validation_errors, physical_device_index, device,
+                         graphics_queue, present_queue, color_format, render_pass, post, dimensions)
+    }
+
+    /// Creates a render server without any window, which renders `dimensions` sized images through `capture`.
+    /// It only needs a device able to draw, so it also runs on software implementations such as lavapipe.
+    pub fn headless(settings: RenderSettings, dimensions: [u32; 2]) -> Result<Self, RenderError> {
+        let instance = Self::create_instance(&Self::headless_extensions()?, &settings.validation)?;
+        let validation_errors = ValidationErrors::default();
+        let debug_callback = validation::create_debug_callback(&instance, &settings.validation, &validation_errors);
+        let physical_device_index = Self::pick_headless_device(&instance, &settings.device)?;
+        let (device, graphics_queue, present_queue) = Self::create_logical_device(&instance, physical_device_index, &Self::device_extensions(false))?;
+        let depth_format = Self::choose_depth_format(PhysicalDevice::from_index(&instance, physical_device_index).unwrap())?;
+        let color_format = HEADLESS_COLOR_FORMAT;
+        let render_pass = Self::create_render_pass(&device, HDR_FORMAT, depth_format)?;
+        let post = PostChain::new(&device, &render_pass, depth_format, color_format)?;
+        let offscreen = OffscreenTarget::new(&device, post.output_pass(), dimensions, color_format)?;
+        Self::from_parts(settings, None, Some(offscreen), instance, debug_callback, validation_errors, physical_device_index, device,
+                         graphics_queue, present_queue, color_format, render_pass, post, dimensions)
+    }
+
+    /// Name of the physical device the render server runs on.
+    pub fn device_name(&self) -> String {
+        PhysicalDevice::from_index(&self.instance, self.physical_device_index).unwrap().name()
+    }
+
+    fn from_parts(settings: RenderSettings, window: Option<WindowTarget>, offscreen: Option<OffscreenTarget>,
+                  instance: Arc<vulkano::instance::Instance>, debug_callback: Option<vulkano::instance::debug::DebugCallback>,
+                  validation_errors: ValidationErrors,
+                  physical_device_index: usize, device: Arc<Device>,
+                  graphics_queue: Arc<vulkano::device::Queue>, present_queue: Arc<vulkano::device::Queue>,
+                  color_format: Format, render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+                  mut post: PostChain, dimensions: [u32; 2]) -> Result<Self, RenderError> {
+        let graphics_pipeline = Self::create_graphics_pipeline(&device, &render_pass, settings.reverse_z, false)?;
+        let translucent_pipeline = Self::create_graphics_pipeline(&device, &render_pass, settings.reverse_z, true)?;
+        let dynamic_state = Self::viewport_dynamic_state(dimensions);
+
+        let camera_uniforms = CpuBufferPool::uniform_buffer(device.clone());
+        let frames_in_flight = settings.frames_in_flight.max(1);
+        camera_uniforms.reserve(frames_in_flight).map_err(RenderError::Allocation)?;
+
+        let anisotropy = Self::texture_anisotropy(&instance, physical_device_index, &device, &settings);
+        let block_textures = BlockTextureArray::load(&graphics_queue, None, &[], anisotropy)?;
+        let texture_set = Self::create_texture_set(&graphics_pipeline, &block_textures);
+        let max_image_size = PhysicalDevice::from_index(&instance, physical_device_index).unwrap().limits().max_image_dimension_2d();
+        let shadow_maps = ShadowMaps::new(&device, &settings.shadows, max_image_size)?;
+        let shadow_uniforms = CpuBufferPool::uniform_buffer(device.clone());
+        shadow_uniforms.reserve(frames_in_flight).map_err(RenderError::Allocation)?;
+        let sky_pass = SkyPass::new(&device, &render_pass, frames_in_flight)?;
+        let lighting_uniforms = CpuBufferPool::uniform_buffer(device.clone());
+        lighting_uniforms.reserve(frames_in_flight).map_err(RenderError::Allocation)?;
+        let fog_uniforms = CpuBufferPool::uniform_buffer(device.clone());
+        fog_uniforms.reserve(frames_in_flight).map_err(RenderError::Allocation)?;
+        if settings.post.bloom.enabled {
+            let bloom = Bloom::new(post.context(), &settings.post.bloom)?;
+            post.push(Box::new(bloom));
+        }
+
+        let meshes = ChunkBuffers::new(device.clone());
+        let indirect = if !settings.indirect_drawing {
+            None
+        } else if IndirectDraws::is_supported(&device) {
+            let pipeline = Self::create_indirect_pipeline(&device, &render_pass, settings.reverse_z)?;
+            Some(IndirectDraws::new(&device, pipeline, settings.gpu_culling, frames_in_flight + 1)?)
+        } else {
+            log::info!("Multi draw indirect is not supported, recording one draw per chunk");
+            None
+        };
+
+        let mut render_server= Self{
+            settings,
+            window,
             instance,
             debug_callback,
+            validation_errors,
             physical_device_index,
             device,
             graphics_queue,
             present_queue,
-            swap_chain,
-            surface,
-            swap_chain_images,
+            color_format,
             render_pass,
+            post,
             graphics_pipeline,
-            swap_chain_framebuffers,
-            command_buffers: vec![],
+            translucent_pipeline,
+            dynamic_state,
+            indirect,
+            offscreen,
+            screenshot_requested: false,
+            camera: Camera::default(),
+            camera_uniforms,
+            meshes,
+            draw_list: vec![],
+            draw_list_culled: false,
+            meshless_chunks: vec![],
+            gpu_mesher: None,
+            gpu_draw_list: vec![],
+            texture_set,
+            shadow_maps,
+            shadow_uniforms,
+            sky: Sky::default(),
+            sky_pass,
+            lighting_uniforms,
+            fog_uniforms,
+            camera_fluid: None,
+            created: Instant::now(),
+            previous_frame_end: None,
+            frame_fences: (0..frames_in_flight).map(|_| None).collect(),
+            frame_index: 0,
+            frame_stats: Default::default(),
         };
 
-        render_server.create_command_buffers();
-        return render_server;
+        render_server.set_camera(&Camera::default());
+        return Ok(render_server);
     }
 
-    pub fn render_loop(&mut self) {
-        println!("Looping");
-        while !self.surface.window().should_close() {
-            self.draw_frame();
-            self.glfw.poll_events();
+    /// Calls `frame` then draws, until the window is closed or a frame fails to render.
+    /// `frame` is where meshes are uploaded and drawn.
+    pub fn render_loop<F: FnMut(&mut RenderServer)>(&mut self, mut frame: F) -> Result<(), RenderError> {
+        if self.is_headless() {
+            panic!("Headless render servers have no render loop, draw with capture instead");
         }
+        while !self.window.as_ref().unwrap().surface.window().should_close() {
+            // Nothing can be presented to a minimized window, block until something happens to it
+            if self.is_minimized() {
+                self.window.as_mut().unwrap().glfw.wait_events();
+                self.handle_window_events();
+                continue;
+            }
+            frame(self);
+            if let Err(error) = self.draw_frame() {
+                if let Err(idle_error) = self.wait_idle() {
+                    log::error!("{}", idle_error);
+                }
+                return Err(error);
+            }
+            self.window.as_mut().unwrap().glfw.poll_events();
+            self.handle_window_events();
+        }
+        self.wait_idle()
     }
-}
\ No newline at end of file
+}
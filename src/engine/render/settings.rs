@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+use crate::base::voxel::CHUNK_SIZE;
+
+/// Kind of device picked when the render server is free to choose.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DevicePreference {
+    Discrete,
+    Integrated,
+    /// CPU implementations such as lavapipe, which render the same everywhere.
+    Software,
+}
+
+/// How the render server picks its physical device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelection {
+    /// Any suitable device, preferably of the given kind.
+    Prefer(DevicePreference),
+    /// The first suitable device whose name contains this, ignoring case.
+    Name(String),
+    /// The device at this index in the order Vulkan enumerates them. Fails if it is not suitable.
+    Index(usize),
+}
+
+/// Which Vulkan debug messages are forwarded to the `log` crate, under the `vulkan` target.
+#[derive(Clone, Debug)]
+pub struct ValidationSettings {
+    /// Loads the validation layers, on by default in debug builds. Only read when the render server is created.
+    pub enabled: bool,
+    /// Least severe messages forwarded, verbose Vulkan messages being `Trace`.
+    pub min_level: log::Level,
+    pub general_messages: bool,
+    pub performance_messages: bool,
+    /// Makes the render server panic after a frame or capture during which the validation layers
+    /// reported an error, so tests fail on them.
+    pub panic_on_error: bool,
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        ValidationSettings {
+            enabled: cfg!(debug_assertions),
+            min_level: log::Level::Warn,
+            general_messages: true,
+            performance_messages: true,
+            panic_on_error: false,
+        }
+    }
+}
+
+/// Sun shadows, rendered into cascaded shadow maps that each cover a slice of the view distance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    /// Number of cascades, at most 4. Each one covers a slice further away than the previous one.
+    pub cascades: u32,
+    /// Width and height of the shadow map of each cascade, in texels. The cascades are laid side by side in
+    /// one image, so `cascades*resolution` is clamped to the largest image the device supports.
+    pub resolution: u32,
+    /// View distance up to which shadows are drawn.
+    pub distance: f32,
+    /// Where the slices are split, from evenly (0) to logarithmically (1). Logarithmic splits spend
+    /// more of the texels close to the camera.
+    pub split_lambda: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            enabled: true,
+            cascades: 4,
+            resolution: 1024,
+            distance: 256.0,
+            split_lambda: 0.75,
+        }
+    }
+}
+
+/// Distance fog, and how it changes under fluids. Can be changed at any time through `RenderServer::fog_mut`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FogSettings {
+    pub enabled: bool,
+    /// Distance at which the fog hides everything. Matching the render distance hides the edge where
+    /// chunks stream in and out.
+    pub distance: f32,
+    /// Fraction of `distance` that is clear of fog.
+    pub start: f32,
+    /// Distance at which the fog hides everything with the camera inside a fluid.
+    pub underwater_distance: f32,
+    /// How far the screen wobbles with the camera inside a fluid, as a fraction of its size.
+    pub underwater_distortion: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        FogSettings {
+            enabled: true,
+            // The default streaming radius of 6 chunks
+            distance: 384.0,
+            start: 0.25,
+            underwater_distance: 24.0,
+            underwater_distortion: 0.004,
+        }
+    }
+}
+
+impl FogSettings {
+    /// Sets the fog to end at the edge of the chunks loaded `radius` chunks around the camera, as with
+    /// `StreamingSettings::radius`.
+    pub fn set_render_distance(&mut self, radius: i32) {
+        self.distance = (radius.max(1) as usize*CHUNK_SIZE) as f32;
+    }
+}
+
+/// Curve mapping the HDR colors of the scene to what the screen can show.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemapping {
+    /// Filmic curve of the Academy Color Encoding System, with contrasty shadows and soft highlights.
+    Aces = 0,
+    Reinhard = 1,
+    /// Clamps colors brighter than white, as if there were no HDR.
+    Clamp = 2,
+}
+
+/// Blurred glow around the parts of the scene brighter than white.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// Brightness above which colors glow.
+    pub threshold: f32,
+    /// How much of the glow is added back to the scene.
+    pub intensity: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        BloomSettings {
+            enabled: true,
+            threshold: 1.0,
+            intensity: 0.3,
+        }
+    }
+}
+
+/// How the HDR image of the scene is turned into the image on screen. Exposure and tonemapping can be
+/// changed at any time through `RenderServer::post_mut`, bloom is only set up when the render server is created.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PostSettings {
+    /// Scales the colors of the scene before they are tonemapped.
+    pub exposure: f32,
+    pub tonemapping: Tonemapping,
+    pub bloom: BloomSettings,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        PostSettings {
+            exposure: 1.0,
+            tonemapping: Tonemapping::Aces,
+            bloom: BloomSettings::default(),
+        }
+    }
+}
+
+/// Options picked when the render server is created.
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    /// Maps the near plane to depth 1 and the far plane to depth 0, which spreads the precision
+    /// of floating point depth buffers much more evenly over large view distances.
+    pub reverse_z: bool,
+    /// How many frames the CPU may record ahead of the GPU. More frames in flight keep both busier,
+    /// at the cost of latency and of one set of per-frame resources each.
+    pub frames_in_flight: usize,
+    /// Skips the meshes whose bounds are outside of the camera's view.
+    pub frustum_culling: bool,
+    /// Skips the chunks hidden behind solid terrain, by walking from the camera through the chunks whose
+    /// faces are connected by non-opaque blocks. Only applies to meshes drawn with `draw_chunk`.
+    pub occlusion_culling: bool,
+    /// Draws the chunks with one indirect draw per mesh arena instead of one recorded draw per chunk.
+    /// Devices without multi draw indirect fall back to recorded draws.
+    pub indirect_drawing: bool,
+    /// With indirect drawing, does the frustum culling in a compute shader instead of on the CPU.
+    /// Nothing is culled on the GPU when `frustum_culling` is disabled.
+    pub gpu_culling: bool,
+    /// Faces each chunk meshed on the GPU has room for. Its buffers are sized for that many faces up front,
+    /// as how many faces the chunk has is only known once it is meshed.
+    pub gpu_mesh_max_faces: u32,
+    /// Where `set_block_textures` loads `<texture name>.png` from. Without one, blocks keep their plain color.
+    pub texture_directory: Option<PathBuf>,
+    /// Upper bound of anisotropic filtering of block textures, clamped to what the device supports.
+    /// 1 disables it.
+    pub max_anisotropy: f32,
+    pub shadows: ShadowSettings,
+    pub fog: FogSettings,
+    pub post: PostSettings,
+    pub device: DeviceSelection,
+    pub validation: ValidationSettings,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            reverse_z: true,
+            frames_in_flight: 2,
+            frustum_culling: true,
+            occlusion_culling: true,
+            indirect_drawing: true,
+            gpu_culling: true,
+            gpu_mesh_max_faces: 1 << 16,
+            texture_directory: None,
+            max_anisotropy: 16.0,
+            shadows: ShadowSettings::default(),
+            fog: FogSettings::default(),
+            post: PostSettings::default(),
+            device: DeviceSelection::Prefer(DevicePreference::Discrete),
+            validation: ValidationSettings::default(),
+        }
+    }
+}
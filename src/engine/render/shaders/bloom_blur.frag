@@ -0,0 +1,25 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 fragUv;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(push_constant) uniform PushConstants {
+    // One texel along the direction of the blur
+    vec2 direction;
+} blur;
+
+layout(location = 0) out vec4 outColor;
+
+// Half of a 9 tap gaussian kernel, the first weight being the center
+const float WEIGHTS[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+void main() {
+    vec3 color = texture(source, fragUv).rgb*WEIGHTS[0];
+    for (int i = 1; i < 5; i++) {
+        color += texture(source, fragUv + blur.direction*float(i)).rgb*WEIGHTS[i];
+        color += texture(source, fragUv - blur.direction*float(i)).rgb*WEIGHTS[i];
+    }
+    outColor = vec4(color, 1.0);
+}
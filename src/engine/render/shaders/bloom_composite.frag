@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 fragUv;
+
+layout(set = 0, binding = 0) uniform sampler2D sceneColor;
+layout(set = 0, binding = 1) uniform sampler2D bloomColor;
+
+layout(push_constant) uniform PushConstants {
+    float intensity;
+} bloom;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec3 color = texture(sceneColor, fragUv).rgb + texture(bloomColor, fragUv).rgb*bloom.intensity;
+    outColor = vec4(color, 1.0);
+}
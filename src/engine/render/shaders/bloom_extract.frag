@@ -0,0 +1,21 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 fragUv;
+
+layout(set = 0, binding = 0) uniform sampler2D sceneColor;
+
+layout(push_constant) uniform PushConstants {
+    float threshold;
+} bloom;
+
+layout(location = 0) out vec4 outColor;
+
+// Keeps what is brighter than the threshold. Drawn at half the size of the scene, where the linear
+// filter averages the 2x2 texels around each pixel
+void main() {
+    vec3 color = texture(sceneColor, fragUv).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+    float bright = max(brightness - bloom.threshold, 0.0)/max(brightness, 0.0001);
+    outColor = vec4(color*bright, 1.0);
+}
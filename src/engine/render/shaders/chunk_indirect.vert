@@ -0,0 +1,54 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+layout(location = 3) in vec4 color;
+layout(location = 4) in uint layer;
+
+layout(set = 0, binding = 0) uniform CameraUniforms {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+struct ChunkDrawInfo {
+    mat4 model;
+    vec4 bounds_min;
+    vec4 bounds_max;
+    uint group;
+    uint group_start;
+};
+
+// The first instance of each indirect draw is the index of its chunk
+layout(set = 0, binding = 1) readonly buffer ChunkDraws {
+    ChunkDrawInfo draws[];
+} chunk_draws;
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+layout(location = 0) out vec4 fragColor;
+layout(location = 1) out vec3 fragNormal;
+layout(location = 2) out vec2 fragUv;
+layout(location = 3) flat out uint fragLayer;
+layout(location = 4) out vec3 fragWorldPosition;
+// Distance along the view direction, which picks the shadow cascade
+layout(location = 5) out float fragViewDepth;
+layout(location = 6) out float fragDistance;
+
+void main() {
+    mat4 model = chunk_draws.draws[gl_InstanceIndex].model;
+    vec4 worldPosition = model * vec4(position, 1.0);
+    gl_Position = camera.view_projection * worldPosition;
+    fragColor = color;
+    fragNormal = mat3(model) * normal;
+    fragUv = uv;
+    fragLayer = layer;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -(camera.view * worldPosition).z;
+    fragDistance = distance(worldPosition.xyz, camera.position.xyz);
+}
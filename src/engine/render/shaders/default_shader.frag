@@ -1,11 +1,83 @@
 #version 450
 #extension GL_ARB_separate_shader_objects: enable
 
-layout(location = 0) in vec3 fragColor;
+layout(location = 0) in vec4 fragColor;
+layout(location = 1) in vec3 fragNormal;
+layout(location = 2) in vec2 fragUv;
+layout(location = 3) flat in uint fragLayer;
+layout(location = 4) in vec3 fragWorldPosition;
+layout(location = 5) in float fragViewDepth;
+layout(location = 6) in float fragDistance;
+
+// One layer per block texture, layer 0 being plain white
+layout(set = 1, binding = 0) uniform sampler2DArray blockTextures;
+
+const int MAX_CASCADES = 4;
+// How far surfaces are pushed along their normal before looking them up in the shadow map, in texels of
+// the cascade, which keeps them from shadowing themselves
+const float NORMAL_OFFSET = 1.5;
+const float DEPTH_BIAS = 0.0005;
+
+layout(set = 2, binding = 0) uniform ShadowUniforms {
+    // From world space to the cascade's part of the shadow map
+    mat4 cascades[MAX_CASCADES];
+    // View depth up to which each cascade is used, 0 for unused cascades
+    vec4 splits;
+    // World space size of a shadow map texel of each cascade
+    vec4 texel_sizes;
+} shadows;
+
+// The cascades side by side
+layout(set = 2, binding = 1) uniform sampler2DShadow shadowMap;
+
+layout(set = 2, binding = 2) uniform LightingUniforms {
+    // Towards the sun, or the moon at night
+    vec4 light_direction;
+    vec4 light_color;
+    // Light reaching every face, including those in shadow
+    vec4 ambient_color;
+} lighting;
+
+layout(set = 2, binding = 3) uniform FogUniforms {
+    vec4 color;
+    // Multiplies the color of everything, to tint it under fluids
+    vec4 tint;
+    // x: distance the fog starts at, y: its density
+    vec4 parameters;
+} fog;
 
 layout(location = 0) out vec4 outColor;
 
+// Fraction of the light reaching the fragment, filtered over 3x3 texels
+float shadow(vec3 normal) {
+    for (int cascade = 0; cascade < MAX_CASCADES; cascade++) {
+        if (fragViewDepth >= shadows.splits[cascade]) {
+            continue;
+        }
+        vec3 position = fragWorldPosition + normal*shadows.texel_sizes[cascade]*NORMAL_OFFSET;
+        vec4 light = shadows.cascades[cascade]*vec4(position, 1.0);
+        vec2 uv = light.xy*0.5 + 0.5;
+        vec2 texel = 1.0/vec2(textureSize(shadowMap, 0));
+        float lit = 0.0;
+        for (int x = -1; x <= 1; x++) {
+            for (int y = -1; y <= 1; y++) {
+                lit += texture(shadowMap, vec3(uv + vec2(x, y)*texel, light.z - DEPTH_BIAS));
+            }
+        }
+        return lit/9.0;
+    }
+    return 1.0;
+}
 
 void main() {
-    outColor = vec4(fragColor, 1.0);
+    vec3 normal = normalize(fragNormal);
+    float diffuse = max(dot(normal, lighting.light_direction.xyz), 0.0);
+    if (diffuse > 0.0) {
+        diffuse *= shadow(normal);
+    }
+    vec3 albedo = texture(blockTextures, vec3(fragUv, fragLayer)).rgb*fragColor.rgb;
+    vec3 color = albedo*(lighting.ambient_color.rgb + lighting.light_color.rgb*diffuse)*fog.tint.rgb;
+    float fogged = max(fragDistance - fog.parameters.x, 0.0)*fog.parameters.y;
+    // Only translucent faces are blended by their alpha, the others replace what is behind them
+    outColor = vec4(mix(color, fog.color.rgb, 1.0 - exp(-fogged*fogged)), fragColor.a);
 }
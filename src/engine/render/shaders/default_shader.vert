@@ -1,25 +1,44 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+layout(location = 3) in vec4 color;
+layout(location = 4) in uint layer;
+
+layout(set = 0, binding = 0) uniform CameraUniforms {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+} push_constants;
+
 out gl_PerVertex {
     vec4 gl_Position;
 };
 
-layout(location = 0) out vec3 fragColor;
-
-vec2 positions[3] = vec2[](
-vec2(0.0, -0.5),
-vec2(0.5, 0.5),
-vec2(-0.5, 0.5)
-);
-
-vec3 colors[3] = vec3[](
-vec3(1.0, 0.0, 0.0),
-vec3(0.0, 1.0, 0.0),
-vec3(0.0, 0.0, 1.0)
-);
+layout(location = 0) out vec4 fragColor;
+layout(location = 1) out vec3 fragNormal;
+layout(location = 2) out vec2 fragUv;
+layout(location = 3) flat out uint fragLayer;
+layout(location = 4) out vec3 fragWorldPosition;
+// Distance along the view direction, which picks the shadow cascade
+layout(location = 5) out float fragViewDepth;
+layout(location = 6) out float fragDistance;
 
 void main() {
-    gl_Position = vec4(positions[gl_VertexIndex], 0.0, 1.0);
-    fragColor = colors[gl_VertexIndex];
-}
\ No newline at end of file
+    vec4 worldPosition = push_constants.model * vec4(position, 1.0);
+    gl_Position = camera.view_projection * worldPosition;
+    fragColor = color;
+    fragNormal = mat3(push_constants.model) * normal;
+    fragUv = uv;
+    fragLayer = layer;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -(camera.view * worldPosition).z;
+    fragDistance = distance(worldPosition.xyz, camera.position.xyz);
+}
@@ -0,0 +1,6 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Shadow maps only need depth, which is written without a fragment shader output
+void main() {
+}
@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    // Projection of the cascade being rendered, to its own shadow map
+    mat4 light_view_projection;
+} push_constants;
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+void main() {
+    gl_Position = push_constants.light_view_projection * push_constants.model * vec4(position, 1.0);
+}
@@ -0,0 +1,61 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 fragPosition;
+
+layout(set = 0, binding = 0) uniform SkyUniforms {
+    mat4 inverse_view_projection;
+    // Turns the stars along with the sun and moon
+    mat4 star_rotation;
+    vec4 camera_position;
+    vec4 sun_direction;
+    vec4 moon_direction;
+    vec4 zenith_color;
+    vec4 horizon_color;
+    // Color of the sunlight scattered around the sun
+    vec4 sun_color;
+    // x: how visible the stars are, from 0 by day to 1 at night
+    vec4 night;
+} sky;
+
+layout(location = 0) out vec4 outColor;
+
+// Cosine of the angular radius of the discs
+const float SUN_SIZE = 0.9996;
+const float MOON_SIZE = 0.9994;
+// Cells per unit of the grid stars are scattered in, and the share of the cells holding a star
+const float STAR_DENSITY = 250.0;
+const float STAR_THRESHOLD = 0.9985;
+
+float hash(vec3 cell) {
+    return fract(sin(dot(cell, vec3(12.9898, 78.233, 37.719)))*43758.5453);
+}
+
+void main() {
+    vec4 point = sky.inverse_view_projection*vec4(fragPosition, 0.5, 1.0);
+    vec3 direction = normalize(point.xyz/point.w - sky.camera_position.xyz);
+    float elevation = direction.y;
+
+    // Brighter and more saturated towards the zenith, fading to the horizon color below it
+    vec3 color = mix(sky.horizon_color.rgb, sky.zenith_color.rgb, sqrt(clamp(elevation, 0.0, 1.0)));
+    // Forward scattering around the sun, wider close to the horizon
+    float sun_cosine = dot(direction, sky.sun_direction.xyz);
+    float horizon_haze = 1.0 - abs(elevation);
+    color += sky.sun_color.rgb*pow(max(sun_cosine, 0.0), mix(32.0, 6.0, horizon_haze))*0.5;
+
+    float stars = sky.night.x*smoothstep(-0.05, 0.1, elevation);
+    if (stars > 0.0) {
+        vec3 star_direction = (sky.star_rotation*vec4(direction, 0.0)).xyz;
+        float star = hash(floor(star_direction*STAR_DENSITY));
+        if (star > STAR_THRESHOLD) {
+            color += vec3((star - STAR_THRESHOLD)/(1.0 - STAR_THRESHOLD))*stars;
+        }
+    }
+
+    float moon = smoothstep(MOON_SIZE - 0.0002, MOON_SIZE, dot(direction, sky.moon_direction.xyz));
+    color = mix(color, vec3(0.85, 0.85, 0.8), moon*mix(0.3, 1.0, sky.night.x));
+    float sun = smoothstep(SUN_SIZE - 0.0002, SUN_SIZE, sun_cosine);
+    color = mix(color, vec3(1.0, 0.95, 0.8), sun);
+
+    outColor = vec4(color, 1.0);
+}
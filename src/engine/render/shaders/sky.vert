@@ -0,0 +1,16 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+// Clip space position, from which the fragment shader finds the view direction
+layout(location = 0) out vec2 fragPosition;
+
+// One triangle covering the whole screen, without any vertex buffer
+void main() {
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2)*2.0 - 1.0;
+    gl_Position = vec4(position, 0.5, 1.0);
+    fragPosition = position;
+}
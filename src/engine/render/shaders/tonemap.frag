@@ -0,0 +1,49 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 fragUv;
+
+layout(set = 0, binding = 0) uniform sampler2D hdrColor;
+
+layout(push_constant) uniform PushConstants {
+    float exposure;
+    // 0: ACES, 1: Reinhard, 2: clamped
+    uint tonemapping;
+    // Set when the output format does not encode to sRGB by itself
+    uint encode_srgb;
+    // How far the screen wobbles, under fluids
+    float distortion;
+    // Seconds animating the wobble
+    float time;
+} post;
+
+layout(location = 0) out vec4 outColor;
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 color) {
+    return clamp((color*(2.51*color + 0.03))/(color*(2.43*color + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 srgb(vec3 color) {
+    return mix(12.92*color, 1.055*pow(color, vec3(1.0/2.4)) - 0.055, step(0.0031308, color));
+}
+
+void main() {
+    vec2 uv = fragUv;
+    if (post.distortion > 0.0) {
+        vec2 screen = uv*2.0 - 1.0;
+        uv += post.distortion*vec2(sin(screen.y*8.0 + post.time*2.0), cos(screen.x*8.0 + post.time*1.7));
+    }
+    vec3 color = texture(hdrColor, uv).rgb*post.exposure;
+    if (post.tonemapping == 0u) {
+        color = aces(color);
+    } else if (post.tonemapping == 1u) {
+        color = color/(1.0 + color);
+    } else {
+        color = clamp(color, 0.0, 1.0);
+    }
+    if (post.encode_srgb != 0u) {
+        color = srgb(color);
+    }
+    outColor = vec4(color, 1.0);
+}
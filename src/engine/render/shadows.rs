@@ -0,0 +1,367 @@
+use std::sync::Arc;
+use cgmath::{Matrix4, Point3, Vector3, InnerSpace, SquareMatrix, Transform, EuclideanSpace};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::sampler::{BorderColor, Filter, MipmapMode, Sampler, SamplerAddressMode};
+use crate::base::mesher::MeshVertex;
+use crate::base::world::ChunkPosition;
+use crate::engine::render::camera::Camera;
+use crate::engine::render::chunk_buffers::{ChunkBuffers, ChunkDraw};
+use crate::engine::render::error::RenderError;
+use crate::engine::render::frustum::Frustum;
+use crate::engine::render::gpu_mesher::{GpuMesher, GpuMeshHandle};
+use crate::engine::render::mesh::MeshHandle;
+use crate::engine::render::render_server::MeshPipeline;
+use crate::engine::render::settings::ShadowSettings;
+
+mod shadow_vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/engine/render/shaders/shadow.vert"
+    }
+}
+
+mod shadow_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/shadow.frag"
+    }
+}
+
+/// Must match `MAX_CASCADES` of the fragment shader.
+pub const MAX_CASCADES: usize = 4;
+
+/// Every device supports sampling 16 bit depth attachments, which is plenty for the depth range of a cascade.
+const SHADOW_FORMAT: Format = Format::D16Unorm;
+
+/// Texels kept free around the slice a cascade covers, so that neither snapping to texels nor filtering
+/// reach into the shadow map of the next cascade.
+const BORDER_TEXELS: u32 = 4;
+
+/// How far towards the sun from its slice a cascade still catches shadow casters.
+const CASTER_DISTANCE: f32 = 128.0;
+
+/// View depths where each cascade ends, splitting `near..far` between `count` cascades.
+pub fn cascade_splits(near: f32, far: f32, count: u32, lambda: f32) -> Vec<f32> {
+    (1..=count).map(|index| {
+        let fraction = index as f32/count as f32;
+        let logarithmic = near*(far/near).powf(fraction);
+        let uniform = near + (far - near)*fraction;
+        lambda*logarithmic + (1.0 - lambda)*uniform
+    }).collect()
+}
+
+/// Orthographic projection to Vulkan clip space, looking towards -z with depth going from 0 at `near` to 1 at `far`.
+fn orthographic(left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32) -> Matrix4<f32> {
+    Matrix4::new(
+        2.0/(right - left), 0.0, 0.0, 0.0,
+        0.0, 2.0/(top - bottom), 0.0, 0.0,
+        0.0, 0.0, -1.0/(far - near), 0.0,
+        -(right + left)/(right - left), -(top + bottom)/(top - bottom), -near/(far - near), 1.0,
+    )
+}
+
+/// One shadow map, covering the part of the view between two depths.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cascade {
+    /// From world space to the clip space of the cascade's shadow map.
+    pub view_projection: Matrix4<f32>,
+    /// View depth up to which the cascade is used.
+    pub split: f32,
+    /// World space size of a texel of the shadow map.
+    pub texel_size: f32,
+}
+
+impl Cascade {
+    /// Fits a cascade around the slice of the camera frustum between `near` and `far`, seen from the direction
+    /// `light_direction` points to. The cascade covers the bounding sphere of the slice, whose size does not
+    /// change as the camera turns, and only moves by whole texels as the camera moves, so that the edges
+    /// of shadows do not shimmer.
+    pub fn fit(camera: &Camera, near: f32, far: f32, light_direction: Vector3<f32>, resolution: u32) -> Self {
+        let inverse_view = camera.view_matrix().invert().expect("Camera view is not invertible");
+        let tan_half_fov = (camera.fov.0/2.0).tan();
+        let mut corners = vec![];
+        for depth in [near, far].iter() {
+            let (half_width, half_height) = (depth*tan_half_fov*camera.aspect, depth*tan_half_fov);
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].iter() {
+                corners.push(inverse_view.transform_point(Point3::new(x*half_width, y*half_height, -depth)));
+            }
+        }
+        let center = Point3::centroid(&corners);
+        let radius = corners.iter().map(|corner| (corner - center).magnitude()).fold(0.0, f32::max);
+        // Rounded up so that float error does not resize the cascade from one frame to the next
+        let radius = (radius*16.0).ceil()/16.0;
+
+        let texel_size = 2.0*radius/(resolution - 2*BORDER_TEXELS) as f32;
+        let half_extent = texel_size*resolution as f32/2.0;
+        let light_direction = light_direction.normalize();
+        let up = if light_direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+        // Centered on the world origin rather than on the cascade, which keeps the texel grid fixed in the world
+        let light_view = Matrix4::look_at_dir(Point3::new(0.0, 0.0, 0.0), -light_direction, up);
+        let center = light_view.transform_point(center);
+        let snap = |value: f32| (value/texel_size).round()*texel_size;
+        let (x, y) = (snap(center.x), snap(center.y));
+        let projection = orthographic(x - half_extent, x + half_extent, y - half_extent, y + half_extent,
+                                      -center.z - radius - CASTER_DISTANCE, -center.z + radius);
+        Cascade { view_projection: projection*light_view, split: far, texel_size }
+    }
+
+    /// Same as `view_projection`, except to the part of a shadow map holding `count` cascades side by side
+    /// that belongs to the `index`th one.
+    pub fn atlas_view_projection(&self, index: usize, count: usize) -> Matrix4<f32> {
+        let scale = 1.0/count as f32;
+        let offset = (2*index + 1) as f32*scale - 1.0;
+        Matrix4::from_translation(Vector3::new(offset, 0.0, 0.0))*Matrix4::from_nonuniform_scale(scale, 1.0, 1.0)*self.view_projection
+    }
+}
+
+/// Fits the cascades of `settings` to the camera.
+pub fn fit_cascades(camera: &Camera, settings: &ShadowSettings, light_direction: Vector3<f32>) -> Vec<Cascade> {
+    let far = settings.distance.min(camera.far);
+    let mut near = camera.near;
+    cascade_splits(camera.near, far, settings.cascades, settings.split_lambda).into_iter().map(|split| {
+        let cascade = Cascade::fit(camera, near, split, light_direction, settings.resolution);
+        near = split;
+        cascade
+    }).collect()
+}
+
+/// What the shadow pass draws during a frame.
+pub(crate) struct ShadowFrame {
+    pub cascades: Vec<Cascade>,
+    /// The meshes within each cascade.
+    pub casters: Vec<Vec<(MeshHandle, Matrix4<f32>)>>,
+}
+
+/// The shadow maps of the cascades, laid side by side in one depth image, and the depth only pass rendering them.
+pub(crate) struct ShadowMaps {
+    /// The settings the maps were made with, with the resolution clamped and no cascades when disabled.
+    settings: ShadowSettings,
+    pipeline: Arc<MeshPipeline>,
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    pub map: Arc<AttachmentImage>,
+    /// Compares depths, with linear filtering blending the comparisons of neighboring texels.
+    pub sampler: Arc<Sampler>,
+}
+
+impl ShadowMaps {
+    /// `max_image_size` is the largest 2D image the device supports. Disabled shadows still get a tiny
+    /// shadow map, so that the main pass can always sample one.
+    pub fn new(device: &Arc<Device>, settings: &ShadowSettings, max_image_size: u32) -> Result<Self, RenderError> {
+        let mut settings = settings.clone();
+        if !settings.enabled {
+            settings.cascades = 0;
+        }
+        settings.cascades = settings.cascades.min(MAX_CASCADES as u32);
+        if settings.cascades > 0 {
+            let max_resolution = max_image_size/settings.cascades;
+            if settings.resolution > max_resolution {
+                log::warn!("Shadow maps of {} texels do not fit in the device limits, using {}", settings.resolution, max_resolution);
+                settings.resolution = max_resolution;
+            }
+            settings.resolution = settings.resolution.max(4*BORDER_TEXELS);
+        }
+        let dimensions = [(settings.cascades*settings.resolution).max(1), settings.resolution.max(1)];
+
+        let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: SHADOW_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        ).map_err(RenderError::RenderPassCreation)?);
+
+        let usage = ImageUsage {
+            depth_stencil_attachment: true,
+            sampled: true,
+            .. ImageUsage::none()
+        };
+        let map = AttachmentImage::with_usage(device.clone(), dimensions, SHADOW_FORMAT, usage)
+            .map_err(RenderError::ImageCreation)?;
+        let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(Framebuffer::start(render_pass.clone())
+            .add(map.clone()).map_err(RenderError::FramebufferCreation)?
+            .build().map_err(RenderError::FramebufferCreation)?);
+
+        let vert_shader_module = shadow_vertex_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let frag_shader_module = shadow_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        // Both sides of the faces cast shadows, as chunk meshes are not closed at the edges of the loaded world
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<MeshVertex>()
+            .vertex_shader(vert_shader_module.main_entry_point(), ())
+            .triangle_list()
+            .primitive_restart(false)
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(frag_shader_module.main_entry_point(), ())
+            .cull_mode_disabled()
+            .depth_stencil_simple_depth()
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .build(device.clone())
+            .map_err(RenderError::PipelineCreation)?;
+
+        // Outside of the shadow maps everything is lit
+        let border = SamplerAddressMode::ClampToBorder(BorderColor::FloatOpaqueWhite);
+        let sampler = Sampler::compare(device.clone(), Filter::Linear, Filter::Linear, MipmapMode::Nearest,
+                                       border, border, border, 0.0, 1.0, 0.0, 0.0, Compare::LessOrEqual)
+            .map_err(RenderError::SamplerCreation)?;
+
+        Ok(ShadowMaps { settings, pipeline: Arc::new(pipeline), framebuffer, map, sampler })
+    }
+
+    /// Fits the cascades to the camera and picks the meshes of the draw list each of them catches.
+    /// This has to happen before the draw list is culled, as meshes out of view still cast shadows into it.
+    pub fn prepare(&self, camera: &Camera, light_direction: Vector3<f32>, meshes: &ChunkBuffers,
+                   draw_list: &[(MeshHandle, Matrix4<f32>, Option<ChunkPosition>)]) -> ShadowFrame {
+        // Without sun, there is nothing to cast shadows
+        let cascades = if light_direction.y > 0.0 {
+            fit_cascades(camera, &self.settings, light_direction)
+        } else {
+            vec![]
+        };
+        let casters = cascades.iter().map(|cascade| {
+            let frustum = Frustum::from_matrix(&cascade.view_projection);
+            draw_list.iter()
+                .filter(|(handle, model, _)| meshes.bounds(*handle).map_or(false, |bounds| frustum.intersects_aabb(&bounds.transformed(model))))
+                .map(|(handle, model, _)| (*handle, *model))
+                .collect()
+        }).collect();
+        ShadowFrame { cascades, casters }
+    }
+
+    /// Records the shadow pass, which must happen before the main pass samples the shadow maps.
+    /// Meshes made on the GPU are not culled and cast shadows in every cascade.
+    pub fn record(&self, mut builder: AutoCommandBufferBuilder, frame: &ShadowFrame, meshes: &ChunkBuffers,
+                  gpu_mesher: Option<&GpuMesher>, gpu_draw_list: &[(GpuMeshHandle, Matrix4<f32>)]) -> AutoCommandBufferBuilder {
+        // Also clears the tiny shadow map of disabled shadows, which has to be initialized before being sampled
+        builder = builder.begin_render_pass(self.framebuffer.clone(), false, vec![1.0f32.into()])
+            .unwrap();
+        let size = self.settings.resolution as f32;
+        for (index, (cascade, casters)) in frame.cascades.iter().zip(frame.casters.iter()).enumerate() {
+            let dynamic_state = DynamicState {
+                viewports: Some(vec![Viewport {
+                    origin: [index as f32*size, 0.0],
+                    dimensions: [size, size],
+                    depth_range: 0.0 .. 1.0,
+                }]),
+                .. DynamicState::none()
+            };
+            let light_view_projection = cascade.view_projection.into();
+            // Translucent faces let the light through
+            for (handle, model) in casters.iter() {
+                let (vertex_buffer, indices) = match meshes.get(*handle) {
+                    Some(ChunkDraw { vertex_buffer, indices: Some(indices), .. }) => (vertex_buffer, indices),
+                    _ => continue,
+                };
+                let push_constants = shadow_vertex_shader::ty::PushConstants {
+                    model: (*model).into(),
+                    light_view_projection,
+                };
+                builder = builder.draw_indexed(self.pipeline.clone(), &dynamic_state, vertex_buffer, indices, (), push_constants)
+                    .unwrap();
+            }
+            if let Some(gpu_mesher) = gpu_mesher {
+                for (handle, model) in gpu_draw_list.iter() {
+                    let mesh = match gpu_mesher.get(*handle) {
+                        Some(mesh) => mesh,
+                        None => continue,
+                    };
+                    let push_constants = shadow_vertex_shader::ty::PushConstants {
+                        model: (*model).into(),
+                        light_view_projection,
+                    };
+                    builder = builder.draw_indexed_indirect(self.pipeline.clone(), &dynamic_state, mesh.vertex_buffer.clone(),
+                                                            gpu_mesher.quad_indices(), mesh.command.clone(), (), push_constants)
+                        .unwrap();
+                }
+            }
+        }
+        builder.end_render_pass()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Rad, Vector4};
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(Point3::new(10.0, 40.0, -5.0), Rad(0.0), Rad(0.0));
+        camera.look_at(Point3::new(50.0, 20.0, 30.0));
+        camera
+    }
+
+    fn light() -> Vector3<f32> {
+        Vector3::new(0.4, 1.0, 0.2).normalize()
+    }
+
+    #[test]
+    fn splits_cover_the_shadow_distance() {
+        let splits = cascade_splits(0.1, 200.0, 4, 0.75);
+        assert_eq!(splits.len(), 4);
+        assert!(splits.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((splits[3] - 200.0).abs() < 1e-3);
+        let uniform = cascade_splits(1.0, 101.0, 4, 0.0);
+        assert_eq!(uniform, vec![26.0, 51.0, 76.0, 101.0]);
+    }
+
+    #[test]
+    fn cascade_contains_its_slice() {
+        let camera = camera();
+        let cascade = Cascade::fit(&camera, 10.0, 40.0, light(), 512);
+        let inverse_view = camera.view_matrix().invert().unwrap();
+        for depth in [10.0f32, 40.0].iter() {
+            let half_height = depth*(camera.fov.0/2.0).tan();
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].iter() {
+                let corner = inverse_view.transform_point(Point3::new(x*half_height*camera.aspect, y*half_height, -depth));
+                let clip = cascade.view_projection*corner.to_homogeneous();
+                // Snapping may move the slice by half a texel, which still leaves room for filtering
+                let border = 1.0 - 2.0*(BORDER_TEXELS - 1) as f32/512.0;
+                assert!(clip.x.abs() <= border && clip.y.abs() <= border, "{:?} is outside of the cascade", clip);
+                assert!(clip.z >= 0.0 && clip.z <= 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn cascade_moves_by_whole_texels() {
+        let mut camera = camera();
+        let first = Cascade::fit(&camera, 10.0, 40.0, light(), 512);
+        camera.position += Vector3::new(0.37, 0.11, -0.23);
+        camera.yaw += Rad(0.3);
+        let second = Cascade::fit(&camera, 10.0, 40.0, light(), 512);
+        assert_eq!(first.texel_size, second.texel_size);
+        // A fixed world point lands at the same place within a texel in both
+        let point = Vector4::new(3.0, 7.0, 11.0, 1.0);
+        let texels = |cascade: &Cascade| (cascade.view_projection*point)*(512.0/2.0);
+        let (a, b) = (texels(&first), texels(&second));
+        for delta in [a.x - b.x, a.y - b.y].iter() {
+            assert!((delta - delta.round()).abs() < 1e-2, "moved by {} texels", delta);
+        }
+    }
+
+    #[test]
+    fn atlas_puts_cascades_side_by_side() {
+        let cascade = Cascade::fit(&camera(), 10.0, 40.0, light(), 512);
+        let point = Vector4::new(30.0, 25.0, 10.0, 1.0);
+        let local = cascade.view_projection*point;
+        let atlas = cascade.atlas_view_projection(2, 4)*point;
+        assert!((atlas.x - (local.x/4.0 + 0.25)).abs() < 1e-5);
+        assert!((atlas.y - local.y).abs() < 1e-5);
+        assert!((atlas.z - local.z).abs() < 1e-5);
+    }
+}
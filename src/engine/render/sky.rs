@@ -0,0 +1,208 @@
+use std::sync::Arc;
+use cgmath::{Matrix4, Vector3, Rad, SquareMatrix};
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use crate::engine::render::camera::Camera;
+use crate::engine::render::error::RenderError;
+use crate::engine::time_of_day::TimeOfDay;
+
+mod sky_vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/engine/render/shaders/sky.vert"
+    }
+}
+
+mod sky_fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/engine/render/shaders/sky.frag"
+    }
+}
+
+const DAY_ZENITH: [f32; 3] = [0.22, 0.42, 0.82];
+const DAY_HORIZON: [f32; 3] = [0.62, 0.76, 0.95];
+const SUNSET_ZENITH: [f32; 3] = [0.2, 0.24, 0.48];
+const SUNSET_HORIZON: [f32; 3] = [0.95, 0.5, 0.25];
+const NIGHT_ZENITH: [f32; 3] = [0.004, 0.006, 0.02];
+const NIGHT_HORIZON: [f32; 3] = [0.02, 0.03, 0.06];
+
+const NOON_LIGHT: [f32; 3] = [0.65, 0.62, 0.58];
+const SUNSET_LIGHT: [f32; 3] = [0.6, 0.35, 0.2];
+const MOON_LIGHT: [f32; 3] = [0.08, 0.1, 0.15];
+const DAY_AMBIENT: [f32; 3] = [0.4, 0.42, 0.45];
+const NIGHT_AMBIENT: [f32; 3] = [0.04, 0.05, 0.08];
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0)/(edge1 - edge0)).max(0.0).min(1.0);
+    t*t*(3.0 - 2.0*t)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> Vector3<f32> {
+    Vector3::from(a)*(1.0 - t) + Vector3::from(b)*t
+}
+
+/// The sky and the light it casts at some time of the day.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sky {
+    /// Normalized, towards the sun.
+    pub sun_direction: Vector3<f32>,
+    /// Normalized, towards the moon.
+    pub moon_direction: Vector3<f32>,
+    pub zenith_color: Vector3<f32>,
+    pub horizon_color: Vector3<f32>,
+    /// Light of the sun by day and of the moon by night, fading out as they reach the horizon.
+    pub light_color: Vector3<f32>,
+    /// Light reaching every face, including those in shadow.
+    pub ambient_color: Vector3<f32>,
+    /// From 0 by day to 1 at night.
+    pub star_visibility: f32,
+    /// Angle the stars turned by since midnight, around the same axis as the sun.
+    pub star_angle: Rad<f32>,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky::at(&TimeOfDay::default())
+    }
+}
+
+impl Sky {
+    pub fn at(time: &TimeOfDay) -> Self {
+        let sun_direction = time.sun_direction();
+        let moon_direction = time.moon_direction();
+        let elevation = sun_direction.y;
+        let (zenith_color, horizon_color) = if elevation >= 0.0 {
+            let day = smoothstep(0.0, 0.3, elevation);
+            (mix(SUNSET_ZENITH, DAY_ZENITH, day), mix(SUNSET_HORIZON, DAY_HORIZON, day))
+        } else {
+            let night = smoothstep(0.0, -0.25, elevation);
+            (mix(SUNSET_ZENITH, NIGHT_ZENITH, night), mix(SUNSET_HORIZON, NIGHT_HORIZON, night))
+        };
+        // Both fade out at the horizon, so that the light does not jump when it switches from one to the other
+        let light_color = if elevation > 0.0 {
+            mix(SUNSET_LIGHT, NOON_LIGHT, smoothstep(0.0, 0.4, elevation))*smoothstep(0.0, 0.1, elevation)
+        } else {
+            Vector3::from(MOON_LIGHT)*smoothstep(0.0, 0.1, moon_direction.y)
+        };
+        Sky {
+            sun_direction,
+            moon_direction,
+            zenith_color,
+            horizon_color,
+            light_color,
+            ambient_color: mix(NIGHT_AMBIENT, DAY_AMBIENT, smoothstep(-0.15, 0.25, elevation)),
+            star_visibility: 1.0 - smoothstep(-0.25, -0.05, elevation),
+            star_angle: Rad(time.time*2.0*std::f32::consts::PI),
+        }
+    }
+
+    /// Direction towards whichever of the sun or moon is lighting the world.
+    pub fn light_direction(&self) -> Vector3<f32> {
+        if self.sun_direction.y > 0.0 { self.sun_direction } else { self.moon_direction }
+    }
+
+    /// What the parts of the screen the sky pass does not cover are cleared to.
+    pub fn clear_color(&self) -> [f32; 4] {
+        [self.horizon_color.x, self.horizon_color.y, self.horizon_color.z, 1.0]
+    }
+}
+
+type SkyPipeline = GraphicsPipeline<BufferlessDefinition, Box<dyn PipelineLayoutAbstract + Send + Sync + 'static>, Arc<dyn RenderPassAbstract + Send + Sync + 'static>>;
+
+/// Draws the sky behind everything else, as a triangle covering the screen.
+pub(crate) struct SkyPass {
+    pipeline: Arc<SkyPipeline>,
+    uniforms: CpuBufferPool<sky_fragment_shader::ty::SkyUniforms>,
+}
+
+impl SkyPass {
+    pub fn new(device: &Arc<Device>, render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>, frames_in_flight: usize) -> Result<Self, RenderError> {
+        let vert_shader_module = sky_vertex_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        let frag_shader_module = sky_fragment_shader::Shader::load(device.clone())
+            .map_err(RenderError::ShaderCreation)?;
+        // Neither tests nor writes depth, so that everything drawn after it ends up in front of it
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input(BufferlessDefinition)
+            .vertex_shader(vert_shader_module.main_entry_point(), ())
+            .triangle_list()
+            .primitive_restart(false)
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(frag_shader_module.main_entry_point(), ())
+            .cull_mode_disabled()
+            .blend_pass_through()
+            .depth_stencil(DepthStencil::disabled())
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .build(device.clone())
+            .map_err(RenderError::PipelineCreation)?;
+
+        let uniforms = CpuBufferPool::uniform_buffer(device.clone());
+        uniforms.reserve(frames_in_flight).map_err(RenderError::Allocation)?;
+        Ok(SkyPass { pipeline: Arc::new(pipeline), uniforms })
+    }
+
+    /// Records the sky, first thing in the render pass.
+    pub fn record(&self, builder: AutoCommandBufferBuilder, dynamic_state: &DynamicState, sky: &Sky, camera: &Camera,
+                  reverse_z: bool) -> AutoCommandBufferBuilder {
+        let inverse_view_projection = camera.view_projection_matrix(reverse_z).invert()
+            .expect("Camera view projection is not invertible");
+        let color = |color: Vector3<f32>| [color.x, color.y, color.z, 1.0];
+        let uniforms = sky_fragment_shader::ty::SkyUniforms {
+            inverse_view_projection: inverse_view_projection.into(),
+            star_rotation: Matrix4::from_angle_z(-sky.star_angle).into(),
+            camera_position: camera.position.to_homogeneous().into(),
+            sun_direction: sky.sun_direction.extend(0.0).into(),
+            moon_direction: sky.moon_direction.extend(0.0).into(),
+            zenith_color: color(sky.zenith_color),
+            horizon_color: color(sky.horizon_color),
+            sun_color: color(sky.light_color),
+            night: [sky.star_visibility, 0.0, 0.0, 0.0],
+        };
+        let buffer = self.uniforms.next(uniforms)
+            .expect("Failed to allocate sky uniforms");
+        let layout = self.pipeline.descriptor_set_layout(0).unwrap();
+        let set = Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(buffer)
+            .unwrap()
+            .build()
+            .unwrap());
+        builder.draw(self.pipeline.clone(), dynamic_state, BufferlessVertices { vertices: 3, instances: 1 }, set, ())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    #[test]
+    fn noon_is_bright_and_midnight_is_dark() {
+        let noon = Sky::at(&TimeOfDay::at(0.5));
+        let midnight = Sky::at(&TimeOfDay::at(0.0));
+        assert!(noon.zenith_color.magnitude() > 10.0*midnight.zenith_color.magnitude());
+        assert!(noon.light_color.magnitude() > midnight.light_color.magnitude());
+        assert_eq!(noon.star_visibility, 0.0);
+        assert_eq!(midnight.star_visibility, 1.0);
+        assert_eq!(midnight.light_direction(), midnight.moon_direction);
+    }
+
+    #[test]
+    fn light_fades_smoothly_through_sunrise() {
+        let mut previous = Sky::at(&TimeOfDay::at(0.2));
+        for step in 1..=200 {
+            let sky = Sky::at(&TimeOfDay::at(0.2 + step as f32*0.0005));
+            assert!((sky.light_color - previous.light_color).magnitude() < 0.05);
+            assert!((sky.horizon_color - previous.horizon_color).magnitude() < 0.05);
+            previous = sky;
+        }
+    }
+}
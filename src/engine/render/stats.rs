@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+/// Weight of the newest frame in the averaged timings.
+const SMOOTHING: f64 = 0.05;
+
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    pub frame_count: u64,
+    /// Time between the last two frames.
+    pub frame_time: Duration,
+    /// Exponential moving average of `frame_time`.
+    pub average_frame_time: Duration,
+    /// Time spent recording and submitting the last frame on the CPU.
+    pub cpu_time: Duration,
+    /// Time the last frame spent waiting for a frame in flight to finish on the GPU.
+    pub gpu_wait_time: Duration,
+    /// Exponential moving average of `gpu_wait_time`.
+    pub average_gpu_wait_time: Duration,
+    /// Chunk meshes drawn during the last frame, after culling. `None` when frustum culling happens on the GPU,
+    /// as how many chunks it culls is never read back.
+    pub visible_chunks: Option<usize>,
+    /// Chunk meshes submitted for the last frame, before culling.
+    pub total_chunks: usize,
+    /// Draw commands recorded for the last frame, each indirect draw counting as one.
+    pub draw_calls: usize,
+    last_frame_start: Option<Instant>,
+}
+
+fn smooth(average: Duration, sample: Duration) -> Duration {
+    if average == Duration::default() {
+        return sample;
+    }
+    Duration::from_secs_f64(average.as_secs_f64()*(1.0 - SMOOTHING) + sample.as_secs_f64()*SMOOTHING)
+}
+
+impl FrameStats {
+    pub fn frames_per_second(&self) -> f64 {
+        let seconds = self.average_frame_time.as_secs_f64();
+        if seconds > 0.0 { 1.0/seconds } else { 0.0 }
+    }
+
+    pub(crate) fn begin_frame(&mut self, now: Instant) {
+        if let Some(last_frame_start) = self.last_frame_start {
+            self.frame_time = now - last_frame_start;
+            self.average_frame_time = smooth(self.average_frame_time, self.frame_time);
+        }
+        self.last_frame_start = Some(now);
+    }
+
+    pub(crate) fn end_frame(&mut self, cpu_time: Duration, gpu_wait_time: Duration) {
+        self.cpu_time = cpu_time;
+        self.gpu_wait_time = gpu_wait_time;
+        self.average_gpu_wait_time = smooth(self.average_gpu_wait_time, gpu_wait_time);
+        self.frame_count += 1;
+    }
+}
@@ -0,0 +1,203 @@
+use std::path::Path;
+use std::sync::Arc;
+use vulkano::buffer::{BufferSlice, BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::{Dimensions, ImageLayout, ImageUsage, ImmutableImage, MipmapsCount};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+use crate::engine::render::capture::CapturedImage;
+use crate::engine::render::error::RenderError;
+
+/// Size of the layers when there is no texture to take it from.
+const DEFAULT_TEXTURE_SIZE: u32 = 16;
+const TEXTURE_FORMAT: Format = Format::R8G8B8A8Srgb;
+
+/// The block textures, one per layer of a 2D array texture so that neighboring textures can not bleed
+/// into each other the way they do in an atlas. Layer 0 is plain white, for untextured faces.
+pub struct BlockTextureArray {
+    pub image: Arc<ImmutableImage<Format>>,
+    /// Magnifies to nearest texels to keep the pixel art crisp, and blends between mipmaps to avoid shimmering.
+    pub sampler: Arc<Sampler>,
+    pub layers: u32,
+}
+
+impl BlockTextureArray {
+    /// Loads `<directory>/<name>.png` into layer `i + 1` for the `i`th name. Textures are scaled to the size
+    /// of the first one, missing ones are replaced by a magenta and black checkerboard.
+    /// Without a directory nothing is loaded and every layer is plain white.
+    pub fn load(queue: &Arc<Queue>, directory: Option<&Path>, names: &[String], max_anisotropy: f32) -> Result<Self, RenderError> {
+        let directory = match directory {
+            Some(directory) => directory,
+            None => return Self::from_layers(queue, &vec![solid(DEFAULT_TEXTURE_SIZE, [255, 255, 255, 255]); names.len() + 1], max_anisotropy),
+        };
+        let textures: Vec<_> = names.iter().map(|name| {
+            let path = directory.join(format!("{}.png", name));
+            match CapturedImage::load_png(&path) {
+                Ok(texture) => Some(texture),
+                Err(error) => {
+                    log::warn!("Failed to load block texture {}: {}", path.display(), error);
+                    None
+                }
+            }
+        }).collect();
+        let size = textures.iter().flatten().next().map_or(DEFAULT_TEXTURE_SIZE, |texture| texture.width);
+        let mut layers = vec![solid(size, [255, 255, 255, 255])];
+        for (name, texture) in names.iter().zip(textures) {
+            layers.push(match texture {
+                Some(texture) if texture.width == size && texture.height == size => texture,
+                Some(texture) => {
+                    log::warn!("Block texture {} is {}x{}, scaling it to {}x{}", name, texture.width, texture.height, size, size);
+                    resize(&texture, size)
+                }
+                None => checkerboard(size),
+            });
+        }
+        Self::from_layers(queue, &layers, max_anisotropy)
+    }
+
+    /// Uploads square layers of the same size, along with their mipmaps. Blocks until the upload is done.
+    pub fn from_layers(queue: &Arc<Queue>, layers: &[CapturedImage], max_anisotropy: f32) -> Result<Self, RenderError> {
+        let device = queue.device();
+        let size = layers[0].width;
+        let mipmaps: Vec<Vec<CapturedImage>> = layers.iter().map(mipmap_chain).collect();
+        let levels = mipmaps[0].len() as u32;
+
+        let dimensions = Dimensions::Dim2dArray { width: size, height: size, array_layers: layers.len() as u32 };
+        let usage = ImageUsage { transfer_destination: true, sampled: true, .. ImageUsage::none() };
+        let (image, initialization) = ImmutableImage::uninitialized(device.clone(), dimensions, TEXTURE_FORMAT, MipmapsCount::Specific(levels),
+                                                                    usage, ImageLayout::ShaderReadOnlyOptimal, device.active_queue_families())
+            .map_err(RenderError::ImageCreation)?;
+        let initialization = Arc::new(initialization);
+
+        // Every level of every layer in one staging buffer, level by level so that each level is one copy
+        let mut ranges = vec![];
+        let mut pixels = vec![];
+        for level in 0..levels as usize {
+            let start = pixels.len();
+            for layer in mipmaps.iter() {
+                pixels.extend_from_slice(&layer[level].pixels);
+            }
+            ranges.push(start..pixels.len());
+        }
+        let staging = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_source(), false, pixels.into_iter())
+            .map_err(RenderError::Allocation)?;
+
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())
+            .unwrap();
+        for (level, range) in ranges.into_iter().enumerate() {
+            let level_size = mipmaps[0][level].width;
+            let source = BufferSlice::from_typed_buffer_access(staging.clone()).slice(range).unwrap();
+            builder = builder.copy_buffer_to_image_dimensions(source, initialization.clone(), [0, 0, 0], [level_size, level_size, 1],
+                                                              0, layers.len() as u32, level as u32)
+                .unwrap();
+        }
+        builder.build()
+            .unwrap()
+            .execute(queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .expect("Failed to upload block textures")
+            .wait(None)
+            .unwrap();
+
+        let sampler = Sampler::new(device.clone(), Filter::Nearest, Filter::Linear, MipmapMode::Linear,
+                                   SamplerAddressMode::Repeat, SamplerAddressMode::Repeat, SamplerAddressMode::Repeat,
+                                   0.0, max_anisotropy, 0.0, levels as f32)
+            .map_err(RenderError::SamplerCreation)?;
+        Ok(BlockTextureArray { image, sampler, layers: layers.len() as u32 })
+    }
+}
+
+fn solid(size: u32, color: [u8; 4]) -> CapturedImage {
+    let pixels = (0..size*size).flat_map(|_| color.iter().cloned()).collect();
+    CapturedImage { width: size, height: size, pixels }
+}
+
+fn checkerboard(size: u32) -> CapturedImage {
+    let cell = (size/2).max(1);
+    let mut pixels = Vec::with_capacity((size*size*4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let color = if (x/cell + y/cell) % 2 == 0 { [255, 0, 255, 255] } else { [0, 0, 0, 255] };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    CapturedImage { width: size, height: size, pixels }
+}
+
+/// Nearest neighbor scaling, which keeps pixel art looking like pixel art.
+fn resize(image: &CapturedImage, size: u32) -> CapturedImage {
+    let mut pixels = Vec::with_capacity((size*size*4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            pixels.extend_from_slice(&image.pixel(x*image.width/size, y*image.height/size));
+        }
+    }
+    CapturedImage { width: size, height: size, pixels }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32/255.0;
+    if value <= 0.04045 { value/12.92 } else { ((value + 0.055)/1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.0031308 { value*12.92 } else { 1.055*value.powf(1.0/2.4) - 0.055 };
+    (value*255.0).round().max(0.0).min(255.0) as u8
+}
+
+/// Averages each 2x2 block of texels, in linear space so that mipmaps do not darken.
+fn downsample(image: &CapturedImage) -> CapturedImage {
+    let size = (image.width/2).max(1);
+    let mut pixels = Vec::with_capacity((size*size*4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let mut sum = [0.0; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let pixel = image.pixel((x*2 + dx).min(image.width - 1), (y*2 + dy).min(image.height - 1));
+                for (total, value) in sum.iter_mut().zip(pixel.iter()).take(3) {
+                    *total += srgb_to_linear(*value);
+                }
+                sum[3] += pixel[3] as f32/255.0;
+            }
+            pixels.extend(sum[..3].iter().map(|total| linear_to_srgb(total/4.0)));
+            pixels.push((sum[3]/4.0*255.0).round() as u8);
+        }
+    }
+    CapturedImage { width: size, height: size, pixels }
+}
+
+/// The image followed by each of its mipmaps, down to 1x1.
+fn mipmap_chain(image: &CapturedImage) -> Vec<CapturedImage> {
+    let mut chain = vec![image.clone()];
+    while chain.last().unwrap().width > 1 {
+        let next = downsample(chain.last().unwrap());
+        chain.push(next);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mipmap_chain_goes_down_to_one_texel() {
+        let chain = mipmap_chain(&checkerboard(16));
+        let sizes: Vec<u32> = chain.iter().map(|level| level.width).collect();
+        assert_eq!(sizes, vec![16, 8, 4, 2, 1]);
+    }
+
+    #[test]
+    fn mipmaps_average_in_linear_space() {
+        let mut pixels = vec![];
+        for color in [[255, 255, 255, 255], [0, 0, 0, 255], [0, 0, 0, 255], [255, 255, 255, 255]].iter() {
+            pixels.extend_from_slice(color);
+        }
+        let mipmap = downsample(&CapturedImage { width: 2, height: 2, pixels });
+        // Half of the light of white, which is brighter than half of its sRGB value
+        assert_eq!(mipmap.pixels, vec![188, 188, 188, 255]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+use log::Level;
+use vulkano::instance::Instance;
+use vulkano::instance::debug::{DebugCallback, Message, MessageSeverity, MessageType};
+use crate::engine::render::settings::ValidationSettings;
+
+/// Validation layers by order of preference. The LunarG meta layer was replaced by the Khronos one,
+/// but older SDKs only ship the former.
+const VALIDATION_LAYERS: &[&str] = &[
+    "VK_LAYER_KHRONOS_validation",
+    "VK_LAYER_LUNARG_standard_validation",
+];
+
+/// Log target of the messages, so they can be filtered separately from the rest of the engine.
+pub const LOG_TARGET: &str = "vulkan";
+
+/// Validation errors reported since they were last taken.
+pub(crate) type ValidationErrors = Arc<Mutex<Vec<String>>>;
+
+/// Picks the preferred validation layer among the installed ones.
+pub fn choose_validation_layer() -> Option<&'static str> {
+    let layers: Vec<_> = match vulkano::instance::layers_list() {
+        Ok(layers) => layers.map(|layer| layer.name().to_owned()).collect(),
+        Err(_) => return None,
+    };
+    VALIDATION_LAYERS.iter().find(|layer_name| layers.iter().any(|layer| layer == *layer_name)).cloned()
+}
+
+fn level(severity: &MessageSeverity) -> Level {
+    if severity.error {
+        Level::Error
+    } else if severity.warning {
+        Level::Warn
+    } else if severity.information {
+        Level::Info
+    } else {
+        Level::Trace
+    }
+}
+
+fn kind(ty: &MessageType) -> &'static str {
+    if ty.validation {
+        "validation"
+    } else if ty.performance {
+        "performance"
+    } else {
+        "general"
+    }
+}
+
+/// Severities at least as severe as `min_level`, verbose messages counting as trace.
+fn severities(min_level: Level) -> MessageSeverity {
+    MessageSeverity {
+        error: true,
+        warning: min_level >= Level::Warn,
+        information: min_level >= Level::Info,
+        verbose: min_level >= Level::Trace,
+    }
+}
+
+/// Forwards the messages selected by `settings` to the log, recording errors in `errors`.
+/// Returns `None` if the instance has no debug utils extension to report messages through.
+pub(crate) fn create_debug_callback(instance: &Arc<Instance>, settings: &ValidationSettings, errors: &ValidationErrors) -> Option<DebugCallback> {
+    if !settings.enabled || !instance.loaded_extensions().ext_debug_utils {
+        return None;
+    }
+    let types = MessageType {
+        general: settings.general_messages,
+        validation: true,
+        performance: settings.performance_messages,
+    };
+    let errors = errors.clone();
+    let result = DebugCallback::new(instance, severities(settings.min_level), types, move |message: &Message| {
+        let level = level(&message.severity);
+        log::log!(target: LOG_TARGET, level, "[{}] {}", kind(&message.ty), message.description);
+        if level == Level::Error {
+            if let Ok(mut errors) = errors.lock() {
+                errors.push(message.description.to_owned());
+            }
+        }
+    });
+    match result {
+        Ok(callback) => Some(callback),
+        Err(error) => {
+            log::warn!(target: LOG_TARGET, "Failed to register the debug callback: {}", error);
+            None
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+use cgmath::{Vector3, InnerSpace};
+use specs::prelude::*;
+
+/// How far the path of the sun leans towards +z, so that it is never straight overhead.
+const SUN_TILT: f32 = 0.35;
+
+/// Time of the day, driving the sun, moon and sky. Insert it as a resource and run `TimeOfDaySystem`
+/// to have it follow real time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeOfDay {
+    /// Fraction of the day, 0 at midnight, 0.25 at sunrise, 0.5 at noon and 0.75 at sunset.
+    pub time: f32,
+    /// Days since the start, incremented each midnight.
+    pub day: u64,
+    /// Real time a day lasts at a time scale of 1.
+    pub day_length: Duration,
+    /// How much faster than real time the day goes by. Negative scales rewind it.
+    pub time_scale: f32,
+    pub paused: bool,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        TimeOfDay {
+            time: 0.375,
+            day: 0,
+            day_length: Duration::from_secs(20*60),
+            time_scale: 1.0,
+            paused: false,
+        }
+    }
+}
+
+impl TimeOfDay {
+    pub fn at(time: f32) -> Self {
+        TimeOfDay { time: time.rem_euclid(1.0), .. Default::default() }
+    }
+
+    /// Moves time forward by `elapsed` real time, scaled by `time_scale`, unless paused.
+    pub fn advance(&mut self, elapsed: Duration) {
+        if self.paused || self.day_length == Duration::default() {
+            return;
+        }
+        let days = elapsed.as_secs_f64()/self.day_length.as_secs_f64()*self.time_scale as f64;
+        let time = self.time as f64 + days;
+        let whole_days = time.floor();
+        self.day = (self.day as i64 + whole_days as i64).max(0) as u64;
+        self.time = (time - whole_days) as f32;
+    }
+
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale;
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Angle of the sun along its path, 0 when it rises.
+    pub fn sun_angle(&self) -> f32 {
+        (self.time - 0.25)*2.0*std::f32::consts::PI
+    }
+
+    /// Normalized direction towards the sun. It rises towards +x and sets towards -x.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let (sin, cos) = self.sun_angle().sin_cos();
+        Vector3::new(cos, sin, SUN_TILT).normalize()
+    }
+
+    /// Normalized direction towards the moon, which is always opposite to the sun.
+    pub fn moon_direction(&self) -> Vector3<f32> {
+        let sun = self.sun_direction();
+        Vector3::new(-sun.x, -sun.y, sun.z)
+    }
+}
+
+/// Advances the `TimeOfDay` resource by the real time elapsed since its previous run.
+#[derive(Default)]
+pub struct TimeOfDaySystem {
+    last_run: Option<Instant>,
+}
+
+impl<'a> System<'a> for TimeOfDaySystem {
+    type SystemData = Write<'a, TimeOfDay>;
+
+    fn run(&mut self, mut time_of_day: Self::SystemData) {
+        let now = Instant::now();
+        if let Some(last_run) = self.last_run {
+            time_of_day.advance(now - last_run);
+        }
+        self.last_run = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advancing_wraps_into_the_next_day() {
+        let mut time = TimeOfDay::at(0.9);
+        time.advance(time.day_length/5);
+        assert_eq!(time.day, 1);
+        assert!((time.time - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn time_scale_and_pause() {
+        let mut time = TimeOfDay::at(0.5);
+        time.set_time_scale(2.0);
+        time.advance(time.day_length/8);
+        assert!((time.time - 0.75).abs() < 1e-4);
+        time.pause();
+        time.advance(time.day_length);
+        assert!((time.time - 0.75).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sun_is_up_by_day_and_moon_by_night() {
+        assert!(TimeOfDay::at(0.5).sun_direction().y > 0.9);
+        assert!(TimeOfDay::at(0.0).sun_direction().y < -0.9);
+        assert!(TimeOfDay::at(0.0).moon_direction().y > 0.9);
+        assert!(TimeOfDay::at(0.3).sun_direction().x > 0.0);
+    }
+}
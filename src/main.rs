@@ -3,6 +3,15 @@ pub mod base;
 use raylib::prelude::*;
 use specs::prelude::*;
 use engine::render::render_server;
+use base::block::{BlockRegistry, BlockDefinition};
+use base::generator::{ChunkGenerator, HeightmapGenerator};
+use base::mesher::culled_mesher;
+use base::voxel::CHUNK_SIZE;
+use base::world::{VoxelWorld, ChunkPosition};
+use engine::render::camera::Camera;
+use engine::render::settings::RenderSettings;
+use engine::time_of_day::TimeOfDay;
+use cgmath::{Point3, Rad};
 fn main() {
     // let mut world = World::new();
     // world.insert(engine::core::GameStatus{should_close:false});
@@ -13,6 +22,69 @@ fn main() {
     // while world.fetch::<engine::core::GameStatus>().should_close == false {
     //     dispatcher.dispatch(&world);
     // }
-    let mut render_server = render_server::RenderServer::new();
-    render_server.render_loop();
+    // Filtered with RUST_LOG, e.g. `RUST_LOG=vulkan=trace` shows every Vulkan message
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut registry = BlockRegistry::new();
+    let stone = registry.register(BlockDefinition::new("stone").with_color([0.5, 0.5, 0.5]));
+    let dirt = registry.register(BlockDefinition::new("dirt").with_color([0.45, 0.3, 0.15]));
+    let grass = registry.register(BlockDefinition::new("grass").with_color([0.3, 0.6, 0.2]));
+    let generator = HeightmapGenerator::new(0, stone, dirt, grass);
+    let chunk_position = ChunkPosition::new(0, 0, 0);
+    let mut world = VoxelWorld::new();
+    world.insert_chunk(chunk_position, generator.generate(chunk_position));
+
+    // `--screenshot <path>` renders a single frame without a window and writes it to `path`
+    let args: Vec<String> = std::env::args().collect();
+    let screenshot_path = args.iter().position(|arg| arg == "--screenshot")
+        .map(|index| args.get(index + 1).expect("Missing path after --screenshot").clone());
+
+    let render_server = match screenshot_path {
+        Some(_) => render_server::RenderServer::headless(RenderSettings::default(), [800, 600]),
+        None => render_server::RenderServer::new(),
+    };
+    let mut render_server = match render_server {
+        Ok(render_server) => render_server,
+        Err(error) => {
+            log::error!("Unable to start the renderer: {}", error);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Rendering with {}", render_server.device_name());
+    if let Err(error) = render_server.set_block_textures(&registry) {
+        log::error!("Unable to load the block textures: {}", error);
+    }
+    let chunk_mesh = match render_server.upload_mesh(&culled_mesher::generate_mesh(&world, chunk_position, &registry)) {
+        Ok(chunk_mesh) => chunk_mesh,
+        Err(error) => {
+            log::error!("Unable to upload the chunk mesh: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let size = CHUNK_SIZE as f32;
+    let mut camera = Camera::new(Point3::new(-0.5*size, 1.5*size, -0.5*size), Rad(0.0), Rad(0.0));
+    camera.look_at(Point3::new(0.5*size, 0.25*size, 0.5*size));
+    render_server.set_camera(&camera);
+    render_server.update_camera_medium(&world, &registry);
+    if let Some(path) = screenshot_path {
+        render_server.draw_chunk(chunk_mesh, chunk_position);
+        match render_server.capture() {
+            Ok(image) => image.save_png(&path).expect("Failed to save screenshot"),
+            Err(error) => log::error!("Unable to render the screenshot: {}", error),
+        }
+        return;
+    }
+    let mut time_of_day = TimeOfDay::default();
+    let mut last_frame = std::time::Instant::now();
+    let result = render_server.render_loop(|server| {
+        let now = std::time::Instant::now();
+        time_of_day.advance(now - last_frame);
+        last_frame = now;
+        server.set_time_of_day(&time_of_day);
+        server.draw_chunk(chunk_mesh, chunk_position);
+    });
+    if let Err(error) = result {
+        log::error!("Rendering stopped: {}", error);
+        std::process::exit(1);
+    }
 }